@@ -1,10 +1,8 @@
 #include <stdint.h>
 #include <stddef.h>
 
-struct Crafting {
-  // ish
-  uint32_t data[0x90];
-};
+// opaque; the fields are found at runtime, see Shared
+struct Crafting;
 
 struct SetEntry {
   void *unknown;
@@ -35,16 +33,33 @@ struct Shared {
   struct Set *set;
   int (*getStatus)(struct Crafting *crafting);
   size_t capacity;
+  // byte offsets of the uint32_t fields in a Crafting
+  size_t unit_number_offset;
+  size_t products_complete_offset;
 
   // out
   size_t count;
   struct CraftingLite crafting[];
 };
 
-extern int entry(
+static void walk(
+  struct SetEntry *entry,
   struct Shared *mem
 );
 
+// first, so it's at the start of the binary
+extern int entry(
+  struct Shared *mem
+) {
+  mem->count = 0;
+  walk(mem->set->begin, mem);
+  return 0;
+}
+
+static uint32_t field(struct Crafting *crafting, size_t offset) {
+  return *(uint32_t *)((char *)crafting + offset);
+}
+
 static void walk(
   struct SetEntry *entry,
   struct Shared *mem
@@ -55,26 +70,16 @@ static void walk(
 
   walk(entry->left, mem);
 
-  if (mem->size >= mem->capacity) {
+  if (mem->count >= mem->capacity) {
     return;
   }
 
   struct Crafting *crafting = entry->data;
-  struct CraftingLite *lite = &mem->crafting[mem->size];
-  // untested
-  lite->unit_number = crafting->data[0x26];
-  lite->products_complete = crafting->data[0x81];
+  struct CraftingLite *lite = &mem->crafting[mem->count];
+  lite->unit_number = field(crafting, mem->unit_number_offset);
+  lite->products_complete = field(crafting, mem->products_complete_offset);
   lite->status = mem->getStatus(crafting);
-  mem->size++;
+  mem->count++;
 
   walk(entry->right, mem);
 }
-
-extern int entry(
-  struct Shared *mem
-) {
-  mem->size = 0;
-  walk(mem->set->begin, mem);
-  return 0;
-}
-
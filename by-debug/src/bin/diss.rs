@@ -1,27 +1,28 @@
 use std::fs;
 
 use anyhow::{anyhow, Context, Result};
-use facto_exporter::debug::elf::full_symbol_table;
 use facto_exporter::debug::mangle::demangle;
+use facto_exporter::debug::symbols::SymbolIndex;
 use iced_x86::{Decoder, DecoderOptions, Formatter, NasmFormatter};
 
 fn main() -> Result<()> {
     let bin_path = fs::canonicalize(
         std::env::args_os()
             .nth(1)
-            .ok_or(anyhow!("usage: bin path"))?,
+            .ok_or(anyhow!("usage: bin path [cache dir]"))?,
     )?;
+    let cache_dir = std::env::args_os().nth(2).unwrap_or_else(|| "cache".into());
 
-    let name_to_loc = full_symbol_table(&bin_path)?;
+    let index = SymbolIndex::open_cached(&bin_path, cache_dir)?;
     let bin = fs::read(&bin_path)?;
 
-    for (name, (loc, size)) in name_to_loc {
+    for (name, &(loc, size)) in index.table() {
         if !name.contains("CraftingMachine") {
             continue;
         }
         // failure to demangle is normally e.g. C names
-        let func = demangle(&name).with_context(|| anyhow!("raw: {name:?}"))?;
-        println!("{}: {:#x} {:#x}: {func:?}", name, loc, size);
+        let func = demangle(name).with_context(|| anyhow!("raw: {name:?}"))?;
+        println!("{}: {:#x} {:#x}: {func}", name, loc, size);
 
         let mut decoder = Decoder::with_ip(64, &bin, loc, DecoderOptions::NONE);
         decoder.set_position(usize::try_from(loc - 0x400000)?)?;
@@ -0,0 +1,686 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Context, Result};
+use archiv::{Compress, CompressStream};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use facto_exporter::{delta, Observation, Unpacker};
+
+use crate::config::ArchiveConfig;
+
+/// a key frame every this many observations, in case we ever want to seek
+const KEY_EVERY: usize = 512;
+
+const EXTENSION: &str = ".facto-cp.archiv";
+/// archives are written under this name, and renamed once they're finished
+const PARTIAL_SUFFIX: &str = ".partial";
+/// while an old archive is being rewritten
+const TEMP_SUFFIX: &str = ".tmp";
+/// the finished archives, and the times they cover; JSON
+const INDEX_FILE: &str = "archives.json";
+/// held by the [`Archive`] writing to the directory, as the partial archives and the index are its
+const LOCK_FILE: &str = "archives.lock";
+
+/// The archive currently being written, replaced with a fresh one when it's old or big enough.
+///
+/// Finished archives are listed in the index, and thinned out or deleted as they age, according
+/// to the [`ArchiveConfig`]. Archives from before the index existed are left alone.
+pub struct Archive {
+    dir: PathBuf,
+    config: ArchiveConfig,
+    index: Vec<IndexEntry>,
+    current: Option<Current>,
+    /// unlocked when it's closed
+    _lock: fs::File,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct IndexEntry {
+    /// relative to the archive directory
+    file: String,
+    #[serde(with = "time::serde::rfc3339")]
+    first: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    last: OffsetDateTime,
+    observations: usize,
+    /// at most one observation every this many seconds is left, if it's been downsampled
+    downsampled_secs: Option<u64>,
+    /// the [`Observation::session_key`]s in it; empty if it's from before they were listed
+    #[serde(default)]
+    sessions: Vec<String>,
+}
+
+struct Current {
+    writer: CompressStream<'static, fs::File>,
+    // archives are read from the start, so can be mostly deltas; each one has its own
+    encoder: delta::Encoder,
+    opened: Instant,
+    /// without the [`PARTIAL_SUFFIX`]
+    file: String,
+    first: OffsetDateTime,
+    last: OffsetDateTime,
+    observations: usize,
+    sessions: BTreeSet<String>,
+}
+
+impl Archive {
+    pub fn new(dir: impl AsRef<Path>, config: &ArchiveConfig) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let lock = lock_dir(&dir)?;
+        let mut archive = Self {
+            index: read_index(&dir)?,
+            dir,
+            config: config.clone(),
+            current: None,
+            _lock: lock,
+        };
+        archive.recover()?;
+        archive.apply_retention()?;
+        Ok(archive)
+    }
+
+    pub fn write(&mut self, obs: &Observation) -> Result<()> {
+        if let Some(current) = &self.current {
+            if current.opened.elapsed().as_secs() >= self.config.rotate_secs {
+                self.finish()?;
+            }
+        }
+
+        let current = match &mut self.current {
+            Some(current) => current,
+            None => {
+                let file = file_for_now();
+                let path = self.dir.join(format!("{file}{PARTIAL_SUFFIX}"));
+                self.current.insert(Current {
+                    writer: archiv::CompressOptions::default()
+                        .stream_compress(fs::File::create(path)?)?,
+                    encoder: delta::Encoder::new(KEY_EVERY),
+                    opened: Instant::now(),
+                    file,
+                    first: obs.time,
+                    last: obs.time,
+                    observations: 0,
+                    sessions: BTreeSet::new(),
+                })
+            }
+        };
+
+        let packed = current.encoder.encode(obs)?;
+        current.writer.write_item(&packed)?;
+        current.writer.flush()?;
+        current.last = obs.time;
+        current.observations += 1;
+        current.sessions.insert(obs.session_key());
+
+        let written = current.writer.get_mut().metadata()?.len();
+        if written >= self.config.rotate_bytes {
+            self.finish()?;
+        }
+        Ok(())
+    }
+
+    /// complete the current archive, if there is one; the next write starts a new one
+    pub fn finish(&mut self) -> Result<()> {
+        let Some(current) = self.current.take() else {
+            return Ok(());
+        };
+        current.writer.finish()?.sync_all()?;
+        fs::rename(
+            self.dir.join(format!("{}{PARTIAL_SUFFIX}", current.file)),
+            self.dir.join(&current.file),
+        )?;
+        self.index.push(IndexEntry {
+            file: current.file,
+            first: current.first,
+            last: current.last,
+            observations: current.observations,
+            downsampled_secs: None,
+            sessions: current.sessions.into_iter().collect(),
+        });
+        self.save_index()?;
+        self.apply_retention()
+    }
+
+    /// finish any archives left partial by a crash, and clean up any interrupted rewrites
+    fn recover(&mut self) -> Result<()> {
+        let mut changed = false;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(TEMP_SUFFIX) {
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            let Some(file) = name.strip_suffix(PARTIAL_SUFFIX) else {
+                continue;
+            };
+            let dest = self.dir.join(file);
+            println!("recovering partial archive {:?}", entry.path());
+            if let Some(copied) = copy_archive(&entry.path(), &dest, None, true)? {
+                self.index.push(IndexEntry {
+                    file: file.to_string(),
+                    first: copied.first,
+                    last: copied.last,
+                    observations: copied.observations,
+                    downsampled_secs: None,
+                    sessions: copied.sessions.into_iter().collect(),
+                });
+                changed = true;
+            }
+            fs::remove_file(entry.path())?;
+        }
+        if changed {
+            self.index.sort_by_key(|e| e.first);
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    fn apply_retention(&mut self) -> Result<()> {
+        let now = OffsetDateTime::now_utc();
+        let older_than = |entry: &IndexEntry, secs: u64| {
+            secs > 0
+                && (now - entry.last).whole_seconds() >= i64::try_from(secs).unwrap_or(i64::MAX)
+        };
+
+        let mut changed = false;
+        let mut i = 0;
+        while i < self.index.len() {
+            let entry = &self.index[i];
+            let path = self.dir.join(&entry.file);
+            if older_than(entry, self.config.delete_after_secs) {
+                println!("deleting old archive {path:?}");
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => bail!(e),
+                    _ => (),
+                }
+                self.index.remove(i);
+                changed = true;
+                continue;
+            }
+            if entry.downsampled_secs.is_none()
+                && older_than(entry, self.config.downsample_after_secs)
+            {
+                let every = self.config.downsample_secs;
+                println!("downsampling old archive {path:?} to every {every}s");
+                let temp = self.dir.join(format!("{}{TEMP_SUFFIX}", entry.file));
+                let copied = copy_archive(&path, &temp, Some(every), false)?
+                    .ok_or_else(|| anyhow!("archive {path:?} was empty"))?;
+                fs::rename(&temp, &path)?;
+                let entry = &mut self.index[i];
+                entry.observations = copied.observations;
+                entry.downsampled_secs = Some(every);
+                entry.sessions = copied.sessions.into_iter().collect();
+                changed = true;
+            }
+            i += 1;
+        }
+
+        if changed {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    fn save_index(&self) -> Result<()> {
+        let temp = self.dir.join(format!("{INDEX_FILE}{TEMP_SUFFIX}"));
+        fs::write(&temp, serde_json::to_vec_pretty(&self.index)?)?;
+        fs::rename(&temp, self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+}
+
+/// Only one extractor can write archives to a directory: anything partial in it is assumed to have
+/// been left by a crash, and finished.
+fn lock_dir(dir: &Path) -> Result<fs::File> {
+    let path = dir.join(LOCK_FILE);
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| anyhow!("opening {path:?}"))?;
+    let ret =
+        unsafe { nix::libc::flock(file.as_raw_fd(), nix::libc::LOCK_EX | nix::libc::LOCK_NB) };
+    if ret == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::WouldBlock {
+            bail!(
+                "another extractor is writing archives to {dir:?}; give each its own --output-dir"
+            );
+        }
+        return Err(e).with_context(|| anyhow!("locking {path:?}"));
+    }
+    Ok(file)
+}
+
+struct Copied {
+    first: OffsetDateTime,
+    last: OffsetDateTime,
+    observations: usize,
+    sessions: BTreeSet<String>,
+}
+
+/// Re-encode `src` into a finished archive at `dest`, keeping at most one observation
+/// every `every` seconds, if set. `None` (and no `dest`) if there was nothing to copy.
+///
+/// With `torn_ok`, stop at the first unreadable item, instead of failing.
+fn copy_archive(
+    src: &Path,
+    dest: &Path,
+    every: Option<u64>,
+    torn_ok: bool,
+) -> Result<Option<Copied>> {
+    let mut encoder = delta::Encoder::new(KEY_EVERY);
+    let mut writer: Option<CompressStream<'static, fs::File>> = None;
+    let mut copied: Option<Copied> = None;
+
+    read_archive(src, torn_ok, |obs| {
+        if let (Some(copied), Some(every)) = (&copied, every) {
+            if obs.ts() < copied.last.unix_timestamp() + i64::try_from(every)? {
+                return Ok(true);
+            }
+        }
+
+        let writer = match &mut writer {
+            Some(writer) => writer,
+            None => writer.insert(
+                archiv::CompressOptions::default().stream_compress(fs::File::create(dest)?)?,
+            ),
+        };
+        writer.write_item(&encoder.encode(&obs)?)?;
+
+        match &mut copied {
+            Some(copied) => {
+                copied.last = obs.time;
+                copied.observations += 1;
+                copied.sessions.insert(obs.session_key());
+            }
+            None => {
+                copied = Some(Copied {
+                    first: obs.time,
+                    last: obs.time,
+                    observations: 1,
+                    sessions: [obs.session_key()].into(),
+                })
+            }
+        }
+        Ok(true)
+    })?;
+
+    if let Some(writer) = writer {
+        writer.finish()?.sync_all()?;
+    }
+    Ok(copied)
+}
+
+/// Call `f` with each observation in the archive at `path`, until it returns `false`.
+///
+/// With `torn_ok`, stop at the first unreadable item, instead of failing.
+fn read_archive(
+    path: &Path,
+    torn_ok: bool,
+    mut f: impl FnMut(Observation) -> Result<bool>,
+) -> Result<()> {
+    let mut reader =
+        archiv::ExpandOptions::default().stream(io::BufReader::new(fs::File::open(path)?))?;
+    let mut unpacker = Unpacker::default();
+    loop {
+        let obs = match reader.next_item() {
+            Ok(Some(item)) => unpacker.unpack(item),
+            Ok(None) => return Ok(()),
+            Err(e) => Err(e.into()),
+        };
+        let obs = match obs {
+            Ok(obs) => obs,
+            Err(e) if torn_ok => {
+                println!("stopping at an unreadable item in {path:?}: {e:?}");
+                return Ok(());
+            }
+            Err(e) => return Err(e.context(anyhow!("reading {path:?}"))),
+        };
+        if !f(obs)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Every session with observations in the archives in `dir`, including the one being written.
+pub fn sessions(dir: &Path) -> Result<BTreeSet<String>> {
+    let index = read_index(dir)?;
+    let mut ret = BTreeSet::new();
+    for name in archive_names(dir)? {
+        match index
+            .iter()
+            .find(|e| e.file == name && !e.sessions.is_empty())
+        {
+            Some(entry) => ret.extend(entry.sessions.iter().cloned()),
+            // partial, or from before the index listed them
+            None => read_archive(&dir.join(name), true, |obs| {
+                ret.insert(obs.session_key());
+                Ok(true)
+            })?,
+        }
+    }
+    Ok(ret)
+}
+
+/// Up to `limit` observations from the sessions in `after`, each after its time there (and all
+/// before `before`), oldest first, from the archives in `dir`, including the one being written.
+pub fn read_since(
+    dir: &Path,
+    after: &BTreeMap<String, OffsetDateTime>,
+    before: Option<OffsetDateTime>,
+    limit: usize,
+) -> Result<Vec<Observation>> {
+    let Some(&earliest) = after.values().min() else {
+        return Ok(Vec::new());
+    };
+    let index = read_index(dir)?;
+    let mut ret = Vec::new();
+    let mut done = false;
+    for name in archive_names(dir)? {
+        if let Some(entry) = index.iter().find(|e| e.file == name) {
+            if entry.last <= earliest {
+                continue;
+            }
+        }
+        read_archive(&dir.join(name), true, |obs| {
+            if before.is_some_and(|before| obs.time >= before) {
+                done = true;
+            } else if after
+                .get(&obs.session_key())
+                .is_some_and(|after| obs.time > *after)
+            {
+                ret.push(obs);
+            }
+            done |= ret.len() >= limit;
+            Ok(!done)
+        })?;
+        if done {
+            break;
+        }
+    }
+    Ok(ret)
+}
+
+/// the archives in `dir`, finished or not, oldest first
+fn archive_names(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        let file = name.strip_suffix(PARTIAL_SUFFIX).unwrap_or(&name);
+        if file.ends_with(EXTENSION) {
+            names.push(name);
+        }
+    }
+    // the names start with the time they were opened
+    names.sort_unstable();
+    Ok(names)
+}
+
+fn read_index(dir: &Path) -> Result<Vec<IndexEntry>> {
+    match fs::read(dir.join(INDEX_FILE)) {
+        Ok(buf) => serde_json::from_slice(&buf)
+            .with_context(|| anyhow!("reading the archive index in {dir:?}")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => bail!(e),
+    }
+}
+
+fn file_for_now() -> String {
+    let time = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .expect("static formatter");
+    format!("{time}{EXTENSION}")
+}
+
+#[cfg(test)]
+mod test {
+    use facto_exporter::{CraftingLite, UNKNOWN_SESSION};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("archive-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// nothing rotates or ages out
+    fn keep_everything() -> ArchiveConfig {
+        ArchiveConfig {
+            rotate_secs: u64::MAX,
+            rotate_bytes: u64::MAX,
+            downsample_after_secs: 0,
+            downsample_secs: 1,
+            delete_after_secs: 0,
+        }
+    }
+
+    /// the whole of the sessionless observations
+    fn everything() -> BTreeMap<String, OffsetDateTime> {
+        [(UNKNOWN_SESSION.to_string(), OffsetDateTime::UNIX_EPOCH)].into()
+    }
+
+    fn obs(time: OffsetDateTime) -> Observation {
+        Observation {
+            time,
+            session: None,
+            tick: None,
+            inner: vec![CraftingLite {
+                unit_number: 1,
+                products_complete: 1,
+                status: 1,
+            }],
+        }
+    }
+
+    /// one finished archive, of observations a second apart, the last `age_secs` ago
+    fn write_old(dir: &Path, count: i64, age_secs: i64) -> Result<()> {
+        let mut archive = Archive::new(dir, &keep_everything())?;
+        let last = OffsetDateTime::now_utc() - time::Duration::seconds(age_secs);
+        for i in (0..count).rev() {
+            archive.write(&obs(last - time::Duration::seconds(i)))?;
+        }
+        archive.finish()
+    }
+
+    fn observations(dir: &Path) -> Result<Vec<usize>> {
+        Ok(read_index(dir)?.iter().map(|e| e.observations).collect())
+    }
+
+    #[test]
+    fn one_writer_per_dir() -> Result<()> {
+        let dir = temp_dir("lock");
+        let mut archive = Archive::new(&dir, &keep_everything())?;
+        archive.write(&obs(OffsetDateTime::now_utc()))?;
+        // which would otherwise finish the other's partial archive
+        assert!(Archive::new(&dir, &keep_everything()).is_err());
+        assert!(read_index(&dir)?.is_empty());
+        drop(archive);
+
+        Archive::new(&dir, &keep_everything())?;
+        assert_eq!(vec![1], observations(&dir)?);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn rotates_on_size_and_age() -> Result<()> {
+        let dir = temp_dir("rotate");
+        let now = OffsetDateTime::now_utc();
+
+        let mut archive = Archive::new(&dir, &keep_everything())?;
+        for _ in 0..3 {
+            archive.write(&obs(now))?;
+        }
+        assert!(read_index(&dir)?.is_empty(), "nothing finished yet");
+        archive.finish()?;
+        assert_eq!(vec![3], observations(&dir)?);
+        drop(archive);
+
+        // every write fills it
+        let mut archive = Archive::new(
+            &dir,
+            &ArchiveConfig {
+                rotate_bytes: 1,
+                ..keep_everything()
+            },
+        )?;
+        archive.write(&obs(now))?;
+        archive.write(&obs(now))?;
+        assert_eq!(vec![3, 1, 1], observations(&dir)?);
+        drop(archive);
+
+        // every write finds it old enough, but only after writing to it once
+        let mut archive = Archive::new(
+            &dir,
+            &ArchiveConfig {
+                rotate_secs: 0,
+                ..keep_everything()
+            },
+        )?;
+        archive.write(&obs(now))?;
+        assert_eq!(3, read_index(&dir)?.len());
+        archive.write(&obs(now))?;
+        archive.write(&obs(now))?;
+        archive.finish()?;
+        assert_eq!(vec![3, 1, 1, 1, 1, 1], observations(&dir)?);
+
+        // every one of them can be read back
+        let back = read_since(&dir, &everything(), None, 100)?;
+        assert_eq!(8, back.len());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn every_session_listed() -> Result<()> {
+        let dir = temp_dir("sessions");
+        let now = OffsetDateTime::now_utc();
+        let in_session = |pid, secs| Observation {
+            session: Some(facto_exporter::Session {
+                pid,
+                build_id: None,
+                save_name: None,
+                started: OffsetDateTime::UNIX_EPOCH,
+            }),
+            ..obs(now + time::Duration::seconds(secs))
+        };
+
+        let mut archive = Archive::new(&dir, &keep_everything())?;
+        archive.write(&in_session(1, 0))?;
+        archive.write(&in_session(2, 1))?;
+        archive.finish()?;
+        // and one still being written
+        archive.write(&in_session(1, 2))?;
+        archive.write(&in_session(3, 3))?;
+        assert_eq!(vec!["1-0", "2-0"], read_index(&dir)?[0].sessions);
+        assert_eq!(
+            BTreeSet::from(["1-0", "2-0", "3-0"].map(String::from)),
+            sessions(&dir)?
+        );
+
+        // each from its own time
+        let after = [("1-0".to_string(), now), ("3-0".to_string(), now)].into();
+        let back = read_since(&dir, &after, None, 100)?;
+        assert_eq!(
+            vec!["1-0", "3-0"],
+            back.iter().map(|o| o.session_key()).collect::<Vec<_>>()
+        );
+        drop(archive);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn recovers_partial() -> Result<()> {
+        let dir = temp_dir("partial");
+        let mut archive = Archive::new(&dir, &keep_everything())?;
+        archive.write(&obs(OffsetDateTime::now_utc()))?;
+        archive.write(&obs(OffsetDateTime::now_utc()))?;
+        // as if we'd crashed
+        drop(archive);
+        assert!(read_index(&dir)?.is_empty());
+
+        Archive::new(&dir, &keep_everything())?;
+        assert_eq!(vec![2], observations(&dir)?);
+        let partials = fs::read_dir(&dir)?
+            .filter(|e| {
+                e.as_ref()
+                    .is_ok_and(|e| e.file_name().to_string_lossy().ends_with(PARTIAL_SUFFIX))
+            })
+            .count();
+        assert_eq!(0, partials);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_once_old_enough() -> Result<()> {
+        let dir = temp_dir("delete");
+        write_old(&dir, 3, 100)?;
+        let file = dir.join(&read_index(&dir)?[0].file);
+
+        let delete_after = |secs| ArchiveConfig {
+            delete_after_secs: secs,
+            ..keep_everything()
+        };
+        Archive::new(&dir, &delete_after(101))?;
+        assert_eq!(vec![3], observations(&dir)?);
+        assert!(file.exists());
+
+        Archive::new(&dir, &delete_after(100))?;
+        assert!(observations(&dir)?.is_empty());
+        assert!(!file.exists());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn downsampled_once_old_enough() -> Result<()> {
+        let dir = temp_dir("downsample");
+        write_old(&dir, 10, 100)?;
+
+        let downsample_after = |secs| ArchiveConfig {
+            downsample_after_secs: secs,
+            downsample_secs: 5,
+            ..keep_everything()
+        };
+        Archive::new(&dir, &downsample_after(101))?;
+        assert_eq!(vec![10], observations(&dir)?);
+
+        // the first, and the one five seconds after it
+        Archive::new(&dir, &downsample_after(100))?;
+        assert_eq!(vec![2], observations(&dir)?);
+        let back = read_since(&dir, &everything(), None, 100)?;
+        assert_eq!(5, (back[1].time - back[0].time).whole_seconds());
+
+        // and only once
+        Archive::new(
+            &dir,
+            &ArchiveConfig {
+                downsample_secs: 60,
+                ..downsample_after(1)
+            },
+        )?;
+        assert_eq!(vec![2], observations(&dir)?);
+        assert_eq!(Some(5), read_index(&dir)?[0].downsampled_secs);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
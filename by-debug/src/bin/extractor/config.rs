@@ -0,0 +1,235 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use clap::Parser;
+use facto_exporter::debug::offsets::OffsetOverrides;
+
+/// Attach to a running Factorio, and export crafting machine statistics.
+///
+/// Settings come from the defaults, then the `--config` file (if any), then the flags.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Args {
+    /// the Factorio binary, used for symbols and to find the process
+    pub bin_path: Option<PathBuf>,
+
+    /// a TOML file with any of the settings below
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+
+    /// attach to this process, instead of searching for the binary
+    #[arg(long, conflicts_with = "all")]
+    pub pid: Option<i32>,
+
+    /// attach to every process running the binary, with an archive directory for each
+    #[arg(long)]
+    pub all: bool,
+
+    /// when the game exits, wait for the binary to be started again, and attach to that
+    #[arg(long, conflicts_with = "all")]
+    pub reattach: bool,
+
+    /// list the processes running the binary, and exit
+    #[arg(long)]
+    pub list: bool,
+
+    /// check the binary against every known profile, show what's missing, and exit
+    #[arg(long, conflicts_with = "list")]
+    pub check: bool,
+
+    /// take an observation every this many game ticks
+    #[arg(long)]
+    pub interval_ticks: Option<u64>,
+    /// where to write archives, one extractor to each; the binary's symbols and struct offsets are
+    /// cached in `cache` under here
+    #[arg(long)]
+    pub output_dir: Option<PathBuf>,
+
+    /// POST each observation here; can be repeated, replaces any from the config file
+    #[arg(long = "upload", value_name = "URL")]
+    pub uploads: Vec<String>,
+
+    /// don't upload anywhere, only write archives
+    #[arg(long, conflicts_with = "uploads")]
+    pub no_upload: bool,
+
+    /// start a new archive after this many seconds
+    #[arg(long)]
+    pub rotate_secs: Option<u64>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bin_path: Option<PathBuf>,
+    pub pid: Option<i32>,
+    pub all: bool,
+    /// wait for a new process, after the one we're attached to exits; not with `all`
+    pub reattach: bool,
+    #[serde(skip)]
+    pub list: bool,
+    #[serde(skip)]
+    pub check: bool,
+    /// 60 ticks is a game second (at normal speed), so every seven game seconds
+    pub interval_ticks: u64,
+    pub output_dir: PathBuf,
+    pub uploads: Vec<String>,
+    pub archive: ArchiveConfig,
+    pub upload: UploadConfig,
+    pub tick: TickConfig,
+    /// where the fields are in the game's structs, in bytes; these are normally found by
+    /// disassembling the binary (or pinned by its profile), so only set them if that gets it wrong
+    pub offsets: OffsetOverrides,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+    pub rotate_secs: u64,
+    /// start a new archive once the current one is this big, on disk
+    pub rotate_bytes: u64,
+    /// thin out archives once they're this old, zero for never
+    pub downsample_after_secs: u64,
+    /// keeping one observation every this many seconds
+    pub downsample_secs: u64,
+    /// delete archives once they're this old, zero for never
+    pub delete_after_secs: u64,
+}
+
+/// Observations waiting to be uploaded are spooled to disk, in `spool` under the output directory.
+#[derive(serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    /// for each url; past this, the oldest are dropped (and left for the backfill)
+    pub spool_max: usize,
+    /// the longest to wait between retries, while a server is failing
+    pub max_backoff_secs: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            spool_max: 100_000,
+            max_backoff_secs: 300,
+        }
+    }
+}
+
+/// Where to find the game tick, instead of the profile's; only used if `symbol` is set.
+///
+/// `symbol` is a variable, mangled, or demangled like `Foo::bar`. Its address is read as a pointer, and the offset added, for each of `offsets`;
+/// the tick is the `u32` at the end of that.
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TickConfig {
+    pub symbol: Option<String>,
+    pub offsets: Vec<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bin_path: None,
+            pid: None,
+            all: false,
+            reattach: false,
+            list: false,
+            check: false,
+            interval_ticks: 60 * 7,
+            output_dir: PathBuf::from("."),
+            uploads: vec!["http://localhost:9429/exp/store".to_string()],
+            archive: ArchiveConfig::default(),
+            upload: UploadConfig::default(),
+            tick: TickConfig::default(),
+            offsets: OffsetOverrides::default(),
+        }
+    }
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        ArchiveConfig {
+            rotate_secs: 3600,
+            rotate_bytes: 64 * 1024 * 1024,
+            downsample_after_secs: 0,
+            downsample_secs: 300,
+            delete_after_secs: 0,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config> {
+        Config::from_args(Args::parse())
+    }
+
+    pub fn from_args(args: Args) -> Result<Config> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let s = fs::read_to_string(path).with_context(|| anyhow!("reading {path:?}"))?;
+                toml::from_str(&s).with_context(|| anyhow!("parsing {path:?}"))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(bin_path) = args.bin_path {
+            config.bin_path = Some(bin_path);
+        }
+        if let Some(pid) = args.pid {
+            config.pid = Some(pid);
+        }
+        if args.all {
+            config.all = true;
+        }
+        if args.reattach {
+            config.reattach = true;
+        }
+        config.list = args.list;
+        config.check = args.check;
+        if let Some(interval_ticks) = args.interval_ticks {
+            config.interval_ticks = interval_ticks;
+        }
+        if let Some(output_dir) = args.output_dir {
+            config.output_dir = output_dir;
+        }
+        if !args.uploads.is_empty() {
+            config.uploads = args.uploads;
+        }
+        if args.no_upload {
+            config.uploads.clear();
+        }
+        if let Some(rotate_secs) = args.rotate_secs {
+            config.archive.rotate_secs = rotate_secs;
+        }
+
+        ensure!(
+            !(config.all && config.pid.is_some()),
+            "can't have both all and a pid"
+        );
+        ensure!(!(config.all && config.reattach), "can't re-attach with all");
+        ensure!(config.interval_ticks > 0, "interval_ticks must be positive");
+        ensure!(
+            config.archive.rotate_secs > 0,
+            "archive.rotate_secs must be positive"
+        );
+        ensure!(
+            config.archive.rotate_bytes > 0,
+            "archive.rotate_bytes must be positive"
+        );
+        ensure!(
+            config.upload.spool_max > 0,
+            "upload.spool_max must be positive"
+        );
+        ensure!(
+            config.upload.max_backoff_secs > 0,
+            "upload.max_backoff_secs must be positive"
+        );
+        ensure!(
+            config.archive.downsample_secs > 0,
+            "archive.downsample_secs must be positive"
+        );
+
+        Ok(config)
+    }
+}
@@ -0,0 +1,650 @@
+mod archive;
+mod config;
+mod upload;
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use std::{fs, thread};
+
+use anyhow::anyhow;
+use anyhow::{ensure, Result};
+use nix::libc::c_long;
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use time::OffsetDateTime;
+
+use facto_exporter::debug::breakpoints::BreakpointId;
+use facto_exporter::debug::elf::{
+    build_id, debug_file, find_candidates, find_pid, find_thread, Candidate,
+};
+use facto_exporter::debug::offsets::{discover, discover_cached, OffsetOverrides, Offsets};
+use facto_exporter::debug::profile::{game_version, profiles, select, TickPath};
+use facto_exporter::debug::ptrace::{read_words_arr, run_until_stop, Gone};
+use facto_exporter::debug::symbols::SymbolIndex;
+use facto_exporter::debug::tracee::Tracee;
+use facto_exporter::{pack_observation, CraftingLite, Observation, SessionTracker, TickCheck};
+
+use crate::archive::Archive;
+use crate::config::{Config, TickConfig};
+use crate::upload::{spool_name, Spool, Uploader};
+
+fn main() -> Result<()> {
+    let started = OffsetDateTime::now_utc();
+    let config = Config::load()?;
+    assert_eq!(std::mem::size_of::<c_long>(), 8);
+    let bin_path = match (&config.bin_path, config.pid) {
+        (Some(bin_path), _) => fs::canonicalize(bin_path)?,
+        (None, Some(pid)) => fs::read_link(format!("/proc/{pid}/exe"))?,
+        (None, None) => return Err(anyhow!("usage: bin path, or --pid")),
+    };
+
+    if config.list {
+        for candidate in find_candidates(&bin_path)? {
+            println!(
+                "{}\t{}\t{}",
+                candidate.pid,
+                candidate.cwd.display(),
+                candidate.cmdline.join(" ")
+            );
+        }
+        return Ok(());
+    }
+
+    if config.check {
+        return check(&bin_path, &config.output_dir.join("cache"));
+    }
+
+    let targets = match config.pid {
+        Some(pid) => vec![Candidate::from_pid(Pid::from_raw(pid))?],
+        None if config.all => find_candidates(&bin_path)?,
+        None => vec![Candidate::from_pid(find_pid(&bin_path)?)?],
+    };
+    ensure!(!targets.is_empty(), "no processes found for {bin_path:?}");
+
+    println!("loading symbols from {bin_path:?}...");
+    let build_id = build_id(&bin_path)?;
+    let symbols = resolve_symbols(
+        &bin_path,
+        &config.output_dir.join("cache"),
+        build_id.as_deref(),
+        &config.tick,
+        &config.offsets,
+    )?;
+
+    let term = Arc::new(AtomicBool::new(false));
+    // anything that'd otherwise kill us while the game is stopped, or has our breakpoints in
+    for signal in [SIGINT, SIGTERM, SIGHUP, SIGQUIT] {
+        signal_hook::flag::register(signal, Arc::clone(&term))?;
+    }
+
+    // for the uploads; the sessions themselves block on ptrace, so get their own threads
+    let runtime = tokio::runtime::Runtime::new()?;
+    let config = Arc::new(config);
+
+    let separate_dirs = config.all;
+    let mut sessions = Vec::with_capacity(targets.len());
+    for target in targets {
+        let label = target.label();
+        println!(
+            "[{label}] supervising pid {} in {:?}",
+            target.pid, target.cwd
+        );
+        let output_dir = if separate_dirs {
+            config.output_dir.join(&label)
+        } else {
+            config.output_dir.clone()
+        };
+        let identity = facto_exporter::Session {
+            pid: u32::try_from(target.pid.as_raw())?,
+            build_id: build_id.clone(),
+            save_name: target.save_name(),
+            started,
+        };
+        let session = Session {
+            config: Arc::clone(&config),
+            bin_path: bin_path.clone(),
+            pid: target.pid,
+            identity,
+            output_dir,
+            symbols: symbols.clone(),
+            term: Arc::clone(&term),
+            runtime: runtime.handle().clone(),
+        };
+        let handle = thread::Builder::new()
+            .name(label.clone())
+            .spawn(move || session.supervise())?;
+        sessions.push((label, handle));
+    }
+
+    let mut failed = 0;
+    for (label, handle) in sessions {
+        match handle.join() {
+            Ok(Ok(())) => println!("[{label}] finished"),
+            Ok(Err(e)) => {
+                eprintln!("[{label}] failed: {e:?}");
+                failed += 1;
+            }
+            Err(_) => {
+                eprintln!("[{label}] panicked");
+                failed += 1;
+            }
+        }
+    }
+
+    ensure!(failed == 0, "{failed} session(s) failed");
+    Ok(())
+}
+
+/// Show how the binary matches up against every profile, and what we'd find by ourselves.
+fn check(bin_path: &Path, cache_dir: &Path) -> Result<()> {
+    let build_id = build_id(bin_path)?;
+    let version = game_version(bin_path)?;
+    println!("{bin_path:?}, build id {build_id:?}, version {version:?}");
+    match debug_file(bin_path)? {
+        Some(path) => println!("debug info in {path:?}"),
+        None => println!("no debug info"),
+    }
+    let symtab = SymbolIndex::open_cached(bin_path, cache_dir)?;
+    let profiles = profiles()?;
+
+    for profile in &profiles {
+        let listed = build_id
+            .as_ref()
+            .is_some_and(|id| profile.build_ids.iter().any(|p| p.eq_ignore_ascii_case(id)))
+            || version
+                .as_ref()
+                .is_some_and(|v| profile.versions.contains(v));
+        let missing = profile.missing(&symtab);
+        println!(
+            "\n{}: {}{}",
+            profile.name,
+            if missing.is_empty() {
+                "usable".to_string()
+            } else {
+                format!("missing {}", missing.join(", "))
+            },
+            if listed {
+                ", and lists this build id or version"
+            } else {
+                ""
+            },
+        );
+        for (field, found) in profile.lookup(&symtab) {
+            match found {
+                Some((name, addr)) => println!("  {field}: 0x{addr:x} {name}"),
+                None => println!("  {field}: MISSING"),
+            }
+        }
+        match &profile.tick {
+            Some(tick) => match symtab.find_data(&tick.symbol) {
+                Ok(found) => println!(
+                    "  tick: 0x{:x} {}, then {:x?}",
+                    found.addr, found.raw, tick.offsets
+                ),
+                Err(_) => println!("  tick: MISSING"),
+            },
+            None => println!("  tick: unknown"),
+        }
+    }
+
+    println!();
+    let discovered = discover(bin_path, symtab.table())?;
+    println!("discovered offsets: {:x?}", discovered.offsets);
+    if !discovered.guessed.is_empty() {
+        println!("  not found, so guessed: {:?}", discovered.guessed);
+    }
+
+    let selected = select(&profiles, build_id.as_deref(), version.as_deref(), &symtab)
+        .map_err(|_| anyhow!("no profile matches this binary"))?;
+    println!("\nwould use {}", selected.name);
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Symbols {
+    /// somewhere we can briefly overwrite, to run the mmap stage; it's put back straight away
+    scratch: u64,
+    crafting_status: u64,
+    crafting_insert: u64,
+    game_update_step: u64,
+    /// the start of the pointer chain to the tick, see [`TickPath`]
+    tick: Option<u64>,
+    tick_offsets: Vec<u64>,
+    offsets: Offsets,
+}
+
+fn resolve_symbols(
+    bin_path: &Path,
+    cache_dir: &Path,
+    build_id: Option<&str>,
+    tick_config: &TickConfig,
+    overrides: &OffsetOverrides,
+) -> Result<Symbols> {
+    let symtab = SymbolIndex::open_cached(bin_path, cache_dir)?;
+
+    let profiles = profiles()?;
+    let version = game_version(bin_path)?;
+    let profile = select(&profiles, build_id, version.as_deref(), &symtab)?;
+    println!("using the symbols for {}", profile.name);
+    let resolved = profile.resolve(&symtab)?;
+
+    println!("found main() at 0x{:x}", resolved.scratch);
+    println!(
+        "found crafting_insert() at 0x{:x}",
+        resolved.crafting_insert
+    );
+
+    // the config's, then the profile's
+    let tick_path = match &tick_config.symbol {
+        Some(symbol) => Some(TickPath {
+            symbol: symbol.clone(),
+            offsets: tick_config.offsets.clone(),
+        }),
+        None => profile.tick.clone(),
+    };
+    let (tick, tick_offsets) = match tick_path {
+        Some(path) => match symtab.find_data(&path.symbol) {
+            Ok(found) => {
+                println!("found the tick's {} at 0x{:x}", found.raw, found.addr);
+                (Some(found.addr), path.offsets)
+            }
+            // a profile might cover builds which have it somewhere else
+            Err(e) if tick_config.symbol.is_none() => {
+                println!("{e}, for the profile's tick; only recording wall-clock time");
+                (None, Vec::new())
+            }
+            Err(e) => return Err(e),
+        },
+        None => {
+            println!("no tick symbol known, only recording wall-clock time");
+            (None, Vec::new())
+        }
+    };
+
+    let discovered = discover_cached(bin_path, cache_dir, symtab.table())?;
+    if !discovered.guessed.is_empty() {
+        println!(
+            "couldn't find {:?} in the binary, guessing they're where they were in 1.1",
+            discovered.guessed
+        );
+    }
+    let mut offsets = discovered.offsets;
+    profile.offsets.apply(&mut offsets);
+    overrides.apply(&mut offsets);
+    println!("using struct offsets {offsets:x?}");
+
+    Ok(Symbols {
+        scratch: resolved.scratch,
+        crafting_status: resolved.crafting_status,
+        crafting_insert: resolved.crafting_insert,
+        game_update_step: resolved.game_update_step,
+        tick,
+        tick_offsets,
+        offsets,
+    })
+}
+
+/// How long to wait between looking for a new process, when re-attaching.
+const REATTACH_POLL: Duration = Duration::from_secs(2);
+
+/// One traced Factorio process, with its own archive.
+struct Session {
+    config: Arc<Config>,
+    bin_path: PathBuf,
+    pid: Pid,
+    /// stamped on every observation
+    identity: facto_exporter::Session,
+    output_dir: PathBuf,
+    symbols: Symbols,
+    term: Arc<AtomicBool>,
+    runtime: tokio::runtime::Handle,
+}
+
+/// Why a [`Session::run`] finished, if it didn't fail.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Finished {
+    /// we were asked to, or couldn't write the archive
+    Stopped,
+    /// the game exited, or was killed
+    Gone,
+}
+
+impl Session {
+    /// Run, then again for each new process of the binary, if we're re-attaching.
+    fn supervise(mut self) -> Result<()> {
+        while self.run()? == Finished::Gone && self.config.reattach {
+            println!("waiting for {:?} to be started again...", self.bin_path);
+            let Some(target) = self.wait_for_game()? else {
+                return Ok(());
+            };
+            let build_id = build_id(&self.bin_path)?;
+            ensure!(
+                build_id == self.identity.build_id,
+                "the binary has changed, from build id {:?} to {build_id:?}; the symbols need finding again",
+                self.identity.build_id
+            );
+            println!("re-attaching to pid {} in {:?}", target.pid, target.cwd);
+            self.pid = target.pid;
+            self.identity = facto_exporter::Session {
+                pid: u32::try_from(target.pid.as_raw())?,
+                build_id,
+                save_name: target.save_name(),
+                started: OffsetDateTime::now_utc(),
+            };
+        }
+        Ok(())
+    }
+
+    /// the first process running the binary which has got as far as starting its game thread, or
+    /// `None` if we're asked to stop first
+    fn wait_for_game(&self) -> Result<Option<Candidate>> {
+        while !self.term.load(Ordering::SeqCst) {
+            for candidate in find_candidates(&self.bin_path)? {
+                if find_thread(candidate.pid, "GameUpdate").is_ok() {
+                    return Ok(Some(candidate));
+                }
+            }
+            thread::sleep(REATTACH_POLL);
+        }
+        Ok(None)
+    }
+
+    fn run(&self) -> Result<Finished> {
+        let config = &self.config;
+        let symbols = self.symbols.clone();
+        let archive = Archive::new(&self.output_dir, &config.archive)?;
+
+        let mut spools = Vec::with_capacity(config.uploads.len());
+        let mut uploaders = Vec::with_capacity(config.uploads.len());
+        for url in &config.uploads {
+            let spool = Arc::new(Spool::open(
+                self.output_dir.join("spool").join(spool_name(url)),
+                config.upload.spool_max,
+            )?);
+            uploaders.push(
+                self.runtime.spawn(
+                    Uploader {
+                        url: url.clone(),
+                        spool: Arc::clone(&spool),
+                        max_backoff: Duration::from_secs(config.upload.max_backoff_secs),
+                        archive_dir: self.output_dir.clone(),
+                    }
+                    .run(),
+                ),
+            );
+            spools.push(spool);
+        }
+
+        let game_update = find_thread(self.pid, "GameUpdate")?;
+        println!("found GameUpdate thread {game_update}");
+
+        // from here, dropping this (on any error) puts the game back as we found it; every thread
+        // is traced, so none of them trips over an `int3`
+        let mut tracee = Tracee::attach_group(self.pid, game_update)?;
+
+        // set if writing the archive fails, just for this session
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // i.e. go back around the loop and continue doing nothing while this is writing;
+        // one writer, so everything is archived and spooled in the order it was observed
+        let (observed, to_write) = mpsc::channel();
+        let writer = {
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("writer".to_string())
+                .spawn(move || write_observations(to_write, archive, spools, stop))?
+        };
+
+        let insert_breakpoint = tracee.add_breakpoint(symbols.crafting_insert)?;
+        tracee.add_breakpoint(symbols.game_update_step)?;
+
+        println!("debugging, waiting for an assembler place...");
+
+        let mut state = BodyState {
+            tracee,
+            insert_breakpoint,
+            set_base: 0,
+            // these are internally consistent, even though they're nonsense
+            // I don't really care about games with no assemblers
+            set_size: 0,
+            hits: 0,
+            interval_ticks: config.interval_ticks,
+            symbols,
+            sessions: SessionTracker::new(self.identity.clone()),
+            ticks: TickCheck::default(),
+        };
+
+        // errors leave the loop with the game in any state; the tracee sorts that out on detach
+        let looped = (|| -> Result<()> {
+            while !self.term.load(Ordering::SeqCst) && !stop.load(Ordering::SeqCst) {
+                let hit = state.tracee.run_until_hit()?;
+
+                let start = Instant::now();
+                let obs = match observe(&mut state, hit) {
+                    Ok(Some(obs)) => {
+                        println!("observed in {:?}", start.elapsed());
+                        obs
+                    }
+                    Ok(None) => continue,
+                    Err(e) if e.is::<Gone>() => return Err(e),
+                    Err(e) => {
+                        println!("error: {:?}", e);
+                        break;
+                    }
+                };
+
+                if observed.send(obs).is_err() {
+                    // the writer's only gone if it panicked
+                    break;
+                }
+            }
+            Ok(())
+        })();
+
+        // nothing to put back, and nobody to put it back in
+        let gone = match &looped {
+            Err(e) if e.is::<Gone>() => {
+                println!("the game has gone: {e}");
+                true
+            }
+            _ => false,
+        };
+        let detached = if gone {
+            state.tracee.forget();
+            Ok(())
+        } else {
+            println!("detaching...");
+            state.tracee.detach()
+        };
+
+        // it finishes the archive once it's written everything we sent it
+        drop(observed);
+        match writer.join() {
+            Ok(finished) => finished?,
+            Err(_) => eprintln!("writer panicked, ignoring for shutdown"),
+        }
+        // whatever's still spooled is picked up by the next session's, or the next run's
+        for uploader in uploaders {
+            uploader.abort();
+            let _ = self.runtime.block_on(uploader);
+        }
+
+        if gone {
+            return Ok(Finished::Gone);
+        }
+        looped?;
+        detached?;
+        Ok(Finished::Stopped)
+    }
+}
+
+/// Archive then spool each observation, until the session hangs up; then finish the archive.
+fn write_observations(
+    to_write: mpsc::Receiver<Observation>,
+    mut archive: Archive,
+    spools: Vec<Arc<Spool>>,
+    stop: Arc<AtomicBool>,
+) -> Result<()> {
+    for obs in to_write {
+        if let Err(e) = archive.write(&obs) {
+            eprintln!("archiv error: {:?}", e);
+            stop.store(true, Ordering::SeqCst);
+        }
+
+        // uploads have to stand alone, unlike archive entries
+        let packed = match pack_observation(&obs) {
+            Ok(packed) => packed,
+            Err(e) => {
+                eprintln!("packing error: {:?}", e);
+                continue;
+            }
+        };
+        for spool in &spools {
+            if let Err(e) = spool.push(&packed) {
+                eprintln!("spool error: {:?}", e);
+            }
+        }
+    }
+    archive.finish()
+}
+
+struct BodyState {
+    /// with the shell injected into it at the first observation, as we need to be stopped somewhere sensible
+    tracee: Tracee,
+    insert_breakpoint: BreakpointId,
+    set_base: u64,
+    // re-read the data iff there's an insert, or the size has changed
+    set_size: u64,
+    hits: u64,
+    interval_ticks: u64,
+    symbols: Symbols,
+    /// stamped on every observation; replaced when a different game is loaded
+    sessions: SessionTracker,
+    ticks: TickCheck,
+}
+
+fn observe(state: &mut BodyState, hit: Option<BreakpointId>) -> Result<Option<Observation>> {
+    let pid = state.tracee.pid();
+    let regs = ptrace::getregs(pid)?;
+
+    if hit == Some(state.insert_breakpoint) {
+        println!(
+            "hit place: old base: {:x}, new base: {:x}",
+            state.set_base, regs.rdi
+        );
+        state.set_base = regs.rdi;
+        // we can't update the actual data here, 'cos we know it is just about to change,
+        // just leave this as "before" data, so the next tick re-reads the full data
+        state.set_size = read_set_size(state)?;
+    }
+
+    state.hits += 1;
+
+    // only work every N game ticks (N/60 real seconds at 60UPS, N/30 at 30UPS)
+    if !state.hits.is_multiple_of(state.interval_ticks) {
+        return Ok(None);
+    }
+    if state.set_base == 0 {
+        return Ok(None);
+    }
+
+    // a bad tick path shouldn't stop us collecting everything else
+    let tick = match read_tick(state) {
+        Ok(Some(tick)) => {
+            let checked = state.ticks.check(OffsetDateTime::now_utc(), tick);
+            if checked.is_none() {
+                println!("leaving out tick {tick}, which doesn't follow on from the last");
+            }
+            checked
+        }
+        Ok(None) => None,
+        Err(e) => {
+            println!("couldn't read the tick: {e:?}");
+            None
+        }
+    };
+
+    if state.tracee.shell().is_none() {
+        println!("injecting the shell, using 0x{:x}", state.symbols.scratch);
+        let shell = state.tracee.inject_shell(state.symbols.scratch)?;
+        shell.set_get_status_addr(state.symbols.crafting_status)?;
+        shell.set_offsets(&state.symbols.offsets)?;
+        println!("shell injected at 0x{:x}", shell.map_addr);
+    }
+
+    state.tracee.save_regs()?;
+    let shell = state.tracee.shell().expect("just injected");
+    shell.set_set_addr(state.set_base)?;
+    shell.enter()?;
+    run_until_stop(pid)?;
+    let stopped_at = ptrace::getregs(pid)?.rip;
+    let craftings = shell.read_craftings();
+    let map_addr = shell.map_addr;
+    // jump back, whatever happened
+    state.tracee.restore_regs()?;
+
+    // just after the int3 at the end of the shell, not some breakpoint in the middle
+    ensure!(
+        (map_addr..map_addr + 8).contains(&stopped_at),
+        "shell stopped at 0x{stopped_at:x}, not at its end"
+    );
+
+    let mut lites = craftings?
+        .into_iter()
+        .map(|c| CraftingLite {
+            unit_number: c.unit,
+            products_complete: c.products,
+            status: c.status,
+        })
+        .collect::<Vec<_>>();
+
+    lites.sort_unstable_by_key(|l| l.unit_number);
+
+    let now = OffsetDateTime::now_utc();
+    // the command line is all we know the save from, which only changes with the process
+    let save_name = match Candidate::from_pid(pid) {
+        Ok(candidate) => candidate.save_name(),
+        Err(_) => state.sessions.current().save_name.clone(),
+    };
+    let (session, changed) = state.sessions.observe(now, tick, state.set_base, save_name);
+    if changed {
+        println!(
+            "a different game was loaded, starting session {}",
+            session.key()
+        );
+    }
+
+    Ok(Some(Observation {
+        time: now,
+        session: Some(session.clone()),
+        tick,
+        inner: lites,
+    }))
+}
+
+fn read_tick(state: &BodyState) -> Result<Option<u32>> {
+    let Some(mut addr) = state.symbols.tick else {
+        return Ok(None);
+    };
+    for offset in &state.symbols.tick_offsets {
+        let [ptr] = read_words_arr(state.tracee.pid(), addr)?;
+        ensure!(ptr != 0, "null pointer reading the tick, at 0x{addr:x}");
+        addr = ptr
+            .checked_add(*offset)
+            .ok_or_else(|| anyhow!("tick offset overflow"))?;
+    }
+    // the tick is the low half; the rest is whatever's next to it
+    let [word] = read_words_arr(state.tracee.pid(), addr)?;
+    Ok(Some(word as u32))
+}
+
+fn read_set_size(state: &BodyState) -> Result<u64> {
+    let [size] = read_words_arr(
+        state.tracee.pid(),
+        state.set_base + state.symbols.offsets.set_size,
+    )?;
+    Ok(size)
+}
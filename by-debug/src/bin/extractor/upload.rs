@@ -0,0 +1,465 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fs, io};
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::StatusCode;
+use time::OffsetDateTime;
+use tokio::sync::Notify;
+
+use facto_exporter::{pack_observation, unpack_observation};
+
+use crate::archive;
+
+const EXTENSION: &str = ".facto-obs";
+const TEMP_SUFFIX: &str = ".tmp";
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// observations read from the archives per backfill request
+const BACKFILL_BATCH: usize = 1024;
+
+/// Packed observations waiting to be uploaded to one url, oldest first, one file each in `dir`.
+///
+/// Survives restarts; if it's full, the oldest are dropped, and left for the backfill.
+pub struct Spool {
+    dir: PathBuf,
+    max_items: usize,
+    state: Mutex<SpoolState>,
+    pushed: Notify,
+}
+
+struct SpoolState {
+    seqs: VecDeque<u64>,
+    next: u64,
+}
+
+impl Spool {
+    pub fn open(dir: impl AsRef<Path>, max_items: usize) -> Result<Spool> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut seqs = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(TEMP_SUFFIX) {
+                fs::remove_file(entry.path())?;
+            } else if let Some(seq) = name.strip_suffix(EXTENSION) {
+                seqs.push(seq.parse::<u64>()?);
+            }
+        }
+        seqs.sort_unstable();
+        if !seqs.is_empty() {
+            println!("{} observations waiting in {dir:?}", seqs.len());
+        }
+        let next = seqs.last().map(|seq| seq + 1).unwrap_or_default();
+        Ok(Spool {
+            dir,
+            max_items,
+            state: Mutex::new(SpoolState {
+                seqs: seqs.into(),
+                next,
+            }),
+            pushed: Notify::new(),
+        })
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{seq:016}{EXTENSION}"))
+    }
+
+    pub fn push(&self, packed: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().expect("no panics while locked");
+        let seq = state.next;
+        let temp = self.dir.join(format!("{seq:016}{TEMP_SUFFIX}"));
+        fs::write(&temp, packed)?;
+        fs::rename(&temp, self.path(seq))?;
+        state.next += 1;
+        state.seqs.push_back(seq);
+
+        while state.seqs.len() > self.max_items {
+            let dropped = state.seqs.pop_front().expect("non-empty");
+            remove_if_present(&self.path(dropped))?;
+        }
+        drop(state);
+
+        self.pushed.notify_one();
+        Ok(())
+    }
+
+    /// the oldest waiting observation; any which have gone from the disk are skipped
+    fn peek(&self) -> Result<Option<(u64, Vec<u8>)>> {
+        let mut state = self.state.lock().expect("no panics while locked");
+        while let Some(&seq) = state.seqs.front() {
+            match fs::read(self.path(seq)) {
+                Ok(packed) => return Ok(Some((seq, packed))),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    eprintln!("spooled observation {seq} has gone from {:?}", self.dir);
+                    state.seqs.pop_front();
+                }
+                Err(e) => bail!(e),
+            }
+        }
+        Ok(None)
+    }
+
+    fn remove(&self, seq: u64) -> Result<()> {
+        let mut state = self.state.lock().expect("no panics while locked");
+        // it may have been dropped for space while we were sending it
+        if state.seqs.front() == Some(&seq) {
+            state.seqs.pop_front();
+            remove_if_present(&self.path(seq))?;
+        }
+        Ok(())
+    }
+}
+
+fn remove_if_present(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => bail!(e),
+        _ => Ok(()),
+    }
+}
+
+/// Where an upload goes, and where to backfill from.
+pub struct Uploader {
+    pub url: String,
+    pub spool: Arc<Spool>,
+    pub max_backoff: Duration,
+    /// the archives, of every session
+    pub archive_dir: PathBuf,
+}
+
+impl Uploader {
+    /// Send everything in the spool, in order, forever; backing off while the server is failing.
+    ///
+    /// Whenever we (re)connect, backfill anything the server is missing from the archives first.
+    pub async fn run(self) {
+        let client = reqwest::Client::new();
+        let mut backoff = MIN_BACKOFF;
+        let mut connected = false;
+        loop {
+            if !connected {
+                match self.backfill(&client).await {
+                    Ok(()) => connected = true,
+                    Err(e) => {
+                        eprintln!("backfill to {} failed: {e:?}", self.url);
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(self.max_backoff);
+                        continue;
+                    }
+                }
+            }
+
+            let (seq, packed) = match self.spool.peek() {
+                Ok(Some(item)) => item,
+                Ok(None) => {
+                    self.spool.pushed.notified().await;
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("reading the spool for {}: {e:?}", self.url);
+                    tokio::time::sleep(self.max_backoff).await;
+                    continue;
+                }
+            };
+
+            let failure = match client.post(&self.url).body(packed).send().await {
+                Ok(res) if res.status() == StatusCode::ACCEPTED => None,
+                // it's never going to accept this one, don't block everything behind it
+                Ok(res) if res.status().is_client_error() => {
+                    eprintln!("{} rejected an observation, dropping: {res:?}", self.url);
+                    None
+                }
+                Ok(res) => Some(format!("surprising send response: {res:?}")),
+                Err(e) => Some(format!("send error: {e:?}")),
+            };
+            if let Some(failure) = failure {
+                eprintln!("{failure}, to {}; retrying in {backoff:?}", self.url);
+                connected = false;
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(self.max_backoff);
+                continue;
+            }
+            backoff = MIN_BACKOFF;
+            if let Err(e) = self.spool.remove(seq) {
+                eprintln!("removing from the spool for {}: {e:?}", self.url);
+            }
+        }
+    }
+
+    /// Ask the server for the newest observation it has from each session in the archives, and
+    /// send it anything newer. Only works for urls ending in `/store`.
+    async fn backfill(&self, client: &reqwest::Client) -> Result<()> {
+        let Some(base) = self.url.strip_suffix("/store") else {
+            return Ok(());
+        };
+        let since_url = format!("{base}/since");
+        let archive_dir = self.archive_dir.clone();
+        let sessions =
+            tokio::task::spawn_blocking(move || archive::sessions(&archive_dir)).await??;
+        loop {
+            let mut after = BTreeMap::new();
+            for session in &sessions {
+                let res = client
+                    .get(&since_url)
+                    .query(&[("session", session)])
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                let body: serde_json::Value = serde_json::from_slice(&res.bytes().await?)?;
+                let last = match body["last"].as_i64() {
+                    Some(nanos) => OffsetDateTime::from_unix_timestamp_nanos(i128::from(nanos))?,
+                    None => OffsetDateTime::UNIX_EPOCH,
+                };
+                after.insert(session.clone(), last);
+            }
+
+            // anything from here on will be sent from the spool anyway
+            let before = match self.spool.peek()? {
+                Some((_, packed)) => Some(unpack_observation(packed.as_slice())?.time),
+                None => None,
+            };
+
+            let archive_dir = self.archive_dir.clone();
+            let missing = tokio::task::spawn_blocking(move || {
+                archive::read_since(&archive_dir, &after, before, BACKFILL_BATCH)
+            })
+            .await??;
+            if missing.is_empty() {
+                return Ok(());
+            }
+            println!(
+                "backfilling {} observations to {}, from {}",
+                missing.len(),
+                self.url,
+                missing[0].time
+            );
+
+            for obs in &missing {
+                let res = client
+                    .post(&self.url)
+                    .body(pack_observation(obs)?)
+                    .send()
+                    .await?;
+                if res.status().is_client_error() {
+                    eprintln!(
+                        "{} rejected a backfill observation, skipping: {res:?}",
+                        self.url
+                    );
+                } else if res.status() != StatusCode::ACCEPTED {
+                    return Err(anyhow!("surprising backfill response: {res:?}"));
+                }
+            }
+            if missing.len() < BACKFILL_BATCH {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// a directory name for the spool of `url`
+pub fn spool_name(url: &str) -> String {
+    url.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::routing::post;
+    use axum::Router;
+    use bunyarrs::Bunyarr;
+    use facto_exporter::store::Store;
+    use facto_exporter::{exp, Observation, Session};
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::archive::Archive;
+    use crate::config::ArchiveConfig;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("upload-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[derive(Default)]
+    struct Server {
+        /// fail this many requests first
+        failures: AtomicUsize,
+        accepted: Mutex<Vec<Bytes>>,
+    }
+
+    // axum's, which isn't the same version as reqwest's
+    async fn store(State(server): State<Arc<Server>>, body: Bytes) -> axum::http::StatusCode {
+        let failing = server
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if failing {
+            return axum::http::StatusCode::SERVICE_UNAVAILABLE;
+        }
+        server.accepted.lock().expect("no panics").push(body);
+        axum::http::StatusCode::ACCEPTED
+    }
+
+    #[test]
+    fn spool_survives_reopening() -> Result<()> {
+        let dir = temp_dir("reopen");
+        let spool = Spool::open(&dir, 2)?;
+        for item in [b"one", b"two", b"thr"] {
+            spool.push(item)?;
+        }
+        drop(spool);
+
+        // the oldest was dropped for space
+        let spool = Spool::open(&dir, 2)?;
+        let (seq, packed) = spool.peek()?.expect("waiting");
+        assert_eq!(b"two", packed.as_slice());
+        spool.remove(seq)?;
+        spool.push(b"for")?;
+        drop(spool);
+
+        let spool = Spool::open(&dir, 2)?;
+        let mut left = Vec::new();
+        while let Some((seq, packed)) = spool.peek()? {
+            left.push(packed);
+            spool.remove(seq)?;
+        }
+        assert_eq!(vec![b"thr".to_vec(), b"for".to_vec()], left);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn vanished_from_the_spool() -> Result<()> {
+        let dir = temp_dir("vanished");
+        let spool = Spool::open(&dir, 10)?;
+        spool.push(b"one")?;
+        spool.push(b"two")?;
+        fs::remove_file(spool.path(0))?;
+        assert_eq!(Some((1, b"two".to_vec())), spool.peek()?);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backfilled_from_every_session() -> Result<()> {
+        let dir = temp_dir("backfill");
+        let at = |secs: i64| OffsetDateTime::from_unix_timestamp(1_700_000_000 + secs);
+        let in_session = |pid, secs| -> Result<Observation> {
+            Ok(Observation {
+                time: at(secs)?,
+                session: Some(Session {
+                    pid,
+                    build_id: None,
+                    save_name: None,
+                    started: at(0)?,
+                }),
+                tick: None,
+                inner: Vec::new(),
+            })
+        };
+
+        // a game was loaded part way through, starting a second session
+        let archive_dir = dir.join("archive");
+        let mut archive = Archive::new(&archive_dir, &ArchiveConfig::default())?;
+        for secs in 0..3 {
+            archive.write(&in_session(1, secs)?)?;
+        }
+        for secs in 3..6 {
+            archive.write(&in_session(2, secs)?)?;
+        }
+        archive.finish()?;
+        drop(archive);
+
+        // which has only heard about the start of the first
+        let mut store = Store::open(dir.join("store"), Bunyarr::with_name("upload-test"))?;
+        store.append(in_session(1, 0)?)?;
+        let data = Arc::new(RwLock::new(store));
+        let app = exp::routes(Arc::clone(&data), Bunyarr::with_name("upload-test"));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/exp/store", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, app.into_make_service()).await });
+
+        let uploader = tokio::spawn(
+            Uploader {
+                url,
+                spool: Arc::new(Spool::open(dir.join("spool"), 10)?),
+                max_backoff: MIN_BACKOFF,
+                archive_dir,
+            }
+            .run(),
+        );
+        let lens = || async {
+            let data = data.read().await;
+            ["1-1700000000", "2-1700000000"].map(|key| data.series(Some(key)).map(|s| s.len()).ok())
+        };
+        let filled = tokio::time::timeout(Duration::from_secs(10), async {
+            while lens().await != [Some(3), Some(3)] {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        uploader.abort();
+        filled?;
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replayed_in_order_after_a_failure() -> Result<()> {
+        let server = Arc::new(Server {
+            failures: AtomicUsize::new(1),
+            ..Server::default()
+        });
+        let app = Router::new()
+            .route("/exp/obs", post(store))
+            .with_state(Arc::clone(&server));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/exp/obs", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, app.into_make_service()).await });
+
+        let dir = temp_dir("replay");
+        let spool = Arc::new(Spool::open(&dir, 10)?);
+        for item in [b"one", b"two", b"thr"] {
+            spool.push(item)?;
+        }
+        let uploader = tokio::spawn(
+            Uploader {
+                url,
+                spool: Arc::clone(&spool),
+                max_backoff: MIN_BACKOFF,
+                archive_dir: dir.clone(),
+            }
+            .run(),
+        );
+
+        let sent = tokio::time::timeout(Duration::from_secs(10), async {
+            while spool.peek()?.is_some() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await;
+        uploader.abort();
+        sent??;
+
+        assert_eq!(0, server.failures.load(Ordering::SeqCst));
+        let accepted = server.accepted.lock().expect("no panics").clone();
+        assert_eq!(vec![&b"one"[..], b"two", b"thr"], accepted);
+        assert_eq!(0, fs::read_dir(&dir)?.count());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
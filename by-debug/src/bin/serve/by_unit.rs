@@ -10,12 +10,13 @@ use bunyarrs::{vars_dbg, Bunyarr};
 use serde_json::json;
 use time::OffsetDateTime;
 
-use crate::{okay_or_500, AppState, Data};
+use crate::{okay_or_500, AppState, TICKS_PER_MINUTE};
+use facto_exporter::store::SeriesRef;
 
 pub fn split_units(logger: &Bunyarr, units: &str) -> Option<Vec<u32>> {
     match units
         .split(',')
-        .map(|s| -> Result<u32> { Ok(s.parse::<u32>().with_context(|| anyhow!("{s:?}"))?) })
+        .map(|s| -> Result<u32> { s.parse::<u32>().with_context(|| anyhow!("{s:?}")) })
         .collect::<Result<Vec<u32>>>()
     {
         Ok(mut units) => {
@@ -40,6 +41,8 @@ pub struct QueryQuery {
     end: Option<i64>,
     // Vec<u32> csv
     units: String,
+    // session key, default the most recently seen
+    session: Option<String>,
 }
 
 #[axum::debug_handler]
@@ -65,30 +68,17 @@ pub async fn query(
     let gap = query.gap.unwrap_or(60);
 
     okay_or_500(&state.logger, || async {
-        let data = state.data.read().await;
+        let store = state.data.read().await;
+        let data = store.series(query.session.as_deref())?;
 
-        ensure!(!data.inner.is_empty(), "no data");
-
-        let all_obses = data
-            .inner
-            .iter()
-            .map(|obs| obs.time.unix_timestamp())
-            .collect::<Vec<_>>();
+        ensure!(!data.is_empty(), "no data");
 
         // find the nearest ones for our chosen steps
         let mut obses = Vec::with_capacity(steps as usize);
         for step in 0..steps {
             let target = end - (step * gap) as i64;
-            let best = match all_obses.binary_search(&target) {
-                Ok(a) => a,
-                Err(a) => a,
-            };
-
-            obses.push(
-                data.inner
-                    .get(best)
-                    .unwrap_or_else(|| data.inner.last().expect("non-empty observations")),
-            );
+            let best = data.position(target).min(data.len() - 1);
+            obses.push(data.get(best)?);
         }
 
         obses.reverse();
@@ -97,10 +87,25 @@ pub async fn query(
             .iter()
             .map(|obs| obs.time.unix_timestamp())
             .collect::<Vec<_>>();
+        let ticks = obses.iter().map(|obs| obs.tick).collect::<Vec<_>>();
+
+        // the length of each gap between observations, by the wall clock and by the game's clock
+        let minutes = times
+            .windows(2)
+            .map(|w| Some(w[1] - w[0]).filter(|d| *d > 0).map(|d| d as f64 / 60.))
+            .collect::<Vec<_>>();
+        let game_minutes = ticks
+            .windows(2)
+            .map(|w| match (w[0], w[1]) {
+                // going backwards is a load, so we don't know
+                (Some(a), Some(b)) if b > a => Some((b - a) as f64 / TICKS_PER_MINUTE as f64),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
 
         let mut unit_data = Vec::with_capacity(units.len());
         for _ in &units {
-            unit_data.push(Vec::with_capacity(all_obses.len()));
+            unit_data.push(Vec::with_capacity(obses.len()));
         }
 
         for obs in obses {
@@ -142,15 +147,43 @@ pub async fn query(
             })
             .collect::<Vec<_>>();
 
-        Ok(json!({ "units": units, "deltas": deltas, "statuses": statuses, "times": times }))
+        let rates = deltas
+            .iter()
+            .map(|deltas| per_minute(deltas, &minutes))
+            .collect::<Vec<_>>();
+        let game_rates = deltas
+            .iter()
+            .map(|deltas| per_minute(deltas, &game_minutes))
+            .collect::<Vec<_>>();
+
+        Ok(json!({
+            "units": units,
+            "deltas": deltas,
+            "rates": rates,
+            "gameRates": game_rates,
+            "statuses": statuses,
+            "times": times,
+            "ticks": ticks,
+        }))
     })
     .await
 }
 
+/// products per minute, for each gap we know both the products and the length of
+fn per_minute(deltas: &[Option<u32>], minutes: &[Option<f64>]) -> Vec<Option<f64>> {
+    deltas
+        .iter()
+        .zip(minutes)
+        .map(|(delta, minutes)| Some(f64::from((*delta)?) / (*minutes)?))
+        .collect()
+}
+
 #[derive(serde::Deserialize)]
 pub struct LastQuery {
     // Vec<u32> csv
     units: String,
+    // session key, default the most recently seen
+    session: Option<String>,
 }
 
 #[derive(serde::Serialize, Default)]
@@ -162,15 +195,6 @@ pub struct UnitData {
     pub previous_status: Option<u32>,
 }
 
-impl UnitData {
-    fn all_complete(&self) -> bool {
-        self.produced_change.is_some()
-            && self.last_status.is_some()
-            && self.previous_status.is_some()
-            && self.last_status_change.is_some()
-    }
-}
-
 #[axum::debug_handler]
 pub(crate) async fn last(
     State(state): State<Arc<AppState>>,
@@ -187,53 +211,33 @@ pub(crate) async fn last(
     };
 
     okay_or_500(&state.logger, || async {
-        let data = state.data.read().await;
-        ensure!(!data.inner.is_empty(), "no data");
+        let store = state.data.read().await;
+        let data = store.series(query.session.as_deref())?;
+        ensure!(!data.is_empty(), "no data");
 
-        let mut changes = HashMap::with_capacity(units.len());
-
-        for unit in &units {
-            changes.insert(unit, status_of(&data, *unit));
-        }
+        let changes = statuses_of(data, &units)?;
 
         Ok(json!({ "changes": changes }))
     })
     .await
 }
 
-pub fn status_of(data: &Data, unit: u32) -> UnitData {
-    let mut unit_data = UnitData::default();
-    let mut produced_previous = None;
-    let mut status_previous = None;
-    for obs in data.inner.iter().rev() {
-        let found = match obs
-            .inner
-            .binary_search_by_key(&unit, |crafting| crafting.unit_number)
-        {
-            Ok(found) => found,
-            Err(_) => continue,
-        };
-        let found = &obs.inner[found];
-        if unit_data.produced_change.is_none() {
-            if produced_previous.is_some() && produced_previous != Some(found.products_complete) {
-                unit_data.produced_change = Some(obs.time.unix_timestamp());
-            }
-            produced_previous = Some(found.products_complete);
-        }
-        if unit_data.last_status.is_none() {
-            unit_data.last_status = Some(found.status);
-        }
-        if unit_data.last_status_change.is_none() {
-            if status_previous.is_some() && status_previous != Some(found.status) {
-                unit_data.last_status_change = Some(obs.time.unix_timestamp());
-                unit_data.previous_status = Some(found.status);
-            }
-            status_previous = Some(found.status);
-        }
-
-        if unit_data.all_complete() {
-            break;
-        }
-    }
-    unit_data
+/// from the summary the store keeps of every unit; units it's never seen have nothing known
+pub fn statuses_of(data: SeriesRef, units: &[u32]) -> Result<HashMap<u32, UnitData>> {
+    let summaries = data.units()?;
+    Ok(units
+        .iter()
+        .map(|unit| {
+            let data = summaries
+                .get(unit)
+                .map(|s| UnitData {
+                    produced_change: s.produced_change,
+                    last_status: Some(s.last_status),
+                    last_status_change: s.last_status_change,
+                    previous_status: s.previous_status,
+                })
+                .unwrap_or_default();
+            (*unit, data)
+        })
+        .collect())
 }
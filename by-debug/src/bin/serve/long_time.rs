@@ -11,7 +11,7 @@ use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 
 use crate::by_unit::split_units;
-use crate::{okay_or_500, AppState};
+use crate::{okay_or_500, AppState, TICKS_PER_MINUTE};
 
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 pub struct LongQuery {
@@ -22,6 +22,8 @@ pub struct LongQuery {
     start: OffsetDateTime,
     #[serde(with = "time::serde::rfc3339")]
     end: OffsetDateTime,
+    // session key, default the most recently seen
+    session: Option<String>,
 }
 
 #[axum::debug_handler]
@@ -33,22 +35,18 @@ pub(crate) async fn long(
         ensure!(query.steps > 0, "steps must be greater than 0");
         let units = split_units(&state.logger, &query.units)
             .ok_or_else(|| anyhow::anyhow!("invalid units"))?;
-        let data = state.data.read().await;
-        let mut obs = data.inner.iter().collect::<Vec<_>>();
-        obs.sort_unstable_by_key(|date| date.ts());
-        obs.dedup_by_key(|date| date.ts());
-        let start_idx =
-            match obs.binary_search_by_key(&query.start.unix_timestamp(), |date| date.ts()) {
-                Ok(p) => p,
-                Err(p) => p,
-            };
-        let end_idx = match obs.binary_search_by_key(&query.end.unix_timestamp(), |date| date.ts())
-        {
-            Ok(p) => p,
-            Err(p) => p,
-        };
+        let store = state.data.read().await;
+        let data = store.series(query.session.as_deref())?;
+        let start_idx = data.position(query.start.unix_timestamp());
+        let end_idx = data.position(query.end.unix_timestamp());
+
+        let mut idxes = (start_idx..end_idx).collect::<Vec<_>>();
+        idxes.dedup_by_key(|idx| data.ts(*idx));
+        let obs = idxes
+            .into_iter()
+            .map(|idx| data.get(idx))
+            .collect::<Result<Vec<_>>>()?;
 
-        let obs = &obs[start_idx..end_idx];
         let step = obs.len() / query.steps;
 
         // let t = obs.chunks(step).map(|v| v.iter().map(|v| v.time.format(&Rfc3339).expect("static format")).collect::<Vec<_>>()).collect::<Vec<_>>();
@@ -63,6 +61,10 @@ pub(crate) async fn long(
             .map(|step| GeneralOutput {
                 observations: step.len(),
                 dates: vec![format(&step[0].time), format(&step[step.len() - 1].time)],
+                ticks: step[step.len() - 1]
+                    .tick
+                    .zip(step[0].tick)
+                    .and_then(|(last, first)| last.checked_sub(first)),
             })
             .collect::<Vec<_>>();
 
@@ -77,6 +79,9 @@ struct GeneralOutput {
     observations: usize,
     #[serde(rename = "ds")]
     dates: Vec<String>,
+    /// game ticks between the first and last observation, if the game didn't load in between
+    #[serde(rename = "t")]
+    ticks: Option<u64>,
 }
 
 #[derive(serde::Serialize)]
@@ -85,15 +90,35 @@ struct UnitOutput {
     statuses: HashMap<u8, usize>,
     #[serde(rename = "p")]
     products: u32,
+    /// products per minute since the previous step, by the wall clock
+    #[serde(rename = "r")]
+    rate: Option<f64>,
+    /// products per game minute since the previous step
+    #[serde(rename = "g")]
+    game_rate: Option<f64>,
 }
 
-fn stepper(steps: &[&[&Observation]], units: &[u32]) -> Result<Vec<Vec<UnitOutput>>> {
+fn stepper(steps: &[&[Arc<Observation>]], units: &[u32]) -> Result<Vec<Vec<UnitOutput>>> {
     // most of these hashmaps from unit number could be fixed by remembering the idxes
 
     let mut by_step = Vec::with_capacity(steps.len());
     let mut prev = HashMap::with_capacity(units.len());
     let units_lookup: HashSet<u32> = units.iter().copied().collect();
+    let mut prev_end: Option<&Observation> = None;
     for step in steps {
+        let end = step.last().map(|obs| obs.as_ref());
+        let (minutes, game_minutes) = match (prev_end, end) {
+            (Some(a), Some(b)) => (
+                Some((b.time - a.time).as_seconds_f64() / 60.).filter(|m| *m > 0.),
+                match (a.tick, b.tick) {
+                    (Some(a), Some(b)) if b > a => Some((b - a) as f64 / TICKS_PER_MINUTE as f64),
+                    _ => None,
+                },
+            ),
+            _ => (None, None),
+        };
+        prev_end = end;
+
         // unit -> (status, count)
         let mut step_statuses: HashMap<u32, HashMap<u8, usize>> =
             HashMap::with_capacity(units.len());
@@ -124,9 +149,14 @@ fn stepper(steps: &[&[&Observation]], units: &[u32]) -> Result<Vec<Vec<UnitOutpu
         by_step.push(
             units
                 .iter()
-                .map(|unit| UnitOutput {
-                    statuses: step_statuses.remove(unit).unwrap_or_default(),
-                    products: step_products.remove(unit).unwrap_or_default(),
+                .map(|unit| {
+                    let products = step_products.remove(unit);
+                    UnitOutput {
+                        statuses: step_statuses.remove(unit).unwrap_or_default(),
+                        products: products.unwrap_or_default(),
+                        rate: products.zip(minutes).map(|(p, m)| f64::from(p) / m),
+                        game_rate: products.zip(game_minutes).map(|(p, m)| f64::from(p) / m),
+                    }
                 })
                 .collect::<Vec<_>>(),
         );
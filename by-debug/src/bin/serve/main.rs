@@ -1,32 +1,33 @@
 mod bulk_unit;
 mod by_unit;
 mod long_time;
+mod metrics;
+mod sessions;
 
 use std::future::Future;
 use std::net::Ipv4Addr;
 use std::sync::Arc;
-use std::{fs, io};
 
 use anyhow::Result;
-use axum::body::Bytes;
-use axum::extract::State;
 use axum::http::StatusCode;
 use axum::Json;
 use bunyarrs::{vars, vars_dbg, Bunyarr};
 use serde_json::{json, Value};
 
-use facto_exporter::{unpack_observation, Observation};
-
-pub struct Data {
-    inner: Vec<Observation>,
-}
+use facto_exporter::exp;
+use facto_exporter::store::Store;
 
 pub struct AppState {
-    data: Arc<tokio::sync::RwLock<Data>>,
+    data: Arc<tokio::sync::RwLock<Store>>,
     logger: Bunyarr,
 }
 
-const KNOWN_STATUSES: [(u32, &'static str); 12] = [
+const STORE_DIR: &str = "facto-store";
+
+/// at normal game speed
+const TICKS_PER_MINUTE: u64 = 60 * 60;
+
+const KNOWN_STATUSES: [(u32, &str); 12] = [
     (1, "working"),
     (2, "normal"),
     (37, "no_power"),
@@ -45,58 +46,25 @@ const KNOWN_STATUSES: [(u32, &'static str); 12] = [
 async fn main() -> Result<()> {
     let logger = bunyarrs::Bunyarr::with_name("serve");
 
-    let mut data = Data {
-        inner: Vec::with_capacity(256),
-    };
-
-    for path in fs::read_dir(".")? {
-        let path = path?;
-        if !path
-            .file_name()
-            .to_string_lossy()
-            .ends_with(".facto-cp.archiv")
-        {
-            continue;
-        }
-        // when the exporter has crashed during startup? boo
-        if path.metadata()?.len() == 0 {
-            continue;
-        }
-        let path = path.path();
-        logger.info(vars! { path }, "loading observation");
-        let mut archiv =
-            archiv::ExpandOptions::default().stream(io::BufReader::new(fs::File::open(path)?))?;
-
-        loop {
-            let item = match archiv.next_item() {
-                Err(err) => {
-                    logger.warn(
-                        vars_dbg! { err },
-                        "failed to read item, assuming live archive",
-                    );
-                    break;
-                }
-                Ok(None) => break,
-                Ok(Some(item)) => item,
-            };
-            data.inner.push(unpack_observation(item)?);
-        }
-    }
+    let mut data = Store::open(STORE_DIR, Bunyarr::with_name("store"))?;
+    data.import_archives(".")?;
 
+    let data = Arc::new(tokio::sync::RwLock::new(data));
     use axum::routing::*;
     let app = Router::new()
         .route("/", get(|| async { env!("CARGO_PKG_NAME") }))
         .route("/healthcheck", get(|| async { "ok" }))
-        .route("/metrics/raw", get(bulk_unit::metrics_raw))
-        .route("/exp/store", post(store))
+        .route("/metrics/raw", get(metrics::metrics_raw))
         .route("/api/query", get(by_unit::query))
         .route("/api/last", get(by_unit::last))
         .route("/api/long", get(long_time::long))
         .route("/api/bulk-status", get(bulk_unit::bulk_status))
+        .route("/api/sessions", get(sessions::sessions))
         .with_state(Arc::new(AppState {
-            data: Arc::new(tokio::sync::RwLock::new(data)),
+            data: Arc::clone(&data),
             logger: Bunyarr::with_name("handler"),
-        }));
+        }))
+        .merge(exp::routes(data, Bunyarr::with_name("handler")));
 
     let port = 9429;
     logger.info(vars! { port }, "starting server");
@@ -106,23 +74,6 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-#[axum::debug_handler]
-async fn store(State(state): State<Arc<AppState>>, buf: Bytes) -> StatusCode {
-    // TODO: less copying / unbounded memory usage?
-    let buf = buf.to_vec();
-    let observation = match unpack_observation(io::Cursor::new(buf)) {
-        Ok(observation) => observation,
-        Err(err) => {
-            eprintln!("error parsing observation: {}", err);
-            return StatusCode::BAD_REQUEST;
-        }
-    };
-
-    let mut data = state.data.write().await;
-    data.inner.push(observation);
-    StatusCode::ACCEPTED
-}
-
 async fn okay_or_500<F: Future<Output = Result<Value>>>(
     logger: &Bunyarr,
     func: impl FnOnce() -> F,
@@ -0,0 +1,269 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::header::{ACCEPT, CONTENT_TYPE};
+use axum::http::HeaderMap;
+use axum::response::IntoResponse;
+use facto_exporter::Observation;
+
+use crate::{AppState, KNOWN_STATUSES};
+
+/// sessions which haven't been seen for this long before the newest one are left out
+const LIVE_SECS: i64 = 10 * 60;
+
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Format {
+    /// the classic prometheus text format, timestamps in integer milliseconds
+    Text,
+    /// OpenMetrics 1.0, timestamps in (fractional) seconds, counters suffixed `_total`, `# EOF`
+    OpenMetrics,
+}
+
+/// whether an `Accept` entry's parameters include `q=0`, i.e. "not this"
+fn refused<'a>(mut params: impl Iterator<Item = &'a str>) -> bool {
+    params.any(|p| {
+        p.strip_prefix("q=")
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .is_some_and(|q| q <= 0.0)
+    })
+}
+
+impl Format {
+    fn negotiate(headers: &HeaderMap) -> Format {
+        let wants_openmetrics = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| {
+                let mut parts = v.split(';').map(str::trim);
+                parts.next() == Some("application/openmetrics-text") && !refused(parts)
+            });
+        if wants_openmetrics {
+            Format::OpenMetrics
+        } else {
+            Format::Text
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Text => TEXT_CONTENT_TYPE,
+            Format::OpenMetrics => OPENMETRICS_CONTENT_TYPE,
+        }
+    }
+
+    fn timestamp(self, obs: &Observation) -> String {
+        let millis = obs.time.unix_timestamp_nanos() / 1_000_000;
+        match self {
+            Format::Text => millis.to_string(),
+            Format::OpenMetrics => {
+                format!("{}.{:03}", millis.div_euclid(1000), millis.rem_euclid(1000))
+            }
+        }
+    }
+}
+
+#[axum::debug_handler]
+pub async fn metrics_raw(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let format = Format::negotiate(&headers);
+    let data = state.data.read().await;
+    let lasts = data
+        .sessions()
+        .into_iter()
+        .filter_map(|series| Some((series.key().to_string(), series.last()?)))
+        .collect::<Vec<_>>();
+    let newest = lasts
+        .iter()
+        .map(|(_, obs)| obs.ts())
+        .max()
+        .unwrap_or_default();
+    let live = lasts
+        .iter()
+        .filter(|(_, obs)| obs.ts() > newest - LIVE_SECS)
+        .map(|(key, obs)| (key.as_str(), obs.as_ref()))
+        .collect::<Vec<_>>();
+    let body = render(format, &live);
+    ([(CONTENT_TYPE, format.content_type())], body)
+}
+
+/// the latest observation of each session, with the session key
+fn render(format: Format, lasts: &[(&str, &Observation)]) -> String {
+    let units = lasts.iter().map(|(_, obs)| obs.inner.len()).sum::<usize>();
+    let mut s = String::with_capacity(256 + units * (KNOWN_STATUSES.len() + 2) * 100);
+
+    // writing to a String can't fail
+    let _ = write_all(&mut s, format, lasts);
+    s
+}
+
+fn write_all(s: &mut String, format: Format, lasts: &[(&str, &Observation)]) -> std::fmt::Result {
+    writeln!(
+        s,
+        "# HELP facto_products_complete Products finished by a crafting machine, as reported by the game."
+    )?;
+    writeln!(s, "# TYPE facto_products_complete counter")?;
+    for &(session, obs) in lasts {
+        let ts = format.timestamp(obs);
+        let sample = match format {
+            Format::Text => "facto_products_complete",
+            Format::OpenMetrics => "facto_products_complete_total",
+        };
+        for crafting in &obs.inner {
+            writeln!(
+                s,
+                "{sample}{{session=\"{session}\",unit=\"{}\"}} {} {ts}",
+                crafting.unit_number, crafting.products_complete
+            )?;
+        }
+    }
+
+    writeln!(
+        s,
+        "# HELP facto_status Whether a crafting machine is in the given status, one-hot over the known statuses."
+    )?;
+    writeln!(s, "# TYPE facto_status gauge")?;
+    for &(session, obs) in lasts {
+        let ts = format.timestamp(obs);
+        for crafting in &obs.inner {
+            for (code, name) in KNOWN_STATUSES {
+                writeln!(
+                    s,
+                    "facto_status{{session=\"{session}\",unit=\"{}\",status=\"{name}\"}} {} {ts}",
+                    crafting.unit_number,
+                    u8::from(crafting.status == code),
+                )?;
+            }
+        }
+    }
+
+    writeln!(
+        s,
+        "# HELP facto_status_code The raw status code of a crafting machine, including ones we don't have names for."
+    )?;
+    writeln!(s, "# TYPE facto_status_code gauge")?;
+    for &(session, obs) in lasts {
+        let ts = format.timestamp(obs);
+        for crafting in &obs.inner {
+            writeln!(
+                s,
+                "facto_status_code{{session=\"{session}\",unit=\"{}\"}} {} {ts}",
+                crafting.unit_number, crafting.status
+            )?;
+        }
+    }
+
+    if format == Format::OpenMetrics {
+        writeln!(s, "# EOF")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+    use facto_exporter::CraftingLite;
+    use time::OffsetDateTime;
+
+    use super::*;
+
+    fn accepting(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(ACCEPT, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn observation() -> Observation {
+        Observation {
+            time: OffsetDateTime::from_unix_timestamp_nanos(1_700_000_000_250_000_000)
+                .expect("in range"),
+            session: None,
+            tick: None,
+            inner: vec![CraftingLite {
+                unit_number: 7,
+                products_complete: 12,
+                status: 37,
+            }],
+        }
+    }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(Format::Text, Format::negotiate(&accepting(&[])));
+        assert_eq!(Format::Text, Format::negotiate(&accepting(&["text/plain"])));
+        // what prometheus sends
+        assert_eq!(
+            Format::OpenMetrics,
+            Format::negotiate(&accepting(&[
+                "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"
+            ]))
+        );
+        assert_eq!(
+            Format::OpenMetrics,
+            Format::negotiate(&accepting(&["text/plain", " application/openmetrics-text"]))
+        );
+        assert_eq!(
+            Format::Text,
+            Format::negotiate(&accepting(&[
+                "application/openmetrics-text; version=1.0.0; q=0, text/plain"
+            ]))
+        );
+        assert_eq!(
+            Format::OpenMetrics,
+            Format::negotiate(&accepting(&[
+                "application/openmetrics-text;q=0.2,text/plain"
+            ]))
+        );
+        assert_eq!(OPENMETRICS_CONTENT_TYPE, Format::OpenMetrics.content_type());
+    }
+
+    #[test]
+    fn text() {
+        let obs = observation();
+        let s = render(Format::Text, &[("1-2", &obs)]);
+        assert!(
+            s.contains("\nfacto_products_complete{session=\"1-2\",unit=\"7\"} 12 1700000000250\n")
+        );
+        assert!(s.contains(
+            "\nfacto_status{session=\"1-2\",unit=\"7\",status=\"no_power\"} 1 1700000000250\n"
+        ));
+        assert!(s.contains(
+            "\nfacto_status{session=\"1-2\",unit=\"7\",status=\"working\"} 0 1700000000250\n"
+        ));
+        assert!(s.contains("\nfacto_status_code{session=\"1-2\",unit=\"7\"} 37 1700000000250\n"));
+        assert!(!s.contains("_total"));
+        assert!(!s.contains("# EOF"));
+    }
+
+    #[test]
+    fn openmetrics() {
+        let obs = observation();
+        let s = render(Format::OpenMetrics, &[("1-2", &obs)]);
+        // the metric family keeps its name, only the sample is suffixed
+        assert!(s.contains("# TYPE facto_products_complete counter\n"));
+        assert!(s.contains(
+            "\nfacto_products_complete_total{session=\"1-2\",unit=\"7\"} 12 1700000000.250\n"
+        ));
+        assert!(s.contains("\nfacto_status_code{session=\"1-2\",unit=\"7\"} 37 1700000000.250\n"));
+        assert!(s.ends_with("\n# EOF\n"));
+        assert_eq!(1, s.matches("# EOF").count());
+    }
+
+    #[test]
+    fn empty() {
+        assert!(
+            render(Format::OpenMetrics, &[]).ends_with("# TYPE facto_status_code gauge\n# EOF\n")
+        );
+        assert!(render(Format::Text, &[]).ends_with("# TYPE facto_status_code gauge\n"));
+    }
+}
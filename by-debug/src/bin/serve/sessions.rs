@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::response::IntoResponse;
+use serde_json::json;
+
+use crate::{okay_or_500, AppState};
+
+/// every session we have observations for, oldest first
+#[axum::debug_handler]
+pub async fn sessions(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    okay_or_500(&state.logger, || async {
+        let store = state.data.read().await;
+        let sessions = store
+            .sessions()
+            .into_iter()
+            .filter(|series| !series.is_empty())
+            .map(|series| {
+                let session = series.session();
+                json!({
+                    "key": series.key(),
+                    "pid": session.as_ref().map(|s| s.pid),
+                    "buildId": session.as_ref().and_then(|s| s.build_id.clone()),
+                    "saveName": session.as_ref().and_then(|s| s.save_name.clone()),
+                    "started": session.as_ref().map(|s| s.started.unix_timestamp()),
+                    "first": series.ts(0),
+                    "last": series.ts(series.len() - 1),
+                    "observations": series.len(),
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({ "sessions": sessions }))
+    })
+    .await
+}
@@ -0,0 +1,273 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+
+use super::ptrace::{
+    clear_debug_slot, clear_dr6, run_until_stop, set_debug_slot, wait_for_stop, which_breakpoints,
+    Condition, Memory, Stop,
+};
+
+const INT3: u8 = 0xcc;
+const RESUME_FLAG: u64 = 1 << 16;
+
+/// Which breakpoint was hit, from [`Breakpoints::add`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BreakpointId(u32);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Slot {
+    /// DR0 to DR3; only for this thread
+    Hardware(usize),
+    /// an `int3` written over the code, which every thread runs; one which isn't being traced will
+    /// be killed by the `SIGTRAP`, taking the process with it
+    Software,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Breakpoint {
+    id: BreakpointId,
+    addr: u64,
+    slot: Slot,
+}
+
+/// Breakpoints on a stopped thread: in the four debug registers, or as many `int3`s as we like;
+/// and watchpoints, in any debug registers left.
+///
+/// After any stop, call [`Breakpoints::hit`] before letting it continue: it puts a thread which
+/// hit an `int3` back on the instruction, to be stepped over (with the original byte) and the
+/// `int3` put back, when it's resumed with [`Breakpoints::run_until_hit`].
+pub struct Breakpoints {
+    pid: Pid,
+    next_id: u32,
+    breakpoints: Vec<Breakpoint>,
+    /// the byte each `int3` replaced
+    originals: BTreeMap<u64, u8>,
+    /// the `int3` we're stopped on
+    stopped_on: Option<u64>,
+}
+
+impl Breakpoints {
+    pub fn new(pid: Pid) -> Breakpoints {
+        Breakpoints {
+            pid,
+            next_id: 0,
+            breakpoints: Vec::new(),
+            originals: BTreeMap::new(),
+            stopped_on: None,
+        }
+    }
+
+    /// in a debug register; an error if they're all in use, see [`Breakpoints::add_software`]
+    pub fn add(&mut self, addr: u64) -> Result<BreakpointId> {
+        let slot = self
+            .free_slot()
+            .ok_or_else(|| anyhow!("all four debug registers are in use"))?;
+        set_debug_slot(self.pid, slot, addr, Condition::Execute)?;
+        Ok(self.push(addr, Slot::Hardware(slot)))
+    }
+
+    /// Stop after the thread reads or writes memory, as it happens; needs a free debug register.
+    pub fn watch(&mut self, addr: u64, condition: Condition) -> Result<BreakpointId> {
+        let slot = self
+            .free_slot()
+            .ok_or_else(|| anyhow!("all four debug registers are in use"))?;
+        set_debug_slot(self.pid, slot, addr, condition)?;
+        Ok(self.push(addr, Slot::Hardware(slot)))
+    }
+
+    pub fn has_free_slot(&self) -> bool {
+        self.free_slot().is_some()
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        (0..4).find(|&slot| self.find(Slot::Hardware(slot)).is_none())
+    }
+
+    /// An `int3`, even if there's a debug register free; only once every thread of the process is
+    /// traced, see [`Slot::Software`].
+    pub fn add_software(&mut self, memory: &mut Memory, addr: u64) -> Result<BreakpointId> {
+        if let Entry::Vacant(entry) = self.originals.entry(addr) {
+            let mut original = [0u8];
+            memory.read(addr, &mut original)?;
+            memory.write(addr, &[INT3])?;
+            entry.insert(original[0]);
+        }
+        Ok(self.push(addr, Slot::Software))
+    }
+
+    fn push(&mut self, addr: u64, slot: Slot) -> BreakpointId {
+        let id = BreakpointId(self.next_id);
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint { id, addr, slot });
+        id
+    }
+
+    pub fn remove(&mut self, memory: &mut Memory, id: BreakpointId) -> Result<()> {
+        let idx = self
+            .breakpoints
+            .iter()
+            .position(|b| b.id == id)
+            .ok_or_else(|| anyhow!("no breakpoint {id:?}"))?;
+        let removed = self.breakpoints.remove(idx);
+        match removed.slot {
+            Slot::Hardware(slot) => clear_debug_slot(self.pid, slot)?,
+            // another breakpoint may be sharing the `int3`
+            Slot::Software if self.find_software(removed.addr).is_none() => {
+                if let Some(original) = self.originals.remove(&removed.addr) {
+                    memory.write(removed.addr, &[original])?;
+                }
+            }
+            Slot::Software => (),
+        }
+        Ok(())
+    }
+
+    /// remove every breakpoint, attempting them all; the first error is returned
+    pub fn clear(&mut self, memory: &mut Memory) -> Result<()> {
+        let mut result = Ok(());
+        for id in self.breakpoints.iter().map(|b| b.id).collect::<Vec<_>>() {
+            if let Err(e) = self.remove(memory, id) {
+                result = result.and(Err(e));
+            }
+        }
+        self.stopped_on = None;
+        result
+    }
+
+    pub fn slot(&self, id: BreakpointId) -> Option<Slot> {
+        self.get(id).map(|b| b.slot)
+    }
+
+    pub fn addr(&self, id: BreakpointId) -> Option<u64> {
+        self.get(id).map(|b| b.addr)
+    }
+
+    fn get(&self, id: BreakpointId) -> Option<&Breakpoint> {
+        self.breakpoints.iter().find(|b| b.id == id)
+    }
+
+    fn find(&self, slot: Slot) -> Option<&Breakpoint> {
+        self.breakpoints.iter().find(|b| b.slot == slot)
+    }
+
+    fn find_software(&self, addr: u64) -> Option<&Breakpoint> {
+        self.breakpoints
+            .iter()
+            .find(|b| b.slot == Slot::Software && b.addr == addr)
+    }
+
+    /// Which of ours the thread has stopped on, if any; call this after every stop.
+    pub fn hit(&mut self, stop: Stop) -> Result<Option<BreakpointId>> {
+        let hardware = which_breakpoints(self.pid)?;
+        match stop {
+            // `int3` leaves us just after it
+            Stop::Breakpoint => {
+                let mut regs = ptrace::getregs(self.pid)?;
+                let int3 = regs.rip.wrapping_sub(1);
+                let Some(found) = self.find_software(int3) else {
+                    return Ok(None);
+                };
+                let id = found.id;
+                regs.rip = int3;
+                ptrace::setregs(self.pid, regs)?;
+                self.stopped_on = Some(int3);
+                Ok(Some(id))
+            }
+            Stop::Hardware => Ok((0..4)
+                .filter(|&slot| hardware[slot])
+                .find_map(|slot| self.find(Slot::Hardware(slot)))
+                .map(|b| b.id)),
+            _ => Ok(None),
+        }
+    }
+
+    /// whether we're on one of our `int3`s, which [`Breakpoints::step_over`] has to run
+    pub fn on_int3(&self) -> bool {
+        self.stopped_on.is_some()
+    }
+
+    /// If we're on an `int3`, run the real instruction, then put the `int3` back.
+    pub fn step_over(&mut self, memory: &mut Memory) -> Result<()> {
+        let pid = self.pid;
+        self.step_over_with(memory, || {
+            ptrace::step(pid, None)
+                .map_err(|e| anyhow!(e))
+                .and_then(|()| wait_for_stop(pid))
+        })
+    }
+
+    /// [`Breakpoints::step_over`], running the real instruction with `step`, e.g. through a
+    /// [`super::ptrace::ThreadGroup`].
+    pub fn step_over_with(
+        &mut self,
+        memory: &mut Memory,
+        step: impl FnOnce() -> Result<Stop>,
+    ) -> Result<()> {
+        let Some(addr) = self.stopped_on.take() else {
+            return Ok(());
+        };
+        let Some(&original) = self.originals.get(&addr) else {
+            return Ok(());
+        };
+        // the registers may have been put back somewhere else
+        let mut regs = ptrace::getregs(self.pid)?;
+        if regs.rip != addr {
+            return Ok(());
+        }
+        // the resume flag, so a debug register on the same instruction doesn't fire again
+        regs.eflags |= RESUME_FLAG;
+        ptrace::setregs(self.pid, regs)?;
+
+        memory.write(addr, &[original])?;
+        let stepped = step();
+        let rearmed = memory.write(addr, &[INT3]);
+        stepped?;
+        rearmed?;
+        // the single step sets a bit of its own
+        clear_dr6(self.pid)
+    }
+
+    /// Put another thread which stopped on one of our `int3`s back on the instruction; `false` if
+    /// it wasn't one of ours.
+    pub fn rewind(&self, tid: Pid) -> Result<bool> {
+        let mut regs = ptrace::getregs(tid)?;
+        let int3 = regs.rip.wrapping_sub(1);
+        if !self.originals.contains_key(&int3) {
+            return Ok(false);
+        }
+        regs.rip = int3;
+        ptrace::setregs(tid, regs)?;
+        Ok(true)
+    }
+
+    /// Another thread stopped on an `int3`: if it's one of ours, run the real instruction in it,
+    /// with `step`, then put the `int3` back; `false` if it wasn't one of ours.
+    pub fn step_thread_over(
+        &self,
+        memory: &mut Memory,
+        tid: Pid,
+        step: impl FnOnce() -> Result<Stop>,
+    ) -> Result<bool> {
+        if !self.rewind(tid)? {
+            return Ok(false);
+        }
+        let addr = ptrace::getregs(tid)?.rip;
+        let original = self.originals[&addr];
+        memory.write(addr, &[original])?;
+        let stepped = step();
+        let rearmed = memory.write(addr, &[INT3]);
+        stepped?;
+        rearmed?;
+        Ok(true)
+    }
+
+    /// continue (stepping over any `int3` we're on), until the next stop
+    pub fn run_until_hit(&mut self, memory: &mut Memory) -> Result<Option<BreakpointId>> {
+        self.step_over(memory)?;
+        let stop = run_until_stop(self.pid)?;
+        self.hit(stop)
+    }
+}
@@ -1,17 +1,22 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, bail, Result};
-use cpp_demangle::DemangleOptions;
+use anyhow::{anyhow, bail, Context, Result};
+use elf::abi::{STT_COMMON, STT_OBJECT};
 use elf::endian::AnyEndian;
-use elf::ElfBytes;
+use elf::note::Note;
+use elf::{ElfBytes, ElfStream};
+use gimli::{AttributeValue, EndianSlice, EntriesTreeNode, RunTimeEndian, UnitOffset};
 use nix::unistd::Pid;
 
 /// (address, size)
 pub type Symbol = (u64, usize);
 
-pub fn full_symbol_table(bin_path: impl AsRef<Path>) -> Result<HashMap<String, Symbol>> {
+/// Every symbol, and the names of those which are data (e.g. globals), rather than code.
+pub fn full_symbol_table(
+    bin_path: impl AsRef<Path>,
+) -> Result<(HashMap<String, Symbol>, HashSet<String>)> {
     let f = fs::read(bin_path)?;
     let f = f.as_slice();
     let f = ElfBytes::<AnyEndian>::minimal_parse(f)?;
@@ -20,19 +25,507 @@ pub fn full_symbol_table(bin_path: impl AsRef<Path>) -> Result<HashMap<String, S
     let symtab = common.symtab.ok_or(anyhow!("no symtab"))?;
     let strtab = common.symtab_strs.ok_or(anyhow!("no strtab"))?;
     let mut ret = HashMap::with_capacity(symtab.len());
+    let mut data = HashSet::new();
 
     for sym in symtab {
         let name = strtab.get(usize::try_from(sym.st_name)?)?;
+        // not thread locals, whose values aren't addresses
+        if matches!(sym.st_symtype(), STT_OBJECT | STT_COMMON) {
+            data.insert(name.to_string());
+        }
         ret.insert(
             name.to_string(),
             (sym.st_value, usize::try_from(sym.st_size)?),
         );
     }
 
-    Ok(ret)
+    Ok((ret, data))
 }
 
-pub fn find_pid(bin_path: impl AsRef<Path>) -> Result<Pid> {
+/// the GNU build id note, as hex, if the binary has one; this only reads the headers and the note
+pub fn build_id(bin_path: impl AsRef<Path>) -> Result<Option<String>> {
+    let mut f = ElfStream::<AnyEndian, _>::open_stream(fs::File::open(bin_path)?)?;
+    let Some(shdr) = f.section_header_by_name(".note.gnu.build-id")?.copied() else {
+        return Ok(None);
+    };
+    for note in f.section_data_as_notes(&shdr)? {
+        if let Note::GnuBuildId(id) = note {
+            return Ok(Some(id.0.iter().map(|b| format!("{b:02x}")).collect()));
+        }
+    }
+    Ok(None)
+}
+
+/// Where the DWARF for a binary is: in the binary itself, or a separate debug file, found by the
+/// build id under `/usr/lib/debug/.build-id`, or by its `.gnu_debuglink`; `None` if there's none.
+pub fn debug_file(bin_path: impl AsRef<Path>) -> Result<Option<PathBuf>> {
+    let bin_path = bin_path.as_ref();
+    let mut f = ElfStream::<AnyEndian, _>::open_stream(fs::File::open(bin_path)?)?;
+    if f.section_header_by_name(".debug_info")?.is_some() {
+        return Ok(Some(bin_path.to_path_buf()));
+    }
+
+    let mut candidates = Vec::new();
+    if let Some(id) = build_id(bin_path)? {
+        if id.len() > 2 {
+            candidates.push(PathBuf::from(format!(
+                "/usr/lib/debug/.build-id/{}/{}.debug",
+                &id[..2],
+                &id[2..]
+            )));
+        }
+    }
+    if let Some(shdr) = f.section_header_by_name(".gnu_debuglink")?.copied() {
+        let (data, _) = f.section_data(&shdr)?;
+        let name = data.split(|b| *b == 0).next().unwrap_or_default();
+        let name = String::from_utf8_lossy(name).to_string();
+        let dir = bin_path.parent().unwrap_or(Path::new("/"));
+        candidates.push(dir.join(&name));
+        candidates.push(dir.join(".debug").join(&name));
+        candidates.push(
+            Path::new("/usr/lib/debug")
+                .join(dir.strip_prefix("/").unwrap_or(dir))
+                .join(&name),
+        );
+    }
+    Ok(candidates.into_iter().find(|path| path.is_file()))
+}
+
+/// A struct, class or union, as the debug info describes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub size: u64,
+    /// the base classes, named by their type
+    pub bases: Vec<Member>,
+    pub members: Vec<Member>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+    /// from the start of the containing struct, in bytes
+    pub offset: u64,
+    pub size: Option<u64>,
+    /// with typedefs resolved, e.g. `long unsigned int`, or `std::_Rb_tree_node_base*`
+    pub type_name: Option<String>,
+}
+
+/// Every struct in the debug info, by qualified name, e.g. `std::_Rb_tree_node_base`.
+///
+/// Names are compared ignoring whitespace, so `std::set<int, std::less<int> >` is found by `std::set<int,std::less<int>>`.
+#[derive(Debug, Default)]
+pub struct Layouts {
+    by_name: HashMap<String, Layout>,
+}
+
+impl Layouts {
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    pub fn get(&self, type_name: &str) -> Option<&Layout> {
+        self.by_name.get(&squash(type_name))
+    }
+
+    /// Follow `path` through the members of `type_name`, looking through base classes at each step;
+    /// the last member, with its offset from the start of `type_name`.
+    pub fn member(&self, type_name: &str, path: &[&str]) -> Result<Member> {
+        let mut current = type_name.to_string();
+        let mut offset = 0;
+        let mut found = None;
+        for step in path {
+            let layout = self
+                .get(&current)
+                .ok_or_else(|| anyhow!("no layout for {current:?}"))?;
+            let (base, member) = self
+                .find_member(layout, step)
+                .ok_or_else(|| anyhow!("{current:?} has no member {step:?}"))?;
+            offset += base + member.offset;
+            current = member
+                .type_name
+                .as_deref()
+                .map(strip_cv)
+                .unwrap_or_default()
+                .to_string();
+            found = Some(member);
+        }
+        let found = found.ok_or_else(|| anyhow!("empty path"))?;
+        Ok(Member {
+            offset,
+            ..found.clone()
+        })
+    }
+
+    /// depth first through the bases, with the offset of the base it was in
+    fn find_member<'l>(&'l self, layout: &'l Layout, name: &str) -> Option<(u64, &'l Member)> {
+        if let Some(member) = layout.members.iter().find(|m| m.name == name) {
+            return Some((0, member));
+        }
+        layout.bases.iter().find_map(|base| {
+            let (offset, member) = self.find_member(self.get(&base.name)?, name)?;
+            Some((base.offset + offset, member))
+        })
+    }
+}
+
+fn squash(s: &str) -> String {
+    s.split_whitespace().collect()
+}
+
+fn strip_cv(mut name: &str) -> &str {
+    while let Some(rest) = name
+        .strip_suffix(" const")
+        .or_else(|| name.strip_suffix(" volatile"))
+    {
+        name = rest;
+    }
+    name
+}
+
+/// The [`Layouts`] from [`debug_file`], if there is one.
+pub fn struct_layouts(bin_path: impl AsRef<Path>) -> Result<Option<Layouts>> {
+    let Some(path) = debug_file(&bin_path)? else {
+        return Ok(None);
+    };
+    read_layouts(&path)
+        .with_context(|| anyhow!("reading the debug info in {path:?}"))
+        .map(Some)
+}
+
+type Slice<'a> = EndianSlice<'a, RunTimeEndian>;
+type Unit<'a> = gimli::Unit<Slice<'a>>;
+
+/// a struct we've found, before its members' types are looked up
+struct Pending {
+    name: String,
+    size: u64,
+    /// (is a base, name, offset, type)
+    members: Vec<(bool, Option<String>, u64, Option<UnitOffset>)>,
+}
+
+fn read_layouts(path: &Path) -> Result<Layouts> {
+    let bin = fs::read(path)?;
+    let f = ElfBytes::<AnyEndian>::minimal_parse(&bin)?;
+    let endian = match f.ehdr.endianness {
+        AnyEndian::Little => RunTimeEndian::Little,
+        AnyEndian::Big => RunTimeEndian::Big,
+    };
+    let dwarf = gimli::Dwarf::load(|id| -> Result<Slice> {
+        let data = match f.section_header_by_name(id.name())? {
+            Some(shdr) => match f.section_data(&shdr)? {
+                (data, None) => data,
+                (_, Some(_)) => bail!("{} is compressed, which isn't supported", id.name()),
+            },
+            None => &[],
+        };
+        Ok(EndianSlice::new(data, endian))
+    })?;
+
+    let mut layouts = Layouts::default();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut qualified = HashMap::new();
+        let mut pending = Vec::new();
+        let mut tree = unit.entries_tree(None)?;
+        walk(
+            &dwarf,
+            &unit,
+            tree.root()?,
+            &mut Vec::new(),
+            &mut qualified,
+            &mut pending,
+        )?;
+
+        for p in pending {
+            // the same struct is in every unit which uses it; they had better agree
+            if layouts.get(&p.name).is_some() {
+                continue;
+            }
+            let mut layout = Layout {
+                size: p.size,
+                bases: Vec::new(),
+                members: Vec::new(),
+            };
+            for (is_base, name, offset, typ) in p.members {
+                let (type_name, size) = match typ {
+                    Some(typ) => describe(&dwarf, &unit, &qualified, typ, 0)?,
+                    None => (None, None),
+                };
+                let member = Member {
+                    name: name.or_else(|| type_name.clone()).unwrap_or_default(),
+                    offset,
+                    size,
+                    type_name,
+                };
+                if is_base {
+                    layout.bases.push(member);
+                } else {
+                    layout.members.push(member);
+                }
+            }
+            layouts.by_name.insert(squash(&p.name), layout);
+        }
+    }
+    Ok(layouts)
+}
+
+fn walk(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit,
+    node: EntriesTreeNode<Slice>,
+    scope: &mut Vec<String>,
+    qualified: &mut HashMap<UnitOffset, String>,
+    pending: &mut Vec<Pending>,
+) -> Result<()> {
+    let entry = node.entry();
+    let tag = entry.tag();
+    let name = match entry.attr_value(gimli::DW_AT_name)? {
+        Some(value) => Some(
+            dwarf
+                .attr_string(unit, value)?
+                .to_string_lossy()
+                .to_string(),
+        ),
+        None => None,
+    };
+
+    let is_record = matches!(
+        tag,
+        gimli::DW_TAG_structure_type | gimli::DW_TAG_class_type | gimli::DW_TAG_union_type
+    );
+    let pushed = match tag {
+        gimli::DW_TAG_namespace => {
+            Some(name.unwrap_or_else(|| "(anonymous namespace)".to_string()))
+        }
+        _ if is_record || tag == gimli::DW_TAG_enumeration_type => name,
+        gimli::DW_TAG_typedef => {
+            if let Some(name) = name {
+                qualified.insert(entry.offset(), qualify(scope, &name));
+            }
+            return Ok(());
+        }
+        gimli::DW_TAG_compile_unit | gimli::DW_TAG_partial_unit => None,
+        // types local to functions aren't interesting
+        _ => return Ok(()),
+    };
+
+    let mut record = None;
+    if let Some(name) = &pushed {
+        if tag != gimli::DW_TAG_namespace {
+            let full = qualify(scope, name);
+            qualified.insert(entry.offset(), full.clone());
+            let declaration = entry.attr_value(gimli::DW_AT_declaration)?.is_some();
+            let size = entry
+                .attr_value(gimli::DW_AT_byte_size)?
+                .and_then(|v| v.udata_value());
+            if let (true, false, Some(size)) = (is_record, declaration, size) {
+                record = Some(Pending {
+                    name: full,
+                    size,
+                    members: Vec::new(),
+                });
+            }
+        }
+        scope.push(name.clone());
+    }
+
+    let mut children = node.children();
+    while let Some(child) = children.next()? {
+        let entry = child.entry();
+        match (&mut record, entry.tag()) {
+            (Some(record), tag @ (gimli::DW_TAG_member | gimli::DW_TAG_inheritance)) => {
+                // static members are declared here, but live elsewhere
+                if entry.attr_value(gimli::DW_AT_external)?.is_some()
+                    || entry.attr_value(gimli::DW_AT_declaration)?.is_some()
+                {
+                    continue;
+                }
+                let name = match entry.attr_value(gimli::DW_AT_name)? {
+                    Some(value) => Some(
+                        dwarf
+                            .attr_string(unit, value)?
+                            .to_string_lossy()
+                            .to_string(),
+                    ),
+                    None => None,
+                };
+                let offset = match entry.attr_value(gimli::DW_AT_data_member_location)? {
+                    Some(AttributeValue::Exprloc(expr)) => evaluate(unit, expr)?,
+                    Some(value) => value.udata_value(),
+                    // bit fields, and the members of unions
+                    None => Some(
+                        entry
+                            .attr_value(gimli::DW_AT_data_bit_offset)?
+                            .and_then(|v| v.udata_value())
+                            .unwrap_or(0)
+                            / 8,
+                    ),
+                };
+                let typ = match entry.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(typ)) => Some(typ),
+                    _ => None,
+                };
+                if let Some(offset) = offset {
+                    record
+                        .members
+                        .push((tag == gimli::DW_TAG_inheritance, name, offset, typ));
+                }
+            }
+            _ => walk(dwarf, unit, child, scope, qualified, pending)?,
+        }
+    }
+
+    if pushed.is_some() {
+        scope.pop();
+    }
+    pending.extend(record);
+    Ok(())
+}
+
+fn qualify(scope: &[String], name: &str) -> String {
+    scope
+        .iter()
+        .map(|s| s.as_str())
+        .chain([name])
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+/// old compilers write the offset as an expression, `DW_OP_plus_uconst`
+fn evaluate(unit: &Unit, expr: gimli::Expression<Slice>) -> Result<Option<u64>> {
+    let mut eval = expr.evaluation(unit.encoding());
+    eval.set_initial_value(0);
+    if eval.evaluate()? != gimli::EvaluationResult::Complete {
+        return Ok(None);
+    }
+    Ok(match eval.result().first().map(|piece| &piece.location) {
+        Some(gimli::Location::Address { address }) => Some(*address),
+        _ => None,
+    })
+}
+
+/// the name and size of the type at `offset`, looking through typedefs
+fn describe(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit,
+    qualified: &HashMap<UnitOffset, String>,
+    offset: UnitOffset,
+    depth: usize,
+) -> Result<(Option<String>, Option<u64>)> {
+    if depth > 32 {
+        return Ok((None, None));
+    }
+    let entry = unit.entry(offset)?;
+    let size = entry
+        .attr_value(gimli::DW_AT_byte_size)?
+        .and_then(|v| v.udata_value());
+    let inner = match entry.attr_value(gimli::DW_AT_type)? {
+        Some(AttributeValue::UnitRef(inner)) => describe(dwarf, unit, qualified, inner, depth + 1)?,
+        _ => (Some("void".to_string()), None),
+    };
+    let own_name = || -> Result<Option<String>> {
+        if let Some(name) = qualified.get(&offset) {
+            return Ok(Some(name.clone()));
+        }
+        Ok(match entry.attr_value(gimli::DW_AT_name)? {
+            Some(value) => Some(
+                dwarf
+                    .attr_string(unit, value)?
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            None => None,
+        })
+    };
+    let suffixed = |suffix: &str| inner.0.as_ref().map(|name| format!("{name}{suffix}"));
+    let pointer_size = Some(u64::from(unit.encoding().address_size));
+
+    Ok(match entry.tag() {
+        gimli::DW_TAG_typedef => inner,
+        gimli::DW_TAG_const_type => (suffixed(" const"), inner.1),
+        gimli::DW_TAG_volatile_type => (suffixed(" volatile"), inner.1),
+        gimli::DW_TAG_pointer_type => (suffixed("*"), size.or(pointer_size)),
+        gimli::DW_TAG_reference_type => (suffixed("&"), size.or(pointer_size)),
+        gimli::DW_TAG_rvalue_reference_type => (suffixed("&&"), size.or(pointer_size)),
+        gimli::DW_TAG_array_type => {
+            // one subrange for each dimension
+            let mut dims = Vec::new();
+            let mut tree = unit.entries_tree(Some(offset))?;
+            let mut children = tree.root()?.children();
+            while let Some(child) = children.next()? {
+                let child = child.entry();
+                if child.tag() != gimli::DW_TAG_subrange_type {
+                    continue;
+                }
+                dims.push(
+                    match (
+                        child.attr_value(gimli::DW_AT_count)?,
+                        child.attr_value(gimli::DW_AT_upper_bound)?,
+                    ) {
+                        (Some(count), _) => count.udata_value(),
+                        (None, Some(upper)) => upper.udata_value().map(|upper| upper + 1),
+                        (None, None) => None,
+                    },
+                );
+            }
+            let suffix = dims
+                .iter()
+                .map(|dim| format!("[{}]", dim.map(|d| d.to_string()).unwrap_or_default()))
+                .collect::<String>();
+            let count = dims.iter().try_fold(1, |acc, dim| Some(acc * (*dim)?));
+            (suffixed(&suffix), size.or_else(|| Some(inner.1? * count?)))
+        }
+        _ => (own_name()?, size),
+    })
+}
+
+/// A running process of the binary we're interested in.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub pid: Pid,
+    pub cmdline: Vec<String>,
+    pub cwd: PathBuf,
+}
+
+impl Candidate {
+    pub fn from_pid(pid: Pid) -> Result<Candidate> {
+        let cmdline = fs::read(format!("/proc/{pid}/cmdline"))?
+            .split(|b| *b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).to_string())
+            .collect();
+        let cwd = fs::read_link(format!("/proc/{pid}/cwd"))?;
+        Ok(Candidate { pid, cmdline, cwd })
+    }
+
+    /// something a human can tell servers apart by: where it's running, and the pid
+    pub fn label(&self) -> String {
+        let dir = self
+            .cwd
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "root".to_string());
+        format!("{dir}-{}", self.pid)
+    }
+
+    /// the save a headless server was started with, i.e. `--start-server foo.zip` gives `foo`
+    pub fn save_name(&self) -> Option<String> {
+        let pos = self
+            .cmdline
+            .iter()
+            .position(|arg| arg == "--start-server")?;
+        let path = Path::new(self.cmdline.get(pos + 1)?);
+        Some(path.file_stem()?.to_string_lossy().to_string())
+    }
+}
+
+/// all the processes running `bin_path`, sorted by pid
+pub fn find_candidates(bin_path: impl AsRef<Path>) -> Result<Vec<Candidate>> {
     let mut candidates = Vec::with_capacity(4);
     let bin_path = bin_path.as_ref();
     for d in std::fs::read_dir("/proc")? {
@@ -40,20 +533,36 @@ pub fn find_pid(bin_path: impl AsRef<Path>) -> Result<Pid> {
         if !d.file_type()?.is_dir() {
             continue;
         }
+        let Ok(pid) = d.file_name().to_string_lossy().parse() else {
+            continue;
+        };
         match d.path().join("exe").read_link() {
-            Ok(p) => {
-                if p == bin_path {
-                    candidates.push(d.file_name().to_string_lossy().parse()?);
-                }
-            }
-            Err(_) => continue,
+            Ok(p) if p == bin_path => (),
+            _ => continue,
+        }
+        // the process may have gone away since we listed it
+        if let Ok(candidate) = Candidate::from_pid(Pid::from_raw(pid)) {
+            candidates.push(candidate);
         }
     }
+    candidates.sort_unstable_by_key(|c| c.pid);
+    Ok(candidates)
+}
 
-    match candidates.len() {
-        0 => bail!("pid not found"),
-        1 => return Ok(Pid::from_raw(candidates[0])),
-        _ => bail!("multiple pids found"),
+pub fn find_pid(bin_path: impl AsRef<Path>) -> Result<Pid> {
+    let candidates = find_candidates(bin_path)?;
+
+    match candidates.as_slice() {
+        [] => bail!("pid not found"),
+        [only] => Ok(only.pid),
+        _ => bail!(
+            "multiple pids found: {}; pick one with --pid",
+            candidates
+                .iter()
+                .map(|c| format!("{} (in {:?})", c.pid, c.cwd))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
     }
 }
 
@@ -83,31 +592,3 @@ pub fn find_thread(pid: Pid, name: &str) -> Result<Pid> {
     }
     bail!("thread not found");
 }
-
-/// demangle function names and take the first one that looks about right
-pub fn find_function(table: &HashMap<String, Symbol>, name: &str) -> Result<(String, u64, usize)> {
-    if let Some((addr, size)) = table.get(name) {
-        return Ok((name.to_string(), *addr, *size));
-    }
-
-    let opts = DemangleOptions::default().no_params().no_return_type();
-
-    for (raw, (addr, size)) in table {
-        if !raw.contains(name) {
-            continue;
-        }
-        let Ok(sym) = cpp_demangle::Symbol::new(raw) else {
-            continue;
-        };
-        let Ok(sym) = sym.demangle(&opts) else {
-            continue;
-        };
-        if sym != name {
-            continue;
-        }
-
-        return Ok((raw.to_string(), *addr, *size));
-    }
-
-    bail!("no function found")
-}
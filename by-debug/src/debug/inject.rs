@@ -1,14 +1,17 @@
-use std::slice;
+use std::fs;
 
 use anyhow::{anyhow, ensure, Result};
+use bytemuck::Zeroable;
+use nix::libc::{user_regs_struct, SYS_munmap};
 use nix::sys::ptrace;
 use nix::unistd::Pid;
 
+use super::offsets::Offsets;
 use super::pad_to_word;
-use super::ptrace::{read_words_arr, read_words_var, run_until_stop, write_words_ptr};
+use super::ptrace::{read_words_arr, read_words_var, run_until_stop, write_words_ptr, Memory};
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct CraftingLite {
     pub unit: u32,
     pub products: u32,
@@ -16,8 +19,16 @@ pub struct CraftingLite {
     pub _reserved: u32,
 }
 
+/// the size of the mapping made by `stage1.bin`
+pub const MAP_LEN: u64 = 100 * 640 * 1024;
+
+/// Our code and its data, in a mapping in the tracee.
+///
+/// Nothing frees the mapping unless you call [`Shell::remove`].
 pub struct Shell {
     pid: Pid,
+    /// where the stages run, for the teardown
+    scratch: u64,
     // TODO: private?
     pub map_addr: u64,
     shared_addr: u64,
@@ -28,8 +39,10 @@ impl Shell {
     const S_GET_STATUS: u64 = 8;
 
     // S_CAPACITY = 16
-    const S_COUNT: u64 = 24;
-    const S_DATA: u64 = 32;
+    const S_UNIT_NUMBER_OFFSET: u64 = 24;
+    // S_PRODUCTS_COMPLETE_OFFSET = 32
+    const S_COUNT: u64 = 40;
+    const S_DATA: u64 = 48;
 
     /// precondition: thread is stopped at a reasonable place, e.g. a breakpoint outside of a lock or syscall
     pub fn inject_into(pid: Pid, working_map: u64) -> Result<Self> {
@@ -49,22 +62,33 @@ impl Shell {
         mem.push(mock_get_status_addr);
         // 16-24: estimated capacity, based on the size of the mmap from stage1
         mem.push(60 * 1024 * 1024 / std::mem::size_of::<CraftingLite>() as u64);
-        // 24-32: size, set by code
+        // 24-40: where to find the fields in a Crafting, until we're told otherwise
+        mem.push(Offsets::KNOWN.unit_number);
+        mem.push(Offsets::KNOWN.products_finished);
+        // 40-48: size, set by code
         mem.push(0);
-        // 32+: data as a list of CraftingLite
+        // 48+: data as a list of CraftingLite
 
         write_words_ptr(pid, map_addr, &mem)?;
 
         Ok(Self {
             pid,
+            scratch: working_map,
             map_addr,
             shared_addr,
         })
     }
 
+    /// unmap the shell; same preconditions as injecting it
+    pub fn remove(self) -> Result<()> {
+        inject_munmap(self.pid, self.scratch, self.map_addr, MAP_LEN)
+    }
+
     pub fn enter(&self) -> Result<()> {
         let mut regs = ptrace::getregs(self.pid)?;
         regs.rip = self.map_addr;
+        // step over the red zone, and align the stack for the `call` at the start
+        regs.rsp = (regs.rsp - 128) & !0xf;
 
         // first param
         regs.rdi = self.shared_addr;
@@ -87,6 +111,15 @@ impl Shell {
         Ok(())
     }
 
+    pub fn set_offsets(&self, offsets: &Offsets) -> Result<()> {
+        write_words_ptr(
+            self.pid,
+            self.shared_addr + Self::S_UNIT_NUMBER_OFFSET,
+            &[offsets.unit_number, offsets.products_finished],
+        )?;
+        Ok(())
+    }
+
     pub fn read_count(&self) -> Result<usize> {
         let [count] = read_words_arr(self.pid, self.shared_addr + Self::S_COUNT)?;
         Ok(count as usize)
@@ -94,25 +127,12 @@ impl Shell {
 
     pub fn read_craftings(&self) -> Result<Vec<CraftingLite>> {
         let count = self.read_count()?;
-        let crafting_lite_size = std::mem::size_of::<CraftingLite>();
-
-        let needed_bytes = crafting_lite_size * count;
-        assert_eq!(crafting_lite_size % 8, 0);
-        let needed_words = needed_bytes / 8;
-
-        let words = read_words_var(self.pid, self.shared_addr + Self::S_DATA, needed_words)?;
-
-        // is this just transmute?
-        let craftings =
-            unsafe { slice::from_raw_parts(words.as_ptr() as *const CraftingLite, count) };
-
-        Ok(craftings.to_vec())
-    }
-}
-
-impl Drop for Shell {
-    fn drop(&mut self) {
-        let _ = ptrace::detach(self.pid, None);
+        let mut craftings = vec![CraftingLite::zeroed(); count];
+        Memory::new(self.pid).read(
+            self.shared_addr + Self::S_DATA,
+            bytemuck::cast_slice_mut(&mut craftings),
+        )?;
+        Ok(craftings)
     }
 }
 
@@ -120,28 +140,108 @@ impl Drop for Shell {
 pub fn inject_mmap(pid: Pid, scratch: u64) -> Result<u64> {
     let stage1 = pad_to_word(include_bytes!("../../shellcode/stage1.bin"), 0xcc);
 
-    let backup = read_words_var(pid, scratch, stage1.len())?;
-    write_words_ptr(pid, scratch, &stage1)?;
+    // let executed = regs.rip as i64 - from as i64;
+    // executed == stage1_bytes.len()
+    let map_addr = run_stage(pid, scratch, &stage1, |_| ())?;
+    ensure!(map_addr != u64::MAX, "mmap failed with -1");
+    // println!("{}", fs::read_to_string(format!("/proc/{}/maps", pid))?);
 
+    Ok(map_addr)
+}
+
+/// undo [`inject_mmap`]; same precondition
+pub fn inject_munmap(pid: Pid, scratch: u64, addr: u64, len: u64) -> Result<()> {
+    let stage = pad_to_word(include_bytes!("../../shellcode/munmap.bin"), 0xcc);
+
+    let ret = run_stage(pid, scratch, &stage, |regs| {
+        regs.rax = SYS_munmap as u64;
+        regs.rdi = addr;
+        regs.rsi = len;
+    })?;
+    // the raw syscall returns -errno
+    ensure!(ret == 0, "munmap failed with {}", ret as i64);
+    Ok(())
+}
+
+/// Run `stage` at `scratch`, with the registers as `setup` leaves them, until it stops; then
+/// put the scratch space and the registers back, even if it failed. Returns `rax`.
+fn run_stage(
+    pid: Pid,
+    scratch: u64,
+    stage: &[u64],
+    setup: impl FnOnce(&mut user_regs_struct),
+) -> Result<u64> {
+    let backup = read_words_var(pid, scratch, stage.len())?;
     let orig_regs = ptrace::getregs(pid)?;
-    let mut regs = orig_regs.clone();
-    regs.rip = scratch;
-    ptrace::setregs(pid, regs)?;
 
-    run_until_stop(pid)?;
+    let run = || -> Result<u64> {
+        write_words_ptr(pid, scratch, stage)?;
 
-    regs = ptrace::getregs(pid)?;
-    // let executed = regs.rip as i64 - from as i64;
-    // executed == stage1_bytes.len()
+        let mut regs = orig_regs;
+        regs.rip = scratch;
+        // if we stopped it in a syscall, don't let the kernel "restart" it in our stage
+        regs.orig_rax = u64::MAX;
+        setup(&mut regs);
+        ptrace::setregs(pid, regs)?;
 
-    let map_addr = regs.rax;
-    ensure!(map_addr != u64::MAX, "mmap failed with -1");
-    // println!("{}", fs::read_to_string(format!("/proc/{}/maps", pid))?);
+        run_until_stop(pid)?;
+
+        Ok(ptrace::getregs(pid)?.rax)
+    };
+    let ret = run();
 
     write_words_ptr(pid, scratch, &backup)?;
     ptrace::setregs(pid, orig_regs)?;
 
-    Ok(map_addr)
+    ret
+}
+
+/// Shells left by an earlier run which didn't get to remove them, by address; each is [`MAP_LEN`]
+/// long, and still starts with our code.
+///
+/// Mappings which are only the right shape could be the game's own, so are left alone.
+pub fn stale_shells(pid: Pid) -> Result<Vec<u64>> {
+    let (code, _) = shell_code();
+    let mut ret = Vec::new();
+    for (from, len) in stale_regions(pid)? {
+        // neighbouring ones get merged by the kernel
+        for addr in (from..from + len).step_by(MAP_LEN as usize) {
+            if read_words_var(pid, addr, code.len())? == code {
+                ret.push(addr);
+            } else {
+                println!("leaving a mapping shaped like a shell at 0x{addr:x}, it isn't running our code");
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Mappings shaped like ours, as `(address, length)`; whether they're ours is up to
+/// [`stale_shells`]. Neighbouring ones get merged by the kernel, so may be a few long.
+fn stale_regions(pid: Pid) -> Result<Vec<(u64, u64)>> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    let mut ret = Vec::new();
+    for line in maps.lines() {
+        let mut it = line.split_whitespace();
+
+        let addrs = it.next().ok_or_else(|| anyhow!("no addrs"))?;
+        let perms = it.next().ok_or_else(|| anyhow!("no perms"))?;
+        let _offset = it.next().ok_or_else(|| anyhow!("no offset"))?;
+        let _dev = it.next().ok_or_else(|| anyhow!("no dev"))?;
+        let inode = it.next().ok_or_else(|| anyhow!("no inode"))?;
+        // anonymous, and not even named like [heap]
+        if perms != "rwxp" || inode != "0" || it.next().is_some() {
+            continue;
+        }
+
+        let (from, to) = addrs.split_once('-').ok_or_else(|| anyhow!("no -"))?;
+        let from = u64::from_str_radix(from, 16)?;
+        let to = u64::from_str_radix(to, 16)?;
+        if (to - from) % MAP_LEN == 0 {
+            ret.push((from, to - from));
+        }
+    }
+    Ok(ret)
 }
 
 pub fn entry_in_addr(addr_file: &str) -> Result<u64> {
@@ -190,3 +290,44 @@ fn shell_code() -> (Vec<u64>, u64) {
 
     (mem, mock_get_status_off)
 }
+
+#[cfg(test)]
+mod test {
+    use nix::libc;
+    use nix::unistd::getpid;
+
+    use super::*;
+
+    #[test]
+    fn only_our_shells_are_stale() -> Result<()> {
+        let len = usize::try_from(MAP_LEN * 3)?;
+        // SAFETY: a fresh anonymous mapping, which nothing else knows about
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(libc::MAP_FAILED, base);
+        let base = base as u64;
+
+        // one mapping, as if the kernel had merged three: ours in the middle, between two of
+        // the same shape which aren't
+        let (code, _) = shell_code();
+        write_words_ptr(getpid(), base + MAP_LEN, &code)?;
+        write_words_ptr(getpid(), base + 2 * MAP_LEN, &code[..code.len() - 1])?;
+        let found = stale_shells(getpid())?
+            .into_iter()
+            .filter(|addr| (base..base + 3 * MAP_LEN).contains(addr))
+            .collect::<Vec<_>>();
+
+        // SAFETY: as mapped above, and nothing refers to it
+        unsafe { libc::munmap(base as *mut libc::c_void, len) };
+        assert_eq!(vec![base + MAP_LEN], found);
+        Ok(())
+    }
+}
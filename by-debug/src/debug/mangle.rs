@@ -1,209 +1,799 @@
-use std::hash::Hasher;
-use std::num::NonZeroUsize;
+use std::fmt;
 
-use anyhow::{anyhow, Context, Result};
-use cpp_demangle::{DemangleNodeType, DemangleOptions};
-use cpp_demangle::{DemangleWrite, Symbol};
+use anyhow::{anyhow, bail, Context, Result};
+use cpp_demangle::{DemangleOptions, Symbol};
 use nom::branch::alt;
-use nom::combinator::{complete, opt};
+use nom::combinator::{all_consuming, map, opt};
 use nom::error::{context, ErrorKind, VerboseError};
-use nom::multi::separated_list0;
-use nom::sequence::{delimited, preceded};
+use nom::multi::{many0, separated_list0, separated_list1};
+use nom::sequence::{delimited, preceded, terminated};
 use nom::{error_position, Finish, IResult};
+use once_cell::sync::Lazy;
+use regex::Regex;
 
+/// A demangled function: `name(args) qualifiers`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Func {
+    pub name: Name,
+    pub args: Vec<Type>,
+    /// after the arguments, for methods, e.g. `const`
+    pub qualifiers: Qualifiers,
+}
+
+/// e.g. `std::vector<int>::push_back`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Name(pub Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub ident: Ident,
+    /// e.g. `cxx11`, from `[abi:cxx11]`
+    pub abi_tags: Vec<String>,
+    pub template_args: Option<Vec<TemplateArg>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ident {
+    /// including things like `(anonymous namespace)` and `{lambda()#1}`
+    Plain(String),
+    /// the symbol, e.g. `==`, or `new[]`
+    Operator(String),
+    /// `operator int`
+    Conversion(Box<Type>),
+    /// `~Foo`
+    Destructor(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateArg {
+    Type(Type),
+    /// anything else, e.g. `3`, `(Foo)1`, or `true`, as it was printed
+    Value(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    /// including the builtins, like `unsigned int`
+    Named {
+        name: Name,
+        cv: Cv,
+    },
+    Pointer {
+        to: Box<Type>,
+        cv: Cv,
+    },
+    Ref(Box<Type>),
+    RvalueRef(Box<Type>),
+    Array {
+        of: Box<Type>,
+        len: Option<String>,
+    },
+    /// as in a pointer to one, `void (*)(int)`
+    Function {
+        ret: Box<Type>,
+        args: Vec<Type>,
+        qualifiers: Qualifiers,
+    },
+    /// `int Foo::*`, or with a [`Type::Function`], a pointer to a method
+    MemberPointer {
+        class: Name,
+        to: Box<Type>,
+        cv: Cv,
+    },
+    /// `...`, in an argument list
+    Ellipsis,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cv {
+    pub is_const: bool,
+    pub is_volatile: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Qualifiers {
+    pub cv: Cv,
+    pub ref_qualifier: Option<RefQualifier>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefQualifier {
+    Lvalue,
+    Rvalue,
+}
+
+/// What the demangled text is made of.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Token {
     Label(String),
-    Type(DemangleNodeType),
+    Number(String),
+    /// `operator` and its symbol, e.g. `+`
+    Operator(String),
+    /// `operator`, followed by the type it converts to
+    Conversion,
+    AbiTag(String),
     DoubleColon,
+    OpenAngle,
+    CloseAngle,
     OpenParen,
     CloseParen,
+    OpenBracket,
+    CloseBracket,
     Comma,
     Star,
-    TypeInfo,
-    Space,
-    Const,
     Amp,
+    AmpAmp,
     Tilde,
-
-    End,
+    Ellipsis,
+    Const,
+    Volatile,
 }
 
-pub fn structured_demangle(sym: &Symbol<&str>) -> Result<Vec<Token>> {
-    struct S {
-        results: Vec<Token>,
+/// longest first, so `<<=` isn't read as `<`
+const OPERATORS: [&str; 38] = [
+    "<=>", "<<=", ">>=", "->*", "->", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "++", "--",
+    "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "()", "[]", "+", "-", "*", "/", "%", "^", "&",
+    "|", "~", "!", "=", "<", ">",
+];
+
+/// Split demangled text, as printed by `cpp_demangle`, into [`Token`]s.
+pub fn tokenise(s: &str) -> Result<Vec<Token>> {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '$';
+    let mut ret = Vec::with_capacity(s.len() / 4);
+    let mut rest = s;
+    loop {
+        rest = rest.trim_start();
+        let Some(c) = rest.chars().next() else {
+            return Ok(ret);
+        };
+
+        let (token, len) = if let Some(special) = ["(anonymous namespace)", "decltype(nullptr)"]
+            .iter()
+            .find(|special| rest.starts_with(**special))
+        {
+            (Token::Label(special.to_string()), special.len())
+        } else if c == '{' {
+            // e.g. {lambda(int)#1}, or {unnamed type#2}
+            let mut depth = 0usize;
+            let end = rest
+                .char_indices()
+                .find(|&(_, c)| {
+                    match c {
+                        '{' => depth += 1,
+                        '}' => depth -= 1,
+                        _ => (),
+                    }
+                    depth == 0
+                })
+                .ok_or_else(|| anyhow!("unbalanced braces in {s:?}"))?
+                .0;
+            (Token::Label(rest[..=end].to_string()), end + 1)
+        } else if let Some(tag) = rest.strip_prefix("[abi:") {
+            let end = tag
+                .find(']')
+                .ok_or_else(|| anyhow!("unterminated abi tag in {s:?}"))?;
+            (
+                Token::AbiTag(tag[..end].to_string()),
+                "[abi:".len() + end + 1,
+            )
+        } else if rest.starts_with("...") {
+            (Token::Ellipsis, 3)
+        } else if rest.starts_with("::") {
+            (Token::DoubleColon, 2)
+        } else if rest.starts_with("&&") {
+            (Token::AmpAmp, 2)
+        } else if c.is_ascii_digit() || c == '-' {
+            let len = rest
+                .find(|c: char| !is_ident(c) && c != '.' && c != '-')
+                .unwrap_or(rest.len());
+            (Token::Number(rest[..len].to_string()), len)
+        } else if is_ident(c) {
+            let len = rest.find(|c| !is_ident(c)).unwrap_or(rest.len());
+            match &rest[..len] {
+                "const" => (Token::Const, len),
+                "volatile" => (Token::Volatile, len),
+                "operator" => {
+                    let after = &rest[len..];
+                    let trimmed = after.trim_start();
+                    let skipped = len + after.len() - trimmed.len();
+                    if let Some(op) = OPERATORS.iter().find(|op| trimmed.starts_with(**op)) {
+                        (Token::Operator(op.to_string()), skipped + op.len())
+                    } else if let Some(op) = ["new[]", "delete[]", "new", "delete"]
+                        .iter()
+                        .find(|op| trimmed.starts_with(**op))
+                    {
+                        (Token::Operator(op.to_string()), skipped + op.len())
+                    } else if trimmed.starts_with(',') {
+                        (Token::Operator(",".to_string()), skipped + 1)
+                    } else {
+                        (Token::Conversion, len)
+                    }
+                }
+                label => (Token::Label(label.to_string()), len),
+            }
+        } else {
+            let token = match c {
+                '<' => Token::OpenAngle,
+                '>' => Token::CloseAngle,
+                '(' => Token::OpenParen,
+                ')' => Token::CloseParen,
+                '[' => Token::OpenBracket,
+                ']' => Token::CloseBracket,
+                ',' => Token::Comma,
+                '*' => Token::Star,
+                '&' => Token::Amp,
+                '~' => Token::Tilde,
+                other => bail!("unexpected {other:?} in {s:?}"),
+            };
+            (token, 1)
+        };
+        ret.push(token);
+        rest = &rest[len..];
     }
+}
 
-    impl DemangleWrite for S {
-        fn push_demangle_node(&mut self, nt: DemangleNodeType) {
-            self.results.push(Token::Type(nt));
-        }
+type PResult<'t, O> = IResult<&'t [Token], O, VerboseError<&'t [Token]>>;
 
-        fn write_string(&mut self, s: &str) -> std::fmt::Result {
-            self.results.push(match s {
-                "(" => Token::OpenParen,
-                ")" => Token::CloseParen,
-                "::" => Token::DoubleColon,
-                "*" => Token::Star,
-                "&" => Token::Amp,
-                "typeinfo name for " => Token::TypeInfo,
-                "typeinfo for " => Token::TypeInfo,
-                " " => Token::Space,
-                "const" => Token::Const,
-                "," => Token::Comma,
-                ", " => Token::Comma,
-                "~" => Token::Tilde,
-                _ => Token::Label(s.to_string()),
-            });
-            Ok(())
+fn tag(expected: Token) -> impl FnMut(&[Token]) -> PResult<'_, Token> {
+    move |input: &[Token]| -> PResult<'_, Token> {
+        // not `Incomplete`, as there's never any more coming
+        if input.first() == Some(&expected) {
+            Ok((&input[1..], expected.clone()))
+        } else {
+            Err(nom::Err::Error(error_position!(input, ErrorKind::Tag)))
         }
+    }
+}
 
-        fn pop_demangle_node(&mut self) {
-            self.results.push(Token::End);
-        }
+fn label(input: &[Token]) -> PResult<'_, String> {
+    match input.first() {
+        Some(Token::Label(s)) => Ok((&input[1..], s.to_string())),
+        _ => Err(nom::Err::Error(error_position!(input, ErrorKind::Alpha))),
     }
+}
 
-    let mut s = S {
-        results: Vec::with_capacity(16),
-    };
-    sym.structured_demangle(&mut s, &DemangleOptions::default())?;
+fn abi_tag(input: &[Token]) -> PResult<'_, String> {
+    match input.first() {
+        Some(Token::AbiTag(s)) => Ok((&input[1..], s.to_string())),
+        _ => Err(nom::Err::Error(error_position!(input, ErrorKind::Tag))),
+    }
+}
 
-    Ok(s.results)
+fn operator(input: &[Token]) -> PResult<'_, Ident> {
+    match input.first() {
+        Some(Token::Operator(s)) => Ok((&input[1..], Ident::Operator(s.to_string()))),
+        _ => Err(nom::Err::Error(error_position!(input, ErrorKind::Tag))),
+    }
 }
 
-fn tag(
-    expected: Token,
-) -> impl FnMut(&[Token]) -> IResult<&[Token], Token, VerboseError<&[Token]>> {
-    move |input: &[Token]| -> IResult<&[Token], Token, VerboseError<&[Token]>> {
-        if input.is_empty() {
-            return Err(nom::Err::Incomplete(nom::Needed::Size(
-                NonZeroUsize::new(1).expect("static"),
-            )));
-        }
+fn cv(input: &[Token]) -> PResult<'_, Cv> {
+    let (input, quals) = many0(alt((tag(Token::Const), tag(Token::Volatile))))(input)?;
+    Ok((
+        input,
+        Cv {
+            is_const: quals.contains(&Token::Const),
+            is_volatile: quals.contains(&Token::Volatile),
+        },
+    ))
+}
 
-        if input[0] == expected {
-            Ok((&input[1..], expected.clone()))
-        } else {
-            Err(nom::Err::Error(error_position!(input, ErrorKind::Tag)))
-        }
-    }
+fn qualifiers(input: &[Token]) -> PResult<'_, Qualifiers> {
+    let (input, cv) = cv(input)?;
+    let (input, ref_qualifier) = opt(alt((
+        map(tag(Token::Amp), |_| RefQualifier::Lvalue),
+        map(tag(Token::AmpAmp), |_| RefQualifier::Rvalue),
+    )))(input)?;
+    Ok((input, Qualifiers { cv, ref_qualifier }))
 }
 
-fn unqualified_name(input: &[Token]) -> IResult<&[Token], String, VerboseError<&[Token]>> {
-    context("unqualified_name", delimited(
-        tag(Token::Type(DemangleNodeType::UnqualifiedName)),
-        label,
-        tag(Token::End),
-    ))(input)
+fn ident(input: &[Token]) -> PResult<'_, Ident> {
+    context(
+        "ident",
+        alt((
+            operator,
+            map(preceded(tag(Token::Conversion), conversion_type), |t| {
+                Ident::Conversion(Box::new(t))
+            }),
+            map(preceded(tag(Token::Tilde), label), Ident::Destructor),
+            map(label, Ident::Plain),
+        )),
+    )(input)
 }
 
-fn label(input: &[Token]) -> IResult<&[Token], String, VerboseError<&[Token]>> {
-    match input.get(0) {
-        Some(Token::Label(s)) => Ok((&input[1..], s.to_string())),
-        _ => Err(nom::Err::Error(error_position!(input, ErrorKind::Alpha))),
-    }
+fn segment(input: &[Token]) -> PResult<'_, Segment> {
+    let (input, ident) = ident(input)?;
+    let (input, abi_tags) = many0(abi_tag)(input)?;
+    let (input, template_args) = opt(template_args)(input)?;
+    Ok((
+        input,
+        Segment {
+            ident,
+            abi_tags,
+            template_args,
+        },
+    ))
 }
 
-fn prefix(input: &[Token]) -> IResult<&[Token], String, VerboseError<&[Token]>> {
-    delimited(
-        tag(Token::Type(DemangleNodeType::Prefix)),
-        unqualified_name,
-        tag(Token::End),
+fn name(input: &[Token]) -> PResult<'_, Name> {
+    context(
+        "name",
+        map(separated_list1(tag(Token::DoubleColon), segment), Name),
     )(input)
 }
 
-fn nested_name(input: &[Token]) -> IResult<&[Token], (String, String), VerboseError<&[Token]>> {
-    let (input, _) = tag(Token::Type(DemangleNodeType::NestedName))(input)?;
-    let (input, prefix) = prefix(input)?;
-    let (input, _) = tag(Token::DoubleColon)(input)?;
-    let (input, suffix) = unqualified_name(input)?;
-    let (input, _) = tag(Token::End)(input)?;
-    Ok((input, (prefix, suffix)))
+fn template_args(input: &[Token]) -> PResult<'_, Vec<TemplateArg>> {
+    context(
+        "template_args",
+        delimited(
+            tag(Token::OpenAngle),
+            separated_list0(tag(Token::Comma), template_arg),
+            tag(Token::CloseAngle),
+        ),
+    )(input)
 }
 
-fn space_const(input: &[Token]) -> IResult<&[Token], (), VerboseError<&[Token]>> {
-    let (input, _) = opt(tag(Token::Space))(input)?;
-    let (input, _) = opt(tag(Token::Const))(input)?;
-    Ok((input, ()))
+fn template_arg(input: &[Token]) -> PResult<'_, TemplateArg> {
+    if let Some(Token::Label(b)) = input.first() {
+        if b == "true" || b == "false" {
+            return Ok((&input[1..], TemplateArg::Value(b.to_string())));
+        }
+    }
+    alt((
+        map(terminated(typ, template_arg_end), TemplateArg::Type),
+        map(value, TemplateArg::Value),
+    ))(input)
 }
 
-fn typ(input: &[Token]) -> IResult<&[Token], String, VerboseError<&[Token]>> {
-    alt((unqualified_name, label))(input)
+fn template_arg_end(input: &[Token]) -> PResult<'_, ()> {
+    match input.first() {
+        Some(Token::Comma | Token::CloseAngle) => Ok((input, ())),
+        _ => Err(nom::Err::Error(error_position!(input, ErrorKind::Eof))),
+    }
 }
 
-fn arg(input: &[Token]) -> IResult<&[Token], (String, String), VerboseError<&[Token]>> {
-    let (input, name) = typ(input)?;
-    let (input, _) = space_const(input)?;
-    let (input, suffix) = opt(alt((tag(Token::Star), tag(Token::Amp))))(input)?;
+/// up to the next `,` or `>` outside of any brackets
+fn value(input: &[Token]) -> PResult<'_, String> {
+    let mut depth = 0usize;
+    let mut len = 0;
+    for token in input {
+        match token {
+            Token::OpenParen | Token::OpenBracket | Token::OpenAngle => depth += 1,
+            Token::CloseParen | Token::CloseBracket | Token::CloseAngle if depth > 0 => depth -= 1,
+            Token::Comma | Token::CloseAngle if depth == 0 => break,
+            Token::CloseParen | Token::CloseBracket => break,
+            _ => (),
+        }
+        len += 1;
+    }
+    if len == 0 {
+        return Err(nom::Err::Error(error_position!(input, ErrorKind::Many1)));
+    }
+    let wordy = |t: &Token| matches!(t, Token::Label(_) | Token::Number(_) | Token::Const);
+    let mut printed = String::new();
+    for (i, token) in input[..len].iter().enumerate() {
+        if i > 0 && wordy(token) && wordy(&input[i - 1]) {
+            printed.push(' ');
+        }
+        printed.push_str(&token.to_string());
+    }
+    Ok((&input[len..], printed))
+}
 
-    let suffix = match suffix {
-        Some(Token::Star) => "*",
-        Some(Token::Amp) => "&",
-        None => "",
-        other => todo!("serialisation for {other:#?}"),
-    };
+const BUILTIN_WORDS: [&str; 17] = [
+    "void",
+    "bool",
+    "char",
+    "wchar_t",
+    "char8_t",
+    "char16_t",
+    "char32_t",
+    "short",
+    "int",
+    "long",
+    "float",
+    "double",
+    "signed",
+    "unsigned",
+    "__int128",
+    "__float128",
+    "decltype(nullptr)",
+];
+
+/// `unsigned long long`, or a [`Name`]
+fn base_name(input: &[Token]) -> PResult<'_, Name> {
+    let mut words = Vec::new();
+    let mut rest = input;
+    while let Some(Token::Label(word)) = rest.first() {
+        if !BUILTIN_WORDS.contains(&word.as_str()) {
+            break;
+        }
+        words.push(word.as_str());
+        rest = &rest[1..];
+    }
+    if words.is_empty() {
+        return name(input);
+    }
+    Ok((
+        rest,
+        Name(vec![Segment {
+            ident: Ident::Plain(words.join(" ")),
+            abi_tags: Vec::new(),
+            template_args: None,
+        }]),
+    ))
+}
 
-    Ok((input, (name, suffix.to_string())))
+enum Declarator {
+    Pointer(Cv),
+    Ref,
+    RvalueRef,
+    Member(Name, Cv),
 }
 
-fn arg_list(input: &[Token]) -> IResult<&[Token], Vec<(String, String)>, VerboseError<&[Token]>> {
-    separated_list0(tag(Token::Comma), arg)(input)
+impl Declarator {
+    fn apply(self, to: Type) -> Type {
+        let to = Box::new(to);
+        match self {
+            Declarator::Pointer(cv) => Type::Pointer { to, cv },
+            Declarator::Ref => Type::Ref(to),
+            Declarator::RvalueRef => Type::RvalueRef(to),
+            Declarator::Member(class, cv) => Type::MemberPointer { class, to, cv },
+        }
+    }
+}
+
+fn declarator(input: &[Token]) -> PResult<'_, Declarator> {
+    alt((
+        map(preceded(tag(Token::Star), cv), Declarator::Pointer),
+        map(tag(Token::Amp), |_| Declarator::Ref),
+        map(tag(Token::AmpAmp), |_| Declarator::RvalueRef),
+        map(
+            |input| {
+                let (input, class) = name(input)?;
+                let (input, _) = tag(Token::DoubleColon)(input)?;
+                let (input, _) = tag(Token::Star)(input)?;
+                let (input, cv) = cv(input)?;
+                Ok((input, (class, cv)))
+            },
+            |(class, cv)| Declarator::Member(class, cv),
+        ),
+    ))(input)
 }
 
-fn args(input: &[Token]) -> IResult<&[Token], Vec<(String, String)>, VerboseError<&[Token]>> {
-    delimited(tag(Token::OpenParen), arg_list, tag(Token::CloseParen))(input)
+fn array_len(input: &[Token]) -> PResult<'_, Option<String>> {
+    let (input, _) = tag(Token::OpenBracket)(input)?;
+    let (input, len) = opt(value)(input)?;
+    let (input, _) = tag(Token::CloseBracket)(input)?;
+    Ok((input, len))
 }
 
-fn opt_tag(tag: Token) -> impl FnMut(&[Token]) -> IResult<&[Token], (), VerboseError<&[Token]>> {
-    move |input: &[Token]| -> IResult<&[Token], (), VerboseError<&[Token]>> {
-        match input.get(0) {
-            Some(t) if t == &tag => Ok((&input[1..], ())),
-            _ => Ok((input, ())),
+/// what's after the type in e.g. `void (*)(int)`, or `int (&) [3]`
+fn suffix(input: &[Token], t: Type) -> PResult<'_, Type> {
+    if let Ok((input, len)) = array_len(input) {
+        return Ok((
+            input,
+            Type::Array {
+                of: Box::new(t),
+                len,
+            },
+        ));
+    }
+
+    let (input, _) = tag(Token::OpenParen)(input)?;
+    if let Ok((input, declarators)) = many0(declarator)(input) {
+        if !declarators.is_empty() {
+            let (input, _) = tag(Token::CloseParen)(input)?;
+            let (input, inner) = suffix(input, t)?;
+            let t = declarators
+                .into_iter()
+                .fold(inner, |t, declarator| declarator.apply(t));
+            return Ok((input, t));
         }
     }
+    let (input, args) = arg_list(input)?;
+    let (input, _) = tag(Token::CloseParen)(input)?;
+    let (input, qualifiers) = qualifiers(input)?;
+    Ok((
+        input,
+        Type::Function {
+            ret: Box::new(t),
+            args,
+            qualifiers,
+        },
+    ))
 }
 
-fn func(input: &[Token]) -> IResult<&[Token], Func, VerboseError<&[Token]>> {
-    let (input, (p, s)) = nested_name(input)?;
-    let (mut input, args) = args(input)?;
-    if matches!(input.get(0), Some(Token::Space)) {
-        input = &input[1..];
+fn typ(input: &[Token]) -> PResult<'_, Type> {
+    let (mut input, mut t) = named(input)?;
+    loop {
+        if let Ok((rest, declarator)) = declarator(input) {
+            t = declarator.apply(t);
+            input = rest;
+            continue;
+        }
+        match suffix(input, t.clone()) {
+            Ok((rest, suffixed)) => {
+                t = suffixed;
+                input = rest;
+            }
+            Err(_) => return Ok((input, t)),
+        }
     }
-    let (input, _) = opt_tag(Token::Space)(input)?;
-    let (input, _) = opt_tag(Token::Const)(input)?;
+}
+
+/// `const Foo`, or `Foo const`
+fn named(input: &[Token]) -> PResult<'_, Type> {
+    let (input, before) = cv(input)?;
+    let (input, name) = context("type", base_name)(input)?;
+    let (input, after) = cv(input)?;
+    let cv = Cv {
+        is_const: before.is_const || after.is_const,
+        is_volatile: before.is_volatile || after.is_volatile,
+    };
+    Ok((input, Type::Named { name, cv }))
+}
+
+/// no function types, or we'd eat the arguments of the `operator int()` itself
+fn conversion_type(input: &[Token]) -> PResult<'_, Type> {
+    let (input, t) = named(input)?;
+    let (input, declarators) = many0(declarator)(input)?;
+    Ok((
+        input,
+        declarators
+            .into_iter()
+            .fold(t, |t, declarator| declarator.apply(t)),
+    ))
+}
+
+fn arg_list(input: &[Token]) -> PResult<'_, Vec<Type>> {
+    separated_list0(
+        tag(Token::Comma),
+        alt((map(tag(Token::Ellipsis), |_| Type::Ellipsis), typ)),
+    )(input)
+}
+
+fn func(input: &[Token]) -> PResult<'_, Func> {
+    let (input, name) = name(input)?;
+    let (input, args) = context(
+        "args",
+        delimited(tag(Token::OpenParen), arg_list, tag(Token::CloseParen)),
+    )(input)?;
+    let (input, qualifiers) = qualifiers(input)?;
     Ok((
         input,
         Func {
-            name: format!("{p}::{s}"),
+            name,
             args,
+            qualifiers,
         },
     ))
 }
 
-#[derive(Debug)]
-pub struct Func {
-    pub name: String,
-    pub args: Vec<(String, String)>,
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Label(s) | Token::Number(s) => f.write_str(s),
+            Token::Operator(op) if op.starts_with(char::is_alphabetic) => {
+                write!(f, "operator {op}")
+            }
+            Token::Operator(op) => write!(f, "operator{op}"),
+            Token::Conversion => f.write_str("operator"),
+            Token::AbiTag(tag) => write!(f, "[abi:{tag}]"),
+            Token::DoubleColon => f.write_str("::"),
+            Token::OpenAngle => f.write_str("<"),
+            Token::CloseAngle => f.write_str(">"),
+            Token::OpenParen => f.write_str("("),
+            Token::CloseParen => f.write_str(")"),
+            Token::OpenBracket => f.write_str("["),
+            Token::CloseBracket => f.write_str("]"),
+            Token::Comma => f.write_str(", "),
+            Token::Star => f.write_str("*"),
+            Token::Amp => f.write_str("&"),
+            Token::AmpAmp => f.write_str("&&"),
+            Token::Tilde => f.write_str("~"),
+            Token::Ellipsis => f.write_str("..."),
+            Token::Const => f.write_str("const"),
+            Token::Volatile => f.write_str("volatile"),
+        }
+    }
 }
 
-pub fn demangle(raw: &str) -> Result<Func> {
-    let hack = raw.replace("Thn208_", "");
-    let hack = hack.strip_suffix(".cold").unwrap_or(&hack);
-    let hack = hack.strip_suffix(".constprop.0").unwrap_or(&hack);
+impl fmt::Display for Func {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}({}){}",
+            self.name,
+            Joined(&self.args),
+            self.qualifiers
+        )
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("::")?;
+            }
+            match &segment.ident {
+                Ident::Plain(s) => f.write_str(s)?,
+                Ident::Operator(op) if op.starts_with(char::is_alphabetic) => {
+                    write!(f, "operator {op}")?
+                }
+                Ident::Operator(op) => write!(f, "operator{op}")?,
+                Ident::Conversion(t) => write!(f, "operator {t}")?,
+                Ident::Destructor(s) => write!(f, "~{s}")?,
+            }
+            for tag in &segment.abi_tags {
+                write!(f, "[abi:{tag}]")?;
+            }
+            if let Some(args) = &segment.template_args {
+                let args = Joined(args).to_string();
+                // `> >`, so it's still valid before C++11
+                let space = if args.ends_with('>') { " " } else { "" };
+                write!(f, "<{args}{space}>")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TemplateArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateArg::Type(t) => t.fmt(f),
+            TemplateArg::Value(v) => f.write_str(v),
+        }
+    }
+}
+
+impl fmt::Display for Cv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_const {
+            f.write_str(" const")?;
+        }
+        if self.is_volatile {
+            f.write_str(" volatile")?;
+        }
+        Ok(())
+    }
+}
 
-    let sym = Symbol::new(hack)?;
-    let tokens = structured_demangle(&sym)?;
-    let v = complete(func)(&tokens);
-    match v.finish() {
-        Ok((rem, f)) if rem.is_empty() => Ok(f),
-        Ok((rem, f)) => {
-            Err(anyhow!("leftover tokens: {:#?}", rem)).with_context(|| anyhow!("parsed: {:#?}", f))
+impl fmt::Display for Qualifiers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.cv.fmt(f)?;
+        match self.ref_qualifier {
+            Some(RefQualifier::Lvalue) => f.write_str(" &"),
+            Some(RefQualifier::Rvalue) => f.write_str(" &&"),
+            None => Ok(()),
         }
-        Err(e) => Err(anyhow!("nom internal: {e:?}")),
     }
-    .with_context(|| anyhow!("input: {:#?}", tokens))
-    .with_context(|| anyhow!("original: {}", sym))
-        .with_context(|| anyhow!("hack: {}", hack))
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.declare(String::new()))
+    }
+}
+
+impl Type {
+    /// C's inside-out declarators: `inner` is what's been wrapped around us so far, e.g. `(*)`
+    fn declare(&self, inner: String) -> String {
+        // pointers to functions and arrays need brackets, as the suffix would bind first
+        let wrap = |to: &Type, inner: String| match to {
+            Type::Function { .. } | Type::Array { .. } => format!("({inner})"),
+            _ => inner,
+        };
+        match self {
+            Type::Named { name, cv } => {
+                let space = if inner.starts_with(|c: char| c.is_alphanumeric() || c == '(') {
+                    " "
+                } else {
+                    ""
+                };
+                format!("{name}{cv}{space}{inner}")
+            }
+            Type::Pointer { to, cv } => to.declare(wrap(to, format!("*{cv}{inner}"))),
+            Type::Ref(to) => to.declare(wrap(to, format!("&{inner}"))),
+            Type::RvalueRef(to) => to.declare(wrap(to, format!("&&{inner}"))),
+            Type::MemberPointer { class, to, cv } => {
+                to.declare(wrap(to, format!("{class}::*{cv}{inner}")))
+            }
+            Type::Array { of, len } => {
+                of.declare(format!("{inner} [{}]", len.as_deref().unwrap_or("")))
+            }
+            Type::Function {
+                ret,
+                args,
+                qualifiers,
+            } => ret.declare(format!("{inner}({}){qualifiers}", Joined(args))),
+            Type::Ellipsis => "...".to_string(),
+        }
+    }
+}
+
+struct Joined<'a, T>(&'a [T]);
+
+impl<T: fmt::Display> fmt::Display for Joined<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, item) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            item.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// A mangled name, without the decorations the compiler adds to copies of the function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Normalised<'s> {
+    /// what the function would have been called, without the decorations
+    pub mangled: String,
+    /// e.g. `.isra.0.cold`, for a specialised or split-out copy
+    pub clone_suffix: Option<&'s str>,
+    /// a `this`-adjusting thunk, which jumps to the real function
+    pub thunk: bool,
+}
+
+static CLONE_SUFFIX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?:\.(?:isra|part|constprop|cold|lto_priv)(?:\.\d+)?)+$").expect("static regex")
+});
+
+/// `Th <offset> _` (non-virtual), or `Tv <offset> _ <virtual offset> _`, where offsets may be negative
+static THUNK: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^_ZT(?:hn?\d+_|vn?\d+_n?\d+_)").expect("static regex"));
+
+/// Strip GCC's clone suffixes (`.isra.N`, `.part.N`, `.constprop.N`, `.cold`...) and thunk prefixes.
+pub fn normalise(raw: &str) -> Normalised<'_> {
+    let (base, clone_suffix) = match CLONE_SUFFIX.find(raw) {
+        Some(m) => (&raw[..m.start()], Some(m.as_str())),
+        None => (raw, None),
+    };
+    match THUNK.find(base) {
+        Some(m) => Normalised {
+            mangled: format!("_Z{}", &base[m.end()..]),
+            clone_suffix,
+            thunk: true,
+        },
+        None => Normalised {
+            mangled: base.to_string(),
+            clone_suffix,
+            thunk: false,
+        },
+    }
+}
+
+/// Demangle a function's symbol; failing on anything else, like data, or a vtable.
+///
+/// Clone suffixes and thunk prefixes are ignored, see [`normalise`].
+pub fn demangle(raw: &str) -> Result<Func> {
+    let mangled = normalise(raw).mangled;
+    let sym = Symbol::new(mangled.as_str())?;
+    let text = sym.demangle(&DemangleOptions::default().no_return_type())?;
+    let tokens = tokenise(&text)?;
+    let parsed = all_consuming(func)(&tokens).finish();
+    match parsed {
+        Ok((_, f)) => Ok(f),
+        Err(e) => Err(anyhow!(
+            "unexpected {}, in {:?}",
+            match e.errors.first().and_then(|(at, _)| at.first()) {
+                Some(token) => format!("`{token}`"),
+                None => "end".to_string(),
+            },
+            e.errors
+                .iter()
+                .filter_map(|(_, kind)| match kind {
+                    nom::error::VerboseErrorKind::Context(c) => Some(*c),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        )),
+    }
+    .with_context(|| anyhow!("demangled: {text}"))
+    .with_context(|| anyhow!("raw: {raw}"))
 }
 
 #[cfg(test)]
@@ -240,6 +830,31 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn normalise_clones_and_thunks() {
+        let n = normalise("_ZN8MainLoop14gameUpdateStepEv.isra.0");
+        assert_eq!("_ZN8MainLoop14gameUpdateStepEv", n.mangled);
+        assert_eq!(Some(".isra.0"), n.clone_suffix);
+        assert!(!n.thunk);
+
+        let n = normalise("_ZN3Foo3barEi.part.0.cold");
+        assert_eq!("_ZN3Foo3barEi", n.mangled);
+        assert_eq!(Some(".part.0.cold"), n.clone_suffix);
+
+        let n = normalise("_ZThn16_NK15CraftingMachine17getAllowedEffectsEv");
+        assert_eq!("_ZNK15CraftingMachine17getAllowedEffectsEv", n.mangled);
+        assert_eq!(None, n.clone_suffix);
+        assert!(n.thunk);
+
+        let n = normalise("_ZTv0_n24_N3Foo3bazEv.constprop.2");
+        assert_eq!("_ZN3Foo3bazEv", n.mangled);
+        assert!(n.thunk);
+
+        // not a clone, just a name with a dot in
+        assert_eq!(None, normalise("main.isrant").clone_suffix);
+        assert_eq!("main", normalise("main").mangled);
+    }
+
     #[test]
     fn insta_qualified_arg() -> Result<()> {
         insta::assert_debug_snapshot!(demangle(
@@ -247,4 +862,93 @@ mod test {
         )?);
         Ok(())
     }
+
+    #[test]
+    fn insta_insert_unique() -> Result<()> {
+        insta::assert_debug_snapshot!(demangle(
+            "_ZNSt8_Rb_treeIP15CraftingMachineS1_St9_IdentityIS1_E20UnitNumberComparatorSaIS1_EE16_M_insert_uniqueIS1_EESt4pairISt17_Rb_tree_iteratorIS1_EbEOT_"
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn insta_game_update_step() -> Result<()> {
+        let f = demangle("_ZN8MainLoop14gameUpdateStepEP22MultiplayerManagerBaseP8ScenarioP10AppManagerNS_9HeavyModeE.isra.0")?;
+        insta::assert_debug_snapshot!(f);
+        assert_eq!(
+            f,
+            demangle("_ZN8MainLoop14gameUpdateStepEP22MultiplayerManagerBaseP8ScenarioP10AppManagerNS_9HeavyModeE")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn insta_operator() -> Result<()> {
+        insta::assert_debug_snapshot!(demangle("_ZNK11MapPositioneqERKS_")?);
+        Ok(())
+    }
+
+    #[test]
+    fn insta_destructor() -> Result<()> {
+        insta::assert_debug_snapshot!(demangle("_ZN15CraftingMachineD2Ev")?);
+        Ok(())
+    }
+
+    #[test]
+    fn insta_abi_tag() -> Result<()> {
+        insta::assert_debug_snapshot!(demangle("_ZNK6Recipe7getNameB5cxx11Ev")?);
+        Ok(())
+    }
+
+    #[test]
+    fn insta_function_pointer() -> Result<()> {
+        insta::assert_debug_snapshot!(demangle("_ZN3Foo3bazEPFviE")?);
+        Ok(())
+    }
+
+    #[test]
+    fn insta_ref_to_pointer() -> Result<()> {
+        insta::assert_debug_snapshot!(demangle("_ZN3Foo3barERKPKi")?);
+        Ok(())
+    }
+
+    /// printing the tree gives back what we parsed
+    #[test]
+    fn round_trip() -> Result<()> {
+        for raw in [
+            "_ZNSt8_Rb_treeIP15CraftingMachineS1_St9_IdentityIS1_E20UnitNumberComparatorSaIS1_EE16_M_insert_uniqueIS1_EESt4pairISt17_Rb_tree_iteratorIS1_EbEOT_",
+            "_ZN17AssemblingMachine5resetER15InventoryBufferN15CraftingMachine10FromScriptENS_5ForceE",
+            "_ZNK6Recipe7getNameB5cxx11Ev",
+            "_ZN3Foo3quxEM3BarFviEMS0_iRA3_i",
+            "_ZN3FoocviEv",
+            "_ZN3FoonwEm",
+            "_ZNO3Foo3getEv",
+            "_ZN3Foo3barEPKcz",
+            "_ZNK3FooIbLb1EE3getEv",
+            "_ZN12_GLOBAL__N_13Foo3barEv",
+            "_ZNKSt8functionIFvRKiEEclES1_",
+            "_ZNSt17_Function_handlerIFvvEZN3Foo3barEvEUlvE_E9_M_invokeERKSt9_Any_data",
+        ] {
+            let expected = Symbol::new(raw)?.demangle(&DemangleOptions::default().no_return_type())?;
+            assert_eq!(expected, demangle(raw)?.to_string());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn not_functions() {
+        for raw in [
+            "main",
+            "",
+            "_Z",
+            "_ZTV15CraftingMachine",
+            "_ZTI15CraftingMachine",
+            "_ZGVZN3Foo3barEvE1x",
+            "_ZN3Foo3barE",
+            "_ZN3Foo",
+            "_ZNK11MapPositioneqERKS",
+        ] {
+            assert!(demangle(raw).is_err(), "{raw:?}");
+        }
+    }
 }
@@ -1,7 +1,12 @@
+pub mod breakpoints;
 pub mod elf;
 pub mod inject;
 pub mod mangle;
+pub mod offsets;
+pub mod profile;
 pub mod ptrace;
+pub mod symbols;
+pub mod tracee;
 
 pub fn pad_to_word(buf: &[u8], with: u8) -> Vec<u64> {
     assert_eq!(std::mem::size_of::<usize>(), 8);
@@ -9,7 +14,7 @@ pub fn pad_to_word(buf: &[u8], with: u8) -> Vec<u64> {
     let mut ret = Vec::with_capacity(buf.len() / 8 + 1);
 
     let mut it = buf.chunks_exact(8);
-    while let Some(chunk) = it.next() {
+    for chunk in it.by_ref() {
         let arr = chunk.try_into().expect("chunks_exact");
         ret.push(u64::from_le_bytes(arr));
     }
@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use elf::abi::PT_LOAD;
+use elf::endian::AnyEndian;
+use elf::ElfStream;
+use iced_x86::{Decoder, DecoderOptions, Instruction, Mnemonic, OpKind, Register};
+
+use super::elf::{struct_layouts, Layouts, Symbol};
+use super::symbols::{cache_key, write_cache, CACHE_VERSION};
+
+/// Where the fields we read live in the game's structs, in bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Offsets {
+    /// `u32`, in a `CraftingMachine`
+    pub unit_number: u64,
+    /// `u32`, in a `CraftingMachine`
+    pub products_finished: u64,
+    /// the `size_t` node count, in the `std::set` of crafting machines
+    pub set_size: u64,
+}
+
+impl Offsets {
+    /// what 1.1 had, back when these were hard-coded
+    pub const KNOWN: Offsets = Offsets {
+        unit_number: 0x26 * 4,
+        products_finished: 0x81 * 4,
+        set_size: 40,
+    };
+}
+
+/// Any of the [`Offsets`], to use instead of whatever was discovered.
+#[derive(serde::Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OffsetOverrides {
+    pub unit_number: Option<u64>,
+    pub products_finished: Option<u64>,
+    pub set_size: Option<u64>,
+}
+
+impl OffsetOverrides {
+    pub fn apply(&self, offsets: &mut Offsets) {
+        if let Some(unit_number) = self.unit_number {
+            offsets.unit_number = unit_number;
+        }
+        if let Some(products_finished) = self.products_finished {
+            offsets.products_finished = products_finished;
+        }
+        if let Some(set_size) = self.set_size {
+            offsets.set_size = set_size;
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Discovered {
+    pub offsets: Offsets,
+    /// fields we couldn't find, which have been left as [`Offsets::KNOWN`]
+    pub guessed: Vec<&'static str>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Cached {
+    version: u32,
+    key: String,
+    offsets: Offsets,
+    guessed: Vec<String>,
+}
+
+/// How to spot the field in the accessor's code, by the size of the memory operand.
+#[derive(Copy, Clone, Debug)]
+enum Pick {
+    FirstRead(usize),
+    LastRead(usize),
+    /// the displacement read most often, e.g. by an inlined comparator
+    MostRead(usize),
+    /// `inc`, or `add ..., 1`, in place
+    Incremented(usize),
+}
+
+struct Probe {
+    field: &'static str,
+    /// (type, path to the member), in the debug info, if there is any
+    members: &'static [(&'static str, &'static [&'static str])],
+    /// (mangled name prefix, how to find the field in it), tried in order
+    sources: &'static [(&'static str, Pick)],
+}
+
+const CRAFTING_INSERT: &str = "_ZNSt8_Rb_treeIP15CraftingMachineS1_St9_IdentityIS1_E20UnitNumberComparatorSaIS1_EE16_M_insert_unique";
+
+const CRAFTING_SET: &str = "std::_Rb_tree<CraftingMachine*, CraftingMachine*, std::_Identity<CraftingMachine*>, UnitNumberComparator, std::allocator<CraftingMachine*> >";
+
+// we've not seen the game's own structs in any debug info, only the standard library's, so their
+// names are from the symbols; members are relative to the `CraftingMachine`, as its bases may not
+// start at zero
+const PROBES: [Probe; 3] = [
+    Probe {
+        field: "unit_number",
+        members: &[("CraftingMachine", &["unitNumber"])],
+        sources: &[
+            ("_ZNK6Entity13getUnitNumber", Pick::FirstRead(4)),
+            (CRAFTING_INSERT, Pick::MostRead(4)),
+        ],
+    },
+    Probe {
+        field: "products_finished",
+        members: &[("CraftingMachine", &["productsFinished"])],
+        sources: &[
+            ("_ZN9LuaEntity23luaReadProductsFinished", Pick::LastRead(4)),
+            ("_ZN15CraftingMachine12giveProducts", Pick::Incremented(4)),
+        ],
+    },
+    Probe {
+        field: "set_size",
+        members: &[(CRAFTING_SET, &["_M_impl", "_M_node_count"])],
+        sources: &[(CRAFTING_INSERT, Pick::Incremented(8))],
+    },
+];
+
+/// Find the [`Offsets`] in the debug info, if there is any, otherwise by disassembling functions
+/// which use them.
+///
+/// Anything we can't find is left at the [`Offsets::KNOWN`] value, and listed in the result.
+pub fn discover(bin_path: impl AsRef<Path>, table: &HashMap<String, Symbol>) -> Result<Discovered> {
+    let layouts = struct_layouts(&bin_path).unwrap_or_else(|e| {
+        println!("ignoring the debug info: {e:?}");
+        None
+    });
+    // only the headers, and the functions we look at, are read; the binary is huge
+    let mut file = fs::File::open(&bin_path)?;
+    let segments = ElfStream::<AnyEndian, _>::open_stream(&mut file)?
+        .segments()
+        .to_vec();
+    if segments.is_empty() {
+        return Err(anyhow!("no program headers"));
+    }
+
+    // the code for `(addr, size)`, at its file offset, via the loaded segments
+    let mut code_of = |(addr, size): Symbol| -> Option<Vec<u8>> {
+        let seg = segments.iter().find(|seg| {
+            seg.p_type == PT_LOAD && (seg.p_vaddr..seg.p_vaddr + seg.p_filesz).contains(&addr)
+        })?;
+        let start = addr - seg.p_vaddr + seg.p_offset;
+        let mut code = vec![0; size];
+        file.seek(SeekFrom::Start(start)).ok()?;
+        file.read_exact(&mut code).ok()?;
+        Some(code)
+    };
+
+    let mut found = match &layouts {
+        Some(layouts) => find_members(layouts),
+        None => [None; 3],
+    };
+    for (probe, found) in PROBES.iter().zip(found.iter_mut()) {
+        if found.is_some() {
+            continue;
+        }
+        for (prefix, pick) in probe.sources {
+            let Some((name, sym)) = find_prefixed(table, prefix) else {
+                continue;
+            };
+            let Some(code) = code_of(sym) else {
+                continue;
+            };
+            if let Some(offset) = find_offset(&code, sym.0, *pick) {
+                println!("found {} at 0x{offset:x}, in {name}", probe.field);
+                *found = Some(offset);
+                break;
+            }
+        }
+    }
+
+    let [unit_number, products_finished, set_size] = found;
+    let guessed = PROBES
+        .iter()
+        .zip(found)
+        .filter(|(_, found)| found.is_none())
+        .map(|(probe, _)| probe.field)
+        .collect();
+    Ok(Discovered {
+        offsets: Offsets {
+            unit_number: unit_number.unwrap_or(Offsets::KNOWN.unit_number),
+            products_finished: products_finished.unwrap_or(Offsets::KNOWN.products_finished),
+            set_size: set_size.unwrap_or(Offsets::KNOWN.set_size),
+        },
+        guessed,
+    })
+}
+
+/// [`discover`], but via a file in `cache_dir`, beside the symbol cache and named for the binary
+/// in the same way (see [`super::symbols::SymbolIndex::open_cached`]), so only the first start
+/// with a new binary reads its debug info.
+pub fn discover_cached(
+    bin_path: impl AsRef<Path>,
+    cache_dir: impl AsRef<Path>,
+    table: &HashMap<String, Symbol>,
+) -> Result<Discovered> {
+    let bin_path = bin_path.as_ref();
+    let key = cache_key(bin_path)?;
+    let path = cache_dir.as_ref().join(format!("offsets-{key}.bin"));
+    match load(&path, &key) {
+        Ok(discovered) => return Ok(discovered),
+        Err(e) if path.exists() => println!("ignoring the offset cache: {e:?}"),
+        Err(_) => (),
+    }
+
+    let discovered = discover(bin_path, table)?;
+    // only slower next time
+    if let Err(e) = save(&path, &key, &discovered) {
+        println!("couldn't write the offset cache: {e:?}");
+    }
+    Ok(discovered)
+}
+
+fn load(path: &Path, key: &str) -> Result<Discovered> {
+    let cached: Cached = bincode::deserialize_from(BufReader::new(fs::File::open(path)?))
+        .with_context(|| anyhow!("reading {path:?}"))?;
+    ensure!(
+        cached.version == CACHE_VERSION && cached.key == key,
+        "{path:?} is for something else"
+    );
+    Ok(Discovered {
+        offsets: cached.offsets,
+        guessed: PROBES
+            .iter()
+            .map(|probe| probe.field)
+            .filter(|field| cached.guessed.iter().any(|g| g == field))
+            .collect(),
+    })
+}
+
+fn save(path: &Path, key: &str, discovered: &Discovered) -> Result<()> {
+    write_cache(
+        path,
+        &Cached {
+            version: CACHE_VERSION,
+            key: key.to_string(),
+            offsets: discovered.offsets,
+            guessed: discovered.guessed.iter().map(|g| g.to_string()).collect(),
+        },
+    )
+}
+
+/// The fields of [`Offsets`] which are in the debug info, in its order.
+pub fn find_members(layouts: &Layouts) -> [Option<u64>; 3] {
+    PROBES.each_ref().map(|probe| {
+        probe.members.iter().find_map(|(typ, path)| {
+            let member = layouts.member(typ, path).ok()?;
+            println!(
+                "found {} at 0x{:x}, in the debug info for {typ}",
+                probe.field, member.offset
+            );
+            Some(member.offset)
+        })
+    })
+}
+
+/// the shortest name, i.e. not some `.isra.0` clone, if there are a few
+fn find_prefixed<'t>(
+    table: &'t HashMap<String, Symbol>,
+    prefix: &str,
+) -> Option<(&'t str, Symbol)> {
+    table
+        .iter()
+        .filter(|(name, (_, size))| name.starts_with(prefix) && *size > 0)
+        .min_by_key(|(name, _)| (name.len(), name.as_str()))
+        .map(|(name, sym)| (name.as_str(), *sym))
+}
+
+/// A field access: `[reg+disp]` through some struct pointer, not the stack or a global.
+struct Access {
+    disp: u64,
+    size: usize,
+    read: bool,
+    incremented: bool,
+}
+
+fn find_offset(code: &[u8], ip: u64, pick: Pick) -> Option<u64> {
+    let accesses = accesses(code, ip);
+    let sized = |size| accesses.iter().filter(move |a| a.size == size);
+    let offset = match pick {
+        Pick::FirstRead(size) => sized(size).find(|a| a.read)?.disp,
+        Pick::LastRead(size) => sized(size).rfind(|a| a.read)?.disp,
+        Pick::MostRead(size) => {
+            let mut counts = HashMap::<u64, usize>::new();
+            for a in sized(size).filter(|a| a.read) {
+                *counts.entry(a.disp).or_default() += 1;
+            }
+            // ties go to the smallest offset, so it's at least stable
+            counts
+                .into_iter()
+                .max_by_key(|&(disp, count)| (count, std::cmp::Reverse(disp)))?
+                .0
+        }
+        Pick::Incremented(size) => sized(size).find(|a| a.incremented)?.disp,
+    };
+    // anything else is probably a misread
+    (offset > 0 && offset < 0x10000).then_some(offset)
+}
+
+fn accesses(code: &[u8], ip: u64) -> Vec<Access> {
+    let mut ret = Vec::new();
+    let mut decoder = Decoder::with_ip(64, code, ip, DecoderOptions::NONE);
+    let mut instr = Instruction::default();
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instr);
+        let Some(op) = (0..instr.op_count()).find(|&op| instr.op_kind(op) == OpKind::Memory) else {
+            continue;
+        };
+        let base = instr.memory_base();
+        if matches!(base, Register::None | Register::RIP | Register::RSP)
+            || instr.memory_index() != Register::None
+        {
+            continue;
+        }
+        let size = instr.memory_size().size();
+        let disp = instr.memory_displacement64();
+        if size == 0 || disp % size as u64 != 0 {
+            continue;
+        }
+
+        let incremented = op == 0
+            && match instr.mnemonic() {
+                Mnemonic::Inc => true,
+                Mnemonic::Add => instr.op1_kind() != OpKind::Register && instr.immediate(1) == 1,
+                _ => false,
+            };
+        let read = incremented
+            || op > 0
+                && matches!(
+                    instr.mnemonic(),
+                    Mnemonic::Mov
+                        | Mnemonic::Movzx
+                        | Mnemonic::Movsx
+                        | Mnemonic::Movsxd
+                        | Mnemonic::Cmp
+                        | Mnemonic::Cvtsi2sd
+                        | Mnemonic::Vcvtsi2sd
+                        | Mnemonic::Cvtsi2ss
+                        | Mnemonic::Vcvtsi2ss
+                )
+            || op == 0 && instr.mnemonic() == Mnemonic::Cmp;
+        ret.push(Access {
+            disp,
+            size,
+            read,
+            incremented,
+        });
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use iced_x86::code_asm::*;
+
+    use super::*;
+
+    const IP: u64 = 0x40_1000;
+
+    fn assemble(f: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>) -> Vec<u8> {
+        let mut a = CodeAssembler::new(64).expect("64 is valid");
+        f(&mut a).expect("valid instructions");
+        a.assemble(IP).expect("assembles")
+    }
+
+    #[test]
+    fn cache_round_trip() -> Result<()> {
+        let discovered = Discovered {
+            offsets: Offsets {
+                unit_number: 0x10,
+                ..Offsets::KNOWN
+            },
+            guessed: vec!["products_finished", "set_size"],
+        };
+        let dir = std::env::temp_dir().join(format!("offsets-test-{}", std::process::id()));
+        let path = dir.join("offsets-abc.bin");
+        save(&path, "abc", &discovered)?;
+        assert_eq!(discovered, load(&path, "abc")?);
+        assert!(load(&path, "def").is_err());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn getter() {
+        let code = assemble(|a| {
+            a.mov(eax, dword_ptr(rdi + 0x98))?;
+            a.ret()
+        });
+        assert_eq!(Some(0x98), find_offset(&code, IP, Pick::FirstRead(4)));
+        assert_eq!(None, find_offset(&code, IP, Pick::FirstRead(8)));
+    }
+
+    #[test]
+    fn lua_getter() {
+        let code = assemble(|a| {
+            let mut global = a.create_label();
+            // some type check, and the entity from the LuaEntity
+            a.mov(rax, qword_ptr(rdi + 0x10))?;
+            a.cmp(byte_ptr(rax + 0x22), 7)?;
+            // the stack and globals are never fields
+            a.mov(dword_ptr(rsp + 0x8), 0)?;
+            a.mov(ecx, dword_ptr(global))?;
+            a.cvtsi2sd(xmm0, dword_ptr(rax + 0x204))?;
+            a.ret()?;
+            a.set_label(&mut global)?;
+            a.dd(&[0])
+        });
+        assert_eq!(Some(0x204), find_offset(&code, IP, Pick::LastRead(4)));
+        assert_eq!(Some(0x10), find_offset(&code, IP, Pick::FirstRead(8)));
+    }
+
+    #[test]
+    fn insert() {
+        let code = assemble(|a| {
+            a.mov(rax, qword_ptr(rdi + 0x10))?;
+            a.mov(ecx, dword_ptr(rsi + 0x98))?;
+            a.cmp(dword_ptr(rax + 0x98), ecx)?;
+            a.mov(edx, dword_ptr(rax + 0x20))?;
+            a.cmp(ecx, dword_ptr(rdx + 0x98))?;
+            a.add(qword_ptr(rdi + 0x28), 1)?;
+            a.add(qword_ptr(rdi + 0x30), 2)?;
+            a.ret()
+        });
+        assert_eq!(Some(0x98), find_offset(&code, IP, Pick::MostRead(4)));
+        assert_eq!(Some(0x28), find_offset(&code, IP, Pick::Incremented(8)));
+    }
+
+    #[test]
+    fn give_products() {
+        let code = assemble(|a| {
+            a.inc(dword_ptr(rbx + 0x204))?;
+            a.ret()
+        });
+        assert_eq!(Some(0x204), find_offset(&code, IP, Pick::Incremented(4)));
+        assert_eq!(None, find_offset(&code, IP, Pick::Incremented(8)));
+    }
+}
@@ -0,0 +1,336 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::offsets::OffsetOverrides;
+use super::symbols::SymbolIndex;
+
+/// What we need from one Factorio release, from `profiles.toml`.
+#[derive(serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub name: String,
+    /// GNU build ids, as hex, of binaries known to be this release
+    #[serde(default)]
+    pub build_ids: Vec<String>,
+    /// game versions, as in `data/base/info.json`, which are this release
+    #[serde(default)]
+    pub versions: Vec<String>,
+    pub symbols: ProfileSymbols,
+    /// pinned, instead of discovered
+    #[serde(default)]
+    pub offsets: OffsetOverrides,
+    /// where the game keeps its tick, if we know
+    pub tick: Option<TickPath>,
+}
+
+/// Where to read the game tick from: the variable `symbol` (mangled, or demangled like `Foo::bar`)
+/// is read as a pointer, and the offset added, for each of `offsets`; the tick is the `u32` at the
+/// end of that.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TickPath {
+    pub symbol: String,
+    #[serde(default)]
+    pub offsets: Vec<u64>,
+}
+
+/// Mangled names for each symbol, tried in order; then tried again allowing for clones of them,
+/// e.g. `.isra.0`, if none are there exactly.
+#[derive(serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileSymbols {
+    /// somewhere we can briefly overwrite, to run the stages
+    pub scratch: Vec<String>,
+    pub crafting_status: Vec<String>,
+    pub crafting_insert: Vec<String>,
+    pub game_update_step: Vec<String>,
+}
+
+/// The addresses of the [`ProfileSymbols`], in a binary.
+#[derive(Copy, Clone, Debug)]
+pub struct Resolved {
+    pub scratch: u64,
+    pub crafting_status: u64,
+    pub crafting_insert: u64,
+    pub game_update_step: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct Profiles {
+    profile: Vec<Profile>,
+}
+
+/// every known profile, newest first
+pub fn profiles() -> Result<Vec<Profile>> {
+    let profiles: Profiles = toml::from_str(include_str!("../../profiles.toml"))
+        .with_context(|| anyhow!("parsing the built-in profiles.toml"))?;
+    Ok(profiles.profile)
+}
+
+impl ProfileSymbols {
+    pub fn fields(&self) -> [(&'static str, &[String]); 4] {
+        [
+            ("scratch", &self.scratch),
+            ("crafting_status", &self.crafting_status),
+            ("crafting_insert", &self.crafting_insert),
+            ("game_update_step", &self.game_update_step),
+        ]
+    }
+}
+
+impl Profile {
+    /// For each symbol, the name we found and its address, or `None` if none of the names are there.
+    pub fn lookup<'i>(
+        &self,
+        index: &'i SymbolIndex,
+    ) -> [(&'static str, Option<(&'i str, u64)>); 4] {
+        self.lookup_with(index, true)
+    }
+
+    fn lookup_with<'i>(
+        &self,
+        index: &'i SymbolIndex,
+        clones: bool,
+    ) -> [(&'static str, Option<(&'i str, u64)>); 4] {
+        let table = index.table();
+        self.symbols.fields().map(|(field, names)| {
+            let exact = names
+                .iter()
+                .find_map(|name| table.get_key_value(name.as_str()))
+                .map(|(name, (addr, _))| (name.as_str(), *addr));
+            // a thunk or a cold part isn't the function we're after
+            let clone = || {
+                if !clones {
+                    return None;
+                }
+                names.iter().find_map(|name| {
+                    index
+                        .lookup_mangled(name)
+                        .into_iter()
+                        .find(|entry| entry.rank() <= 1)
+                        .map(|entry| (entry.raw.as_str(), entry.addr))
+                })
+            };
+            (field, exact.or_else(clone))
+        })
+    }
+
+    pub fn missing(&self, index: &SymbolIndex) -> Vec<&'static str> {
+        self.lookup(index)
+            .into_iter()
+            .filter(|(_, found)| found.is_none())
+            .map(|(field, _)| field)
+            .collect()
+    }
+
+    pub fn resolve(&self, index: &SymbolIndex) -> Result<Resolved> {
+        let [scratch, crafting_status, crafting_insert, game_update_step] =
+            self.lookup(index).map(|(field, found)| {
+                found
+                    .map(|(_, addr)| addr)
+                    .ok_or_else(|| anyhow!("{field} not found, for profile {}", self.name))
+            });
+        Ok(Resolved {
+            scratch: scratch?,
+            crafting_status: crafting_status?,
+            crafting_insert: crafting_insert?,
+            game_update_step: game_update_step?,
+        })
+    }
+}
+
+/// The game version the binary at `bin_path` came with, from the `data` directory of the install
+/// it's in, i.e. `bin/x64/factorio` has `data/base/info.json`; `None` if it's not in one.
+pub fn game_version(bin_path: impl AsRef<Path>) -> Result<Option<String>> {
+    let Some(root) = bin_path.as_ref().ancestors().nth(3) else {
+        return Ok(None);
+    };
+    let path = root.join("data").join("base").join("info.json");
+    let buf = match fs::read(&path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => bail!(e),
+    };
+    let info: serde_json::Value =
+        serde_json::from_slice(&buf).with_context(|| anyhow!("parsing {path:?}"))?;
+    Ok(info["version"].as_str().map(|v| v.to_string()))
+}
+
+/// The profile listing `build_id`, or `version`, otherwise the first one which has all its
+/// symbols in `index`; preferring one with the exact names over one which only has clones of them.
+pub fn select<'p>(
+    profiles: &'p [Profile],
+    build_id: Option<&str>,
+    version: Option<&str>,
+    index: &SymbolIndex,
+) -> Result<&'p Profile> {
+    if let Some(build_id) = build_id {
+        if let Some(profile) = profiles.iter().find(|p| {
+            p.build_ids
+                .iter()
+                .any(|id| id.eq_ignore_ascii_case(build_id))
+        }) {
+            return Ok(profile);
+        }
+    }
+    if let Some(version) = version {
+        if let Some(profile) = profiles
+            .iter()
+            .find(|p| p.versions.iter().any(|v| v == version))
+        {
+            return Ok(profile);
+        }
+    }
+    let complete = |p: &&Profile, clones| {
+        p.lookup_with(index, clones)
+            .iter()
+            .all(|(_, found)| found.is_some())
+    };
+    profiles
+        .iter()
+        .find(|p| complete(p, false))
+        .or_else(|| profiles.iter().find(|p| complete(p, true)))
+        .ok_or_else(|| {
+            anyhow!(
+                "no profile matches this binary (build id {build_id:?}, version {version:?}); `--check` shows what's missing"
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn index_of(names: &[&str]) -> SymbolIndex {
+        SymbolIndex::new(
+            names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| (name.to_string(), (0x1000 * (i as u64 + 1), 16)))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn built_in_profiles_parse() {
+        let profiles = profiles().expect("valid toml");
+        assert!(!profiles.is_empty());
+        for profile in &profiles {
+            for (field, names) in profile.symbols.fields() {
+                assert!(!names.is_empty(), "{}: no names for {field}", profile.name);
+            }
+            if let Some(tick) = &profile.tick {
+                assert!(
+                    !tick.offsets.is_empty(),
+                    "{}: tick isn't a pointer",
+                    profile.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn select_by_symbols_then_build_id() {
+        let profiles = profiles().expect("valid toml");
+        let old = profiles.iter().find(|p| p.name == "1.1.53").expect("known");
+        let mut names = old
+            .symbols
+            .fields()
+            .map(|(_, names)| names[0].to_string())
+            .to_vec();
+        let index = index_of(&names.iter().map(|n| n.as_str()).collect::<Vec<_>>());
+
+        let selected = select(&profiles, Some("abcdef"), None, &index).expect("matches");
+        assert_eq!("1.1.53", selected.name);
+        let resolved = selected.resolve(&index).expect("all present");
+        assert_eq!(0x1000, resolved.scratch);
+        assert_eq!(0x4000, resolved.game_update_step);
+
+        // the newest only lists a clone of gameUpdateStep, but will take the real one
+        let newest = profiles.first().expect("non-empty");
+        assert!(newest.missing(&index).is_empty());
+
+        // so a new clone falls back to whichever will have it
+        names[3].push_str(".constprop.1");
+        let cloned = index_of(&names.iter().map(|n| n.as_str()).collect::<Vec<_>>());
+        let selected = select(&profiles, None, None, &cloned).expect("a clone");
+        assert_eq!(newest.name, selected.name);
+        assert_eq!(
+            0x4000,
+            selected.resolve(&cloned).expect("clone").game_update_step
+        );
+
+        let mut profiles = profiles;
+        profiles[1].build_ids.push("ABCDEF".to_string());
+        let selected = select(&profiles, Some("abcdef"), None, &cloned).expect("by id");
+        assert_eq!(profiles[1].name, selected.name);
+
+        let empty = index_of(&[]);
+        assert!(select(&profiles, None, None, &empty).is_err());
+        assert_eq!(4, profiles[0].missing(&empty).len());
+    }
+
+    #[test]
+    fn tick_is_data() {
+        let symbol = &"_ZN4Game4tickE".to_string();
+        let table = [
+            (symbol.clone(), (0x5000, 8)),
+            ("main".to_string(), (0x1000, 16)),
+        ]
+        .into_iter()
+        .collect();
+
+        let index = SymbolIndex::with_data(table, &[symbol.clone()].into_iter().collect());
+        assert_eq!(0x5000, index.find_data(symbol).expect("a variable").addr);
+        // a function of the same name isn't it
+        let table = [(symbol.clone(), (0x5000, 8))].into_iter().collect();
+        assert!(SymbolIndex::new(table).find_data(symbol).is_err());
+    }
+
+    #[test]
+    fn select_by_version() -> Result<()> {
+        let profiles = profiles()?;
+        // the old one has all of these, as clones, but the newest has them exactly
+        let names = profiles[0]
+            .symbols
+            .fields()
+            .map(|(_, names)| names[0].to_string())
+            .to_vec();
+        let index = index_of(&names.iter().map(|n| n.as_str()).collect::<Vec<_>>());
+        assert_eq!(
+            profiles[0].name,
+            select(&profiles, None, None, &index)?.name
+        );
+
+        let root = std::env::temp_dir().join(format!("profile-test-{}", std::process::id()));
+        let bin = root
+            .join("factorio")
+            .join("bin")
+            .join("x64")
+            .join("factorio");
+        assert_eq!(None, game_version(&bin)?);
+        let base = root.join("factorio").join("data").join("base");
+        fs::create_dir_all(&base)?;
+        fs::write(
+            base.join("info.json"),
+            r#"{"name": "base", "version": "1.1.53", "title": "Base Mod"}"#,
+        )?;
+        let version = game_version(&bin)?;
+        fs::remove_dir_all(&root)?;
+        assert_eq!(Some("1.1.53"), version.as_deref());
+
+        assert_eq!(
+            "1.1.53",
+            select(&profiles, Some("abcdef"), version.as_deref(), &index)?.name
+        );
+        // an unknown version falls back to the symbols
+        assert_eq!(
+            profiles[0].name,
+            select(&profiles, None, Some("0.17.79"), &index)?.name
+        );
+        Ok(())
+    }
+}
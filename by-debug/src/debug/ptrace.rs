@@ -1,14 +1,20 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::ffi::c_void;
-use std::io::IoSliceMut;
+use std::fs;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::os::unix::fs::FileExt;
 
 use anyhow::{anyhow, bail, Result};
+use nix::errno::Errno;
 use nix::libc::c_long;
 use nix::sys::ptrace;
 use nix::sys::signal::Signal;
-use nix::sys::uio::{process_vm_readv, RemoteIoVec};
+use nix::sys::uio::{process_vm_readv, process_vm_writev, RemoteIoVec};
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 
+use super::elf::find_threads;
+
 const DR0: *mut c_void = 848 as *mut c_void;
 const DR1: *mut c_void = 856 as *mut c_void;
 const DR2: *mut c_void = 864 as *mut c_void;
@@ -16,55 +22,262 @@ const DR3: *mut c_void = 872 as *mut c_void;
 const DR6: *mut c_void = 896 as *mut c_void;
 const DR7: *mut c_void = 904 as *mut c_void;
 
-#[inline]
-pub fn read_words_arr<const N: usize>(pid: Pid, addr: u64) -> Result<[u64; N]> {
-    let mut ret = [0u64; N];
-    assert!(
-        addr.checked_add(u64::try_from(N * 8).expect("usize <= 2^64"))
-            .is_some(),
-        "{addr} + {N} words overflows u64"
-    );
-    for i in 0..N {
-        // assert! above validates cast
-        let start = addr + (i * 8) as u64;
-        let word = ptrace::read(pid, start as *mut _)?;
-        ret[i] = word as u64;
+/// How [`Memory`] gets at the tracee's memory, best first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Method {
+    /// `process_vm_readv` and `process_vm_writev`: a syscall for up to a thousand regions, but it
+    /// can't write to read-only pages, like code
+    VmRw,
+    /// `/proc/<pid>/mem`: a syscall for each region
+    ProcMem,
+    /// `PTRACE_PEEKDATA` and `PTRACE_POKEDATA`: a syscall for each word
+    Peek,
+}
+
+impl Method {
+    fn next(self) -> Option<Method> {
+        match self {
+            Method::VmRw => Some(Method::ProcMem),
+            Method::ProcMem => Some(Method::Peek),
+            Method::Peek => None,
+        }
     }
-    Ok(ret)
 }
 
-#[inline]
-pub fn read_words_var(pid: Pid, addr: u64, words: usize) -> Result<Vec<u64>> {
-    let mut ret = vec![0u64; words];
-    assert!(
-        addr.checked_add(u64::try_from(words * 8).expect("usize <= 2^64"))
-            .is_some(),
-        "{addr} + {words} words overflows u64"
-    );
-    for i in 0..words {
-        // assert! above validates cast
-        let start = addr + (i * 8) as u64;
-        println!("reading {start:x}");
-        let word = ptrace::read(pid, start as *mut _)?;
-        ret[i] = word as u64;
+/// the most regions the kernel takes in one `process_vm_readv`
+const IOV_MAX: usize = 1024;
+
+/// A tracee's memory, read and written a region at a time, or many at once.
+///
+/// Each transfer tries the [`Method`]s in order, falling back if one fails, e.g. for writing to code;
+/// a method we're denied (by a seccomp filter, or `ptrace_scope`) isn't tried again.
+pub struct Memory {
+    pid: Pid,
+    best: Method,
+    proc_mem: Option<fs::File>,
+}
+
+impl Memory {
+    pub fn new(pid: Pid) -> Memory {
+        Memory::with_method(pid, Method::VmRw)
+    }
+
+    /// never trying anything better than `best`
+    pub fn with_method(pid: Pid, best: Method) -> Memory {
+        Memory {
+            pid,
+            best,
+            proc_mem: None,
+        }
+    }
+
+    /// the best method which we've not been denied
+    pub fn method(&self) -> Method {
+        self.best
+    }
+
+    pub fn read(&mut self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        self.read_many(&mut [(addr, buf)])
+    }
+
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        self.write_many(&[(addr, data)])
+    }
+
+    pub fn read_words(&mut self, addr: u64, words: usize) -> Result<Vec<u64>> {
+        let mut ret = vec![0u64; words];
+        self.read(addr, bytemuck::cast_slice_mut(&mut ret))?;
+        Ok(ret)
+    }
+
+    pub fn write_words(&mut self, addr: u64, data: &[u64]) -> Result<()> {
+        self.write(addr, bytemuck::cast_slice(data))
+    }
+
+    /// scatter: fill each buffer from its address
+    pub fn read_many(&mut self, reads: &mut [(u64, &mut [u8])]) -> Result<()> {
+        self.with_fallback("reading", |memory, method| match method {
+            Method::VmRw => vm_read(memory.pid, reads),
+            Method::ProcMem => {
+                let f = memory.proc_mem()?;
+                for (addr, buf) in reads.iter_mut() {
+                    f.read_exact_at(buf, *addr).map_err(to_errno)?;
+                }
+                Ok(())
+            }
+            Method::Peek => {
+                for (addr, buf) in reads.iter_mut() {
+                    peek(memory.pid, *addr, buf)?;
+                }
+                Ok(())
+            }
+        })
+    }
+
+    /// gather: write each slice to its address
+    pub fn write_many(&mut self, writes: &[(u64, &[u8])]) -> Result<()> {
+        self.with_fallback("writing", |memory, method| match method {
+            Method::VmRw => vm_write(memory.pid, writes),
+            Method::ProcMem => {
+                let f = memory.proc_mem()?;
+                for (addr, data) in writes {
+                    f.write_all_at(data, *addr).map_err(to_errno)?;
+                }
+                Ok(())
+            }
+            Method::Peek => {
+                for (addr, data) in writes {
+                    poke(memory.pid, *addr, data)?;
+                }
+                Ok(())
+            }
+        })
+    }
+
+    fn proc_mem(&mut self) -> nix::Result<&fs::File> {
+        if self.proc_mem.is_none() {
+            let f = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(format!("/proc/{}/mem", self.pid))
+                .map_err(to_errno)?;
+            self.proc_mem = Some(f);
+        }
+        Ok(self.proc_mem.as_ref().expect("just set"))
+    }
+
+    fn with_fallback(
+        &mut self,
+        what: &str,
+        mut transfer: impl FnMut(&mut Memory, Method) -> nix::Result<()>,
+    ) -> Result<()> {
+        let mut failures = Vec::new();
+        let mut method = Some(self.best);
+        while let Some(current) = method {
+            match transfer(self, current) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    let denied = matches!(e, Errno::EPERM | Errno::EACCES | Errno::ENOSYS);
+                    if denied && current == self.best {
+                        self.best = current.next().unwrap_or(current);
+                    }
+                    failures.push(format!("{current:?}: {e}"));
+                }
+            }
+            method = current.next();
+        }
+        Err(anyhow!("{what} {}: {}", self.pid, failures.join(", ")))
     }
-    Ok(ret)
 }
 
-pub fn write_words_ptr(pid: Pid, addr: u64, data: &[u64]) -> Result<()> {
-    let mut addr = addr;
-    let ptr_size = std::mem::size_of::<*mut c_void>();
-    assert_eq!(ptr_size, 8, "64-bit machines only");
+fn to_errno(e: io::Error) -> Errno {
+    match e.raw_os_error() {
+        Some(raw) => Errno::from_raw(raw),
+        // a short read or write, off the end of a mapping
+        None => Errno::EFAULT,
+    }
+}
+
+/// a short transfer means part of it wasn't mapped
+fn vm_read(pid: Pid, reads: &mut [(u64, &mut [u8])]) -> nix::Result<()> {
+    for chunk in reads.chunks_mut(IOV_MAX) {
+        let remote = chunk
+            .iter()
+            .map(|(addr, buf)| RemoteIoVec {
+                base: *addr as usize,
+                len: buf.len(),
+            })
+            .collect::<Vec<_>>();
+        let wanted = remote.iter().map(|r| r.len).sum::<usize>();
+        let mut local = chunk
+            .iter_mut()
+            .map(|(_, buf)| IoSliceMut::new(buf))
+            .collect::<Vec<_>>();
+        if process_vm_readv(pid, &mut local, &remote)? != wanted {
+            return Err(Errno::EFAULT);
+        }
+    }
+    Ok(())
+}
+
+fn vm_write(pid: Pid, writes: &[(u64, &[u8])]) -> nix::Result<()> {
+    for chunk in writes.chunks(IOV_MAX) {
+        let remote = chunk
+            .iter()
+            .map(|(addr, data)| RemoteIoVec {
+                base: *addr as usize,
+                len: data.len(),
+            })
+            .collect::<Vec<_>>();
+        let wanted = remote.iter().map(|r| r.len).sum::<usize>();
+        let local = chunk
+            .iter()
+            .map(|(_, data)| IoSlice::new(data))
+            .collect::<Vec<_>>();
+        if process_vm_writev(pid, &local, &remote)? != wanted {
+            return Err(Errno::EFAULT);
+        }
+    }
+    Ok(())
+}
+
+/// the words covering `len` bytes from `addr`: (aligned start, count)
+fn covering(addr: u64, len: usize) -> nix::Result<(u64, u64)> {
+    let start = addr & !7;
+    let end = addr
+        .checked_add(len as u64)
+        .and_then(|end| end.checked_add(7))
+        .ok_or(Errno::EFAULT)?
+        & !7;
+    Ok((start, (end - start) / 8))
+}
+
+fn peek(pid: Pid, addr: u64, buf: &mut [u8]) -> nix::Result<()> {
+    let (start, words) = covering(addr, buf.len())?;
+    let mut bytes = Vec::with_capacity(words as usize * 8);
+    for i in 0..words {
+        let word = ptrace::read(pid, (start + i * 8) as *mut _)?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let skip = (addr - start) as usize;
+    buf.copy_from_slice(&bytes[skip..skip + buf.len()]);
+    Ok(())
+}
 
-    for word in data {
+/// whole words are written, so the ends are read first
+fn poke(pid: Pid, addr: u64, data: &[u8]) -> nix::Result<()> {
+    let (start, words) = covering(addr, data.len())?;
+    let mut bytes = vec![0u8; words as usize * 8];
+    let skip = (addr - start) as usize;
+    if skip != 0 || !data.len().is_multiple_of(8) {
+        peek(pid, start, &mut bytes)?;
+    }
+    bytes[skip..skip + data.len()].copy_from_slice(data);
+    for (i, word) in bytes.chunks_exact(8).enumerate() {
+        let word = u64::from_ne_bytes(word.try_into().expect("chunks of 8"));
         unsafe {
-            ptrace::write(pid, addr as *mut _, *word as *mut _)?;
+            ptrace::write(pid, (start + i as u64 * 8) as *mut _, word as *mut _)?;
         }
-        addr += 8;
     }
     Ok(())
 }
 
+#[inline]
+pub fn read_words_arr<const N: usize>(pid: Pid, addr: u64) -> Result<[u64; N]> {
+    let mut ret = [0u64; N];
+    Memory::new(pid).read(addr, bytemuck::cast_slice_mut(&mut ret))?;
+    Ok(ret)
+}
+
+#[inline]
+pub fn read_words_var(pid: Pid, addr: u64, words: usize) -> Result<Vec<u64>> {
+    Memory::new(pid).read_words(addr, words)
+}
+
+pub fn write_words_ptr(pid: Pid, addr: u64, data: &[u64]) -> Result<()> {
+    Memory::new(pid).write_words(addr, data)
+}
+
 pub fn dump(mem: &[u8], addr: u64) -> Result<()> {
     for (off, block) in mem.chunks(8).enumerate() {
         let off = 8 * u64::try_from(off)?;
@@ -77,36 +290,111 @@ pub fn dump(mem: &[u8], addr: u64) -> Result<()> {
     Ok(())
 }
 
-pub fn breakpoint(pid: Pid, addrs: [Option<u64>; 4]) -> Result<()> {
-    let mut dr7 = ptrace::read_user(pid, DR7)?;
+/// What a debug register stops the thread on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Condition {
+    /// running the instruction at the address; stops before it runs
+    Execute,
+    /// writing any of the 1, 2, 4 or 8 (aligned) bytes there; stops after the instruction
+    Write(u8),
+    /// reading or writing any of them
+    ReadWrite(u8),
+}
 
-    for (i, addr) in addrs.iter().enumerate() {
-        let addr = match addr {
-            Some(addr) => addr,
-            None => continue,
+impl Condition {
+    /// the R/W and LEN fields of DR7, for a slot watching `addr`
+    fn dr7_fields(self, addr: u64) -> Result<c_long> {
+        let (rw, len) = match self {
+            Condition::Execute => return Ok(0),
+            Condition::Write(len) => (0b01, len),
+            Condition::ReadWrite(len) => (0b11, len),
         };
-        let dr = match i {
-            0 => DR0,
-            1 => DR1,
-            2 => DR2,
-            3 => DR3,
-            _ => unreachable!(),
+        let len_field = match len {
+            1 => 0b00,
+            2 => 0b01,
+            8 => 0b10,
+            4 => 0b11,
+            _ => bail!("can only watch 1, 2, 4 or 8 bytes, not {len}"),
         };
-        unsafe {
-            ptrace::write_user(pid, dr, *addr as *mut c_void)?;
+        if !addr.is_multiple_of(u64::from(len)) {
+            bail!("can't watch {len} bytes at unaligned 0x{addr:x}");
         }
-        set_bit(&mut dr7, i as u8 * 2, true);
+        Ok(rw | len_field << 2)
     }
+}
+
+fn debug_addr_register(slot: usize) -> Result<*mut c_void> {
+    Ok(match slot {
+        0 => DR0,
+        1 => DR1,
+        2 => DR2,
+        3 => DR3,
+        _ => bail!("no debug register {slot}"),
+    })
+}
+
+/// `dr7` with `slot` locally enabled, with these R/W and LEN fields
+fn dr7_enabling(dr7: c_long, slot: usize, fields: c_long) -> c_long {
+    dr7_disabling(dr7, slot) | 1 << (slot * 2) | fields << (16 + slot * 4)
+}
+
+/// `dr7` with both enable bits, and the fields, of `slot` cleared
+fn dr7_disabling(dr7: c_long, slot: usize) -> c_long {
+    dr7 & !(0b11 << (slot * 2)) & !(0xf << (16 + slot * 4))
+}
 
+/// Point one of DR0 to DR3 at `addr`, replacing whatever it was doing.
+pub fn set_debug_slot(pid: Pid, slot: usize, addr: u64, condition: Condition) -> Result<()> {
+    let dr = debug_addr_register(slot)?;
+    let fields = condition.dr7_fields(addr)?;
+    // disabled while it moves, so the kernel doesn't check the new address against the old length
+    clear_debug_slot(pid, slot)?;
     unsafe {
-        ptrace::write_user(pid, DR7, dr7 as *mut c_void)?;
+        ptrace::write_user(pid, dr, addr as *mut c_void)?;
     }
+    write_dr7(pid, dr7_enabling(read_dr7(pid)?, slot, fields))
+}
+
+pub fn clear_debug_slot(pid: Pid, slot: usize) -> Result<()> {
+    debug_addr_register(slot)?;
+    write_dr7(pid, dr7_disabling(read_dr7(pid)?, slot))
+}
 
+/// break on execution at each address; `None` clears that debug register
+pub fn breakpoint(pid: Pid, addrs: [Option<u64>; 4]) -> Result<()> {
+    for (slot, addr) in addrs.into_iter().enumerate() {
+        match addr {
+            Some(addr) => set_debug_slot(pid, slot, addr, Condition::Execute)?,
+            None => clear_debug_slot(pid, slot)?,
+        }
+    }
     Ok(())
 }
 
+/// The kernel only resets DR6 on a debug exception, so it still shows the last hardware hit after
+/// an `int3`, or a signal.
+pub fn clear_dr6(pid: Pid) -> Result<()> {
+    unsafe {
+        ptrace::write_user(pid, DR6, std::ptr::null_mut())?;
+    }
+    Ok(())
+}
+
+pub fn read_dr7(pid: Pid) -> Result<c_long> {
+    Ok(ptrace::read_user(pid, DR7)?)
+}
+
+pub fn write_dr7(pid: Pid, dr7: c_long) -> Result<()> {
+    unsafe {
+        ptrace::write_user(pid, DR7, dr7 as *mut c_void)?;
+    }
+    Ok(())
+}
+
+/// which debug registers stopped the thread, clearing them so the next stop doesn't show them too
 pub fn which_breakpoints(pid: Pid) -> Result<[bool; 4]> {
     let dr6 = ptrace::read_user(pid, DR6)?;
+    clear_dr6(pid)?;
     Ok([
         get_bit(dr6, 0),
         get_bit(dr6, 1),
@@ -117,50 +405,462 @@ pub fn which_breakpoints(pid: Pid) -> Result<[bool; 4]> {
 
 pub fn bulk_read(pid: Pid, base: usize, len: usize) -> Result<Vec<u8>> {
     let mut buf = vec![0; len];
-    let chunks_read = process_vm_readv(
-        pid,
-        &mut [IoSliceMut::new(&mut buf)],
-        &[RemoteIoVec { base, len }],
-    )?;
+    Memory::new(pid).read(base as u64, &mut buf)?;
+    Ok(buf)
+}
 
-    assert_eq!(
-        chunks_read, 1,
-        "readv only asked for one chunk, and it succeeded, so must have read one chunk"
-    );
+/// continue, and wait for the next stop which is for us; see [`wait_for_stop`]
+pub fn run_until_stop(pid: Pid) -> Result<Stop> {
+    resume(pid, None)?;
+    wait_for_stop(pid)
+}
 
-    Ok(buf)
+/// `PTRACE_CONT`, except for a thread which has been killed while stopped; waiting says so
+fn resume(pid: Pid, signal: Option<Signal>) -> Result<()> {
+    match ptrace::cont(pid, signal) {
+        Ok(()) | Err(Errno::ESRCH) => Ok(()),
+        Err(e) => Err(anyhow!("continuing {pid}: {e}")),
+    }
+}
+
+/// Why a seized thread stopped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// by `PTRACE_INTERRUPT`, e.g. [`interrupt`] or [`ThreadGroup::interrupt`]
+    Interrupted,
+    /// the whole process has been stopped, e.g. by a user's `SIGSTOP`, until it gets a `SIGCONT`
+    GroupStop(Signal),
+    /// an `int3`; the thread is just after it
+    Breakpoint,
+    /// one of the debug registers; see [`which_breakpoints`]
+    Hardware,
+    /// a single step finished
+    Step,
+    /// it made this thread, which is already traced, and running
+    Cloned(Pid),
+    /// about to get this signal, which is passed on when it's continued; including a `SIGTRAP`
+    /// that someone else sent
+    Signal(Signal),
+    /// gone
+    Exited(i32),
+    Killed(Signal),
+}
+
+/// The thread we were waiting on exited, or was killed, instead of stopping.
+#[derive(Copy, Clone, Debug)]
+pub struct Gone {
+    pub pid: Pid,
+    /// [`Stop::Exited`] or [`Stop::Killed`]
+    pub stop: Stop,
+}
+
+impl std::fmt::Display for Gone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.stop {
+            Stop::Exited(code) => write!(f, "{} exited, with status {code}", self.pid),
+            Stop::Killed(signal) => write!(f, "{} was killed by {signal:?}", self.pid),
+            stop => write!(f, "{} is gone: {stop:?}", self.pid),
+        }
+    }
+}
+
+impl std::error::Error for Gone {}
+
+/// What a `waitpid` status means, for a seized thread; `None` if it isn't a stop or an exit.
+fn classify(status: WaitStatus) -> Result<Option<(Pid, Stop)>> {
+    let stop_event = ptrace::Event::PTRACE_EVENT_STOP as i32;
+    let clone_event = ptrace::Event::PTRACE_EVENT_CLONE as i32;
+    Ok(Some(match status {
+        WaitStatus::Exited(pid, code) => (pid, Stop::Exited(code)),
+        WaitStatus::Signaled(pid, signal, _) => (pid, Stop::Killed(signal)),
+        WaitStatus::PtraceEvent(pid, _, event) if event == clone_event => {
+            let child = Pid::from_raw(i32::try_from(ptrace::getevent(pid)?)?);
+            (pid, Stop::Cloned(child))
+        }
+        // the signal is what caused a group-stop, and `SIGTRAP` for anything else
+        WaitStatus::PtraceEvent(pid, signal, event) if event == stop_event => match signal {
+            Signal::SIGSTOP | Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU => {
+                (pid, Stop::GroupStop(signal))
+            }
+            _ => (pid, Stop::Interrupted),
+        },
+        WaitStatus::Stopped(pid, Signal::SIGTRAP) => {
+            let stop = match ptrace::getsiginfo(pid)?.si_code {
+                nix::libc::SI_KERNEL | nix::libc::TRAP_BRKPT => Stop::Breakpoint,
+                nix::libc::TRAP_HWBKPT => Stop::Hardware,
+                nix::libc::TRAP_TRACE => Stop::Step,
+                // e.g. `kill`ed, by someone else
+                _ => Stop::Signal(Signal::SIGTRAP),
+            };
+            (pid, stop)
+        }
+        WaitStatus::Stopped(pid, signal) => (pid, Stop::Signal(signal)),
+        _ => return Ok(None),
+    }))
+}
+
+/// the next stop of `pid`, whatever it is
+pub fn wait_stop(pid: Pid) -> Result<Stop> {
+    loop {
+        let status = waitpid(pid, Some(WaitPidFlag::__WALL))?;
+        if let Some((_, stop)) = classify(status)? {
+            return Ok(stop);
+        }
+    }
 }
 
-pub fn run_until_stop(pid: Pid) -> Result<()> {
-    ptrace::cont(pid, None)?;
-    wait_for_stop(pid)?;
+/// Let a thread in a group-stop stay stopped, unlike continuing it; it stops again, as
+/// [`Stop::Interrupted`], when the group-stop ends.
+pub fn listen(pid: Pid) -> Result<()> {
+    let ret = unsafe { nix::libc::ptrace(nix::libc::PTRACE_LISTEN, pid.as_raw(), 0, 0) };
+    if ret == -1 {
+        bail!("listening to {pid}: {}", Errno::last());
+    }
     Ok(())
 }
 
-/// issue a cont(), then wait for a stop; if we didn't get a stop, issue another cont() and try again repeatedly
+/// Wait for a seized thread to stop for us: a breakpoint, a single step, or an interrupt.
 ///
-/// Stops cleanly on SIGSTOP (ptrace) and SIGTRAP (int3).
-pub fn wait_for_stop(pid: Pid) -> Result<()> {
+/// Signals are passed on, including `SIGTRAP`s from anyone else, and a group-stop is waited out,
+/// rather than running through it. The error is a [`Gone`] if the thread exits.
+pub fn wait_for_stop(pid: Pid) -> Result<Stop> {
+    let mut listening = false;
     loop {
-        let status = waitpid(pid, Some(WaitPidFlag::WSTOPPED))?;
-        println!("waitpid: {:?}", status);
-
-        let signal = match status {
-            WaitStatus::Stopped(stopped_pid, signal) => {
-                assert_eq!(stopped_pid, pid, "waitpid should only return our pid");
-                if signal == Signal::SIGSTOP || signal == Signal::SIGTRAP {
-                    return Ok(());
-                }
-                signal
+        let stop = wait_stop(pid)?;
+        match stop {
+            // the end of the group-stop
+            Stop::Interrupted if listening => {
+                listening = false;
+                resume(pid, None)?;
             }
-            _ => continue,
+            Stop::Interrupted | Stop::Breakpoint | Stop::Hardware | Stop::Step => return Ok(stop),
+            Stop::GroupStop(_) => {
+                listening = true;
+                listen(pid)?;
+            }
+            Stop::Signal(signal) => resume(pid, Some(signal))?,
+            Stop::Cloned(_) => resume(pid, None)?,
+            Stop::Exited(_) | Stop::Killed(_) => return Err(Gone { pid, stop }.into()),
+        }
+    }
+}
+
+/// Stop a seized thread, which may get to a breakpoint first, or already be in a group-stop.
+pub fn interrupt(pid: Pid) -> Result<Stop> {
+    ptrace::interrupt(pid)?;
+    loop {
+        match wait_stop(pid)? {
+            Stop::Signal(signal) => resume(pid, Some(signal))?,
+            Stop::Cloned(_) => resume(pid, None)?,
+            stop @ (Stop::Exited(_) | Stop::Killed(_)) => return Err(Gone { pid, stop }.into()),
+            stop => return Ok(stop),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Thread {
+    stopped: bool,
+    /// cloned, and we haven't seen the stop it starts with yet
+    starting: bool,
+    /// asked to stop, so the stop a new thread starts with is kept, as it's the one asked for
+    interrupted: bool,
+    /// new, and its parent's event announcing it hasn't come yet
+    unannounced: bool,
+    /// to pass on when it's continued
+    signal: Option<Signal>,
+    /// in a group-stop, so continuing it only listens for the end of it
+    group_stopped: bool,
+}
+
+/// Every thread of a process, seized: each is stopped and continued by itself, and threads it
+/// makes are traced as they appear.
+///
+/// This waits for any of the calling thread's tracees, so there should be one per tracing thread.
+/// Dropping it stops and detaches every thread, passing on any signals they were stopped for.
+pub struct ThreadGroup {
+    pid: Pid,
+    threads: BTreeMap<Pid, Thread>,
+    /// stops we've waited for, but not handed out yet, e.g. while waiting for a different thread
+    pending: VecDeque<(Pid, Stop)>,
+    /// new threads which exited before their parent's event announcing them
+    exited: BTreeSet<Pid>,
+}
+
+impl ThreadGroup {
+    /// Seize every thread of `pid`; unlike attaching, this leaves them running.
+    pub fn seize(pid: Pid) -> Result<ThreadGroup> {
+        let mut group = ThreadGroup {
+            pid,
+            threads: BTreeMap::new(),
+            pending: VecDeque::new(),
+            exited: BTreeSet::new(),
         };
+        // threads we've seized announce their children, but the rest can still be making more
+        loop {
+            let mut seized = false;
+            for tid in find_threads(pid.as_raw())? {
+                let tid = Pid::from_raw(tid);
+                if group.threads.contains_key(&tid) {
+                    continue;
+                }
+                match ptrace::seize(tid, ptrace::Options::PTRACE_O_TRACECLONE) {
+                    Ok(()) => {}
+                    // exited since we listed it
+                    Err(Errno::ESRCH) => continue,
+                    Err(e) => return Err(anyhow!("seizing thread {tid} of {pid}: {e}")),
+                }
+                group.threads.insert(tid, Thread::default());
+                seized = true;
+            }
+            if !seized {
+                return Ok(group);
+            }
+        }
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn threads(&self) -> impl Iterator<Item = Pid> + '_ {
+        self.threads.keys().copied()
+    }
+
+    pub fn is_stopped(&self, tid: Pid) -> bool {
+        self.threads.get(&tid).is_some_and(|t| t.stopped)
+    }
+
+    /// Ask a running thread to stop, without a signal; see [`ThreadGroup::wait_for`].
+    pub fn interrupt(&mut self, tid: Pid) -> Result<()> {
+        self.thread(tid)?.interrupted = true;
+        ptrace::interrupt(tid).map_err(|e| anyhow!("interrupting {tid}: {e}"))
+    }
+
+    /// Stop every thread; any which stop for something else first have that queued instead, for
+    /// [`ThreadGroup::wait`], and will stop again for the interrupt once they're continued.
+    pub fn interrupt_all(&mut self) -> Result<()> {
+        let running = self.running();
+        for &tid in &running {
+            self.thread(tid)?.interrupted = true;
+            match ptrace::interrupt(tid) {
+                // it's exiting, which the wait below picks up
+                Ok(()) | Err(Errno::ESRCH) => {}
+                Err(e) => return Err(anyhow!("interrupting {tid}: {e}")),
+            }
+        }
+        for tid in running {
+            // it may have gone, taking its stop with it
+            if self.threads.contains_key(&tid) {
+                let stop = self.wait_for(tid)?;
+                if stop != Stop::Interrupted {
+                    self.pending.push_back((tid, stop));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Continue a stopped thread, passing on the signal it stopped for, if any; a thread in a
+    /// group-stop stays in it, see [`listen`].
+    pub fn cont(&mut self, tid: Pid) -> Result<()> {
+        let signal = self.resuming(tid)?;
+        if std::mem::take(&mut self.thread(tid)?.group_stopped) {
+            return listen(tid);
+        }
+        ptrace::cont(tid, signal).map_err(|e| anyhow!("continuing {tid}: {e}"))
+    }
+
+    /// Run one instruction of a stopped thread, even one in a group-stop; it then stops with a
+    /// [`Stop::Step`]. Any signal it stopped for is kept for when it's continued, as delivering it
+    /// would step into the handler.
+    pub fn step(&mut self, tid: Pid) -> Result<()> {
+        let thread = self.thread(tid)?;
+        if !thread.stopped {
+            bail!("{tid} is already running");
+        }
+        thread.stopped = false;
+        thread.group_stopped = false;
+        ptrace::step(tid, None).map_err(|e| anyhow!("stepping {tid}: {e}"))
+    }
+
+    pub fn cont_all(&mut self) -> Result<()> {
+        let stopped = self
+            .threads
+            .iter()
+            .filter(|(_, t)| t.stopped)
+            .map(|(&tid, _)| tid)
+            .collect::<Vec<_>>();
+        for tid in stopped {
+            self.cont(tid)?;
+        }
+        Ok(())
+    }
+
+    /// Continue every stopped thread but `tid`, except those with a stop still to be handed out by
+    /// [`ThreadGroup::wait`], which are continued once it's been dealt with.
+    pub fn cont_others(&mut self, tid: Pid) -> Result<()> {
+        let stopped = self
+            .threads
+            .iter()
+            .filter(|&(&t, thread)| t != tid && thread.stopped)
+            .filter(|&(&t, _)| !self.pending.iter().any(|&(p, _)| p == t))
+            .map(|(&t, _)| t)
+            .collect::<Vec<_>>();
+        for t in stopped {
+            self.cont(t)?;
+        }
+        Ok(())
+    }
+
+    /// the next stop of any thread
+    pub fn wait(&mut self) -> Result<(Pid, Stop)> {
+        match self.pending.pop_front() {
+            Some(stop) => Ok(stop),
+            None => self.next_stop(),
+        }
+    }
+
+    /// the next stop of this thread, keeping any others' for [`ThreadGroup::wait`]
+    pub fn wait_for(&mut self, tid: Pid) -> Result<Stop> {
+        if let Some(idx) = self.pending.iter().position(|(t, _)| *t == tid) {
+            return Ok(self.pending.remove(idx).expect("just found").1);
+        }
+        self.thread(tid)?;
+        loop {
+            let (stopped, stop) = self.next_stop()?;
+            if stopped == tid {
+                return Ok(stop);
+            }
+            self.pending.push_back((stopped, stop));
+        }
+    }
+
+    /// stops we've waited for, which [`ThreadGroup::wait`] hasn't handed out yet
+    pub fn pending(&self) -> impl Iterator<Item = (Pid, Stop)> + '_ {
+        self.pending.iter().copied()
+    }
+
+    /// stop and detach every thread
+    pub fn detach(mut self) -> Result<()> {
+        self.release()
+    }
+
+    /// The process has gone, so there's nothing to stop or detach from.
+    pub fn forget(mut self) {
+        self.threads.clear();
+        self.pending.clear();
+    }
 
-        if let Ok(regs) = ptrace::getregs(pid) {
-            println!("passing on signal at {:x}", regs.rip);
+    fn release(&mut self) -> Result<()> {
+        let mut result = self.interrupt_all();
+        // a thread can't be detached while it's running, which it is if the interrupt failed
+        for (tid, thread) in std::mem::take(&mut self.threads) {
+            if !thread.stopped {
+                continue;
+            }
+            if let Err(e) = ptrace::detach(tid, thread.signal) {
+                if result.is_ok() {
+                    result = Err(anyhow!("detaching {tid}: {e}"));
+                }
+            }
+        }
+        self.pending.clear();
+        result
+    }
+
+    fn thread(&mut self, tid: Pid) -> Result<&mut Thread> {
+        self.threads
+            .get_mut(&tid)
+            .ok_or_else(|| anyhow!("{tid} isn't a thread of {}", self.pid))
+    }
+
+    fn running(&self) -> Vec<Pid> {
+        self.threads
+            .iter()
+            .filter(|(_, t)| !t.stopped)
+            .map(|(&tid, _)| tid)
+            .collect()
+    }
+
+    fn resuming(&mut self, tid: Pid) -> Result<Option<Signal>> {
+        let thread = self.thread(tid)?;
+        if !thread.stopped {
+            bail!("{tid} is already running");
+        }
+        thread.stopped = false;
+        Ok(thread.signal.take())
+    }
+
+    /// Wait for a stop, dealing with the ones we cause ourselves, e.g. the one a new thread starts
+    /// with.
+    fn next_stop(&mut self) -> Result<(Pid, Stop)> {
+        loop {
+            if self.threads.is_empty() {
+                bail!("every thread of {} has exited", self.pid);
+            }
+            let status = waitpid(None, Some(WaitPidFlag::__WALL | WaitPidFlag::__WNOTHREAD))?;
+            let Some((tid, stop)) = classify(status)? else {
+                continue;
+            };
+
+            match stop {
+                Stop::Cloned(child) => {
+                    if let Some(thread) = self.threads.get_mut(&child) {
+                        thread.unannounced = false;
+                    } else if !self.exited.remove(&child) {
+                        self.threads.insert(
+                            child,
+                            Thread {
+                                starting: true,
+                                ..Thread::default()
+                            },
+                        );
+                    }
+                }
+                Stop::Interrupted | Stop::GroupStop(_) => {
+                    // a new thread's first stop can also get here before its parent's event
+                    let thread = self.threads.entry(tid).or_insert(Thread {
+                        starting: true,
+                        unannounced: true,
+                        ..Thread::default()
+                    });
+                    let starting = std::mem::take(&mut thread.starting);
+                    thread.group_stopped = matches!(stop, Stop::GroupStop(_));
+                    let asked =
+                        stop == Stop::Interrupted && std::mem::take(&mut thread.interrupted);
+                    if starting && stop == Stop::Interrupted && !asked {
+                        ptrace::cont(tid, None)?;
+                        continue;
+                    }
+                }
+                Stop::Signal(signal) => self.thread(tid)?.signal = Some(signal),
+                _ => (),
+            }
+
+            match stop {
+                Stop::Exited(_) | Stop::Killed(_) => {
+                    if self.threads.remove(&tid).is_none_or(|t| t.unannounced) {
+                        self.exited.insert(tid);
+                    }
+                    self.pending.retain(|(t, _)| *t != tid);
+                }
+                _ => {
+                    if let Some(thread) = self.threads.get_mut(&tid) {
+                        thread.stopped = true;
+                    }
+                }
+            }
+            return Ok((tid, stop));
         }
+    }
+}
 
-        ptrace::cont(pid, signal)?;
+impl Drop for ThreadGroup {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            eprintln!("failed to cleanly detach from {}: {e:?}", self.pid);
+        }
     }
 }
 
@@ -221,12 +921,187 @@ fn get_bit(val: c_long, bit: u8) -> bool {
     (val & (1 << bit)) != 0
 }
 
-#[inline]
-fn set_bit(val: &mut c_long, bit: u8, to: bool) {
-    let mask = 1u64 << bit;
-    if to {
-        *val |= mask as c_long;
-    } else {
-        *val &= !(mask as c_long);
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+
+    use nix::unistd::{fork, getpid, ForkResult};
+
+    use super::*;
+
+    /// somewhere read-only, which only `/proc/<pid>/mem` can write to
+    static READ_ONLY: [u8; 16] = [7; 16];
+
+    fn addr_of(b: &[u8]) -> u64 {
+        b.as_ptr() as u64
+    }
+
+    #[test]
+    fn scatter_gather_ourselves() -> Result<()> {
+        for method in [Method::VmRw, Method::ProcMem] {
+            let src = (0..=255u8).collect::<Vec<_>>();
+            let mut memory = Memory::with_method(getpid(), method);
+
+            let (mut a, mut b) = ([0u8; 3], [0u8; 5]);
+            memory.read_many(&mut [
+                (addr_of(&src[10..]), &mut a[..]),
+                (addr_of(&src[200..]), &mut b[..]),
+            ])?;
+            assert_eq!([10, 11, 12], a);
+            assert_eq!([200, 201, 202, 203, 204], b);
+
+            let dest = vec![0u8; 32];
+            let words = [0x0102_0304_0506_0708u64];
+            memory.write_many(&[
+                (addr_of(&dest[1..]), &[9, 9]),
+                (addr_of(&dest[8..]), bytemuck::cast_slice(&words)),
+            ])?;
+            let dest = std::hint::black_box(dest);
+            assert_eq!([0, 9, 9, 0], dest[..4]);
+            assert_eq!(words.to_vec(), memory.read_words(addr_of(&dest[8..]), 1)?);
+            assert_eq!(method, memory.method());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_for_read_only() -> Result<()> {
+        let mut memory = Memory::new(getpid());
+        let addr = addr_of(&READ_ONLY) + 4;
+        memory.write(addr, &[1, 2])?;
+
+        let mut back = [0u8; 4];
+        memory.read(addr - 1, &mut back)?;
+        assert_eq!([7, 1, 2, 7], back);
+        // it wasn't denied, the page just isn't writable
+        assert_eq!(Method::VmRw, memory.method());
+
+        assert!(memory.read(0, &mut back).is_err());
+        Ok(())
+    }
+
+    fn sleep_forever() -> ! {
+        loop {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// killed when dropped, even if the test fails
+    struct Child(Pid);
+
+    impl Drop for Child {
+        fn drop(&mut self) {
+            unsafe { nix::libc::kill(self.0.as_raw(), nix::libc::SIGKILL) };
+            let _ = waitpid(self.0, None);
+        }
+    }
+
+    #[test]
+    fn seize_every_thread() -> Result<()> {
+        let child = match unsafe { fork() }? {
+            ForkResult::Child => {
+                thread::spawn(|| sleep_forever());
+                thread::sleep(Duration::from_millis(300));
+                thread::spawn(|| sleep_forever());
+                sleep_forever()
+            }
+            ForkResult::Parent { child } => Child(child),
+        };
+        let main = child.0;
+        thread::sleep(Duration::from_millis(100));
+
+        let mut group = ThreadGroup::seize(main)?;
+        let other = group.threads().find(|&t| t != main).expect("two threads");
+        assert_eq!(2, group.threads().count());
+
+        // each gets its own stop, whichever came first
+        group.interrupt(main)?;
+        group.interrupt(other)?;
+        assert_eq!(Stop::Interrupted, group.wait_for(other)?);
+        assert_eq!(Stop::Interrupted, group.wait_for(main)?);
+        assert!(group.is_stopped(main) && group.is_stopped(other));
+        group.cont_all()?;
+
+        let (parent, stop) = group.wait()?;
+        assert_eq!(main, parent);
+        let Stop::Cloned(third) = stop else {
+            panic!("expected the third thread, not {stop:?}");
+        };
+        group.cont(main)?;
+        assert_eq!(3, group.threads().count());
+        assert!(!group.is_stopped(third));
+
+        group.interrupt_all()?;
+        assert!(group.threads().all(|t| group.is_stopped(t)));
+        group.detach()?;
+
+        // and it's left traceable
+        let again = ThreadGroup::seize(main)?;
+        assert_eq!(3, again.threads().count());
+        again.detach()
+    }
+
+    /// Everything that isn't ours is passed on, or waited out, until the child's own `int3`.
+    #[test]
+    fn signals_and_group_stops() -> Result<()> {
+        let child = match unsafe { fork() }? {
+            ForkResult::Child => {
+                unsafe { nix::libc::signal(nix::libc::SIGTRAP, nix::libc::SIG_IGN) };
+                thread::sleep(Duration::from_millis(300));
+                unsafe { std::arch::asm!("int3") };
+                sleep_forever()
+            }
+            ForkResult::Parent { child } => Child(child),
+        };
+        let pid = child.0;
+        // for it to ignore `SIGTRAP`s
+        thread::sleep(Duration::from_millis(50));
+        ptrace::seize(pid, ptrace::Options::empty())?;
+        assert_eq!(Stop::Interrupted, interrupt(pid)?);
+
+        let send = move |signal| unsafe { nix::libc::kill(pid.as_raw(), signal) };
+        send(nix::libc::SIGTRAP);
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            send(nix::libc::SIGSTOP);
+            thread::sleep(Duration::from_millis(100));
+            send(nix::libc::SIGCONT);
+        });
+        assert_eq!(Stop::Breakpoint, run_until_stop(pid)?);
+        sender.join().expect("no panic");
+
+        send(nix::libc::SIGKILL);
+        let gone = run_until_stop(pid).expect_err("killed");
+        let gone = gone.downcast_ref::<Gone>().expect("a Gone");
+        assert_eq!(Stop::Killed(Signal::SIGKILL), gone.stop);
+        Ok(())
+    }
+
+    #[test]
+    fn dr7_fields() -> Result<()> {
+        let dr7 = dr7_enabling(0, 0, Condition::Execute.dr7_fields(0x1001)?);
+        assert_eq!(0b01, dr7);
+        let dr7 = dr7_enabling(dr7, 2, Condition::Write(8).dr7_fields(0x1008)?);
+        assert_eq!(0b1001_0000_0000 << 16 | 0b01_00_01, dr7);
+        let dr7 = dr7_enabling(dr7, 2, Condition::ReadWrite(4).dr7_fields(0x1004)?);
+        assert_eq!(0b1111_0000_0000 << 16 | 0b01_00_01, dr7);
+        assert_eq!(0b01, dr7_disabling(dr7, 2));
+        // and leaves the rest alone
+        assert_eq!(1 << 8, dr7_disabling(1 << 8 | 0b11 << 2, 1));
+
+        assert!(Condition::Write(3).dr7_fields(0x1000).is_err());
+        assert!(Condition::Write(4).dr7_fields(0x1002).is_err());
+        assert!(debug_addr_register(4).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn covering_words() {
+        assert_eq!(Ok((0x1000, 1)), covering(0x1000, 8));
+        assert_eq!(Ok((0x1000, 2)), covering(0x1004, 8));
+        assert_eq!(Ok((0x1008, 1)), covering(0x100f, 1));
+        assert_eq!(Ok((0x1000, 0)), covering(0x1000, 0));
+        assert_eq!(Err(Errno::EFAULT), covering(u64::MAX - 2, 8));
     }
 }
@@ -0,0 +1,426 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use cpp_demangle::DemangleOptions;
+
+use super::elf::{build_id, full_symbol_table, Symbol};
+use super::mangle::normalise;
+
+/// One symbol from the table, demangled.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    pub raw: String,
+    pub addr: u64,
+    pub size: usize,
+    /// qualified, without the arguments, e.g. `CraftingMachine::getStatus`; C names are as-is
+    pub name: String,
+    /// the argument types, e.g. `["Recipe const&", "bool"]`, if it's a C++ function
+    pub args: Option<Vec<String>>,
+    /// see [`super::mangle::Normalised`]
+    pub clone_suffix: Option<String>,
+    pub thunk: bool,
+    /// a variable, rather than a function
+    pub data: bool,
+}
+
+impl Entry {
+    /// lower is better: the function itself, then a specialised copy, then a thunk, then a cold part
+    pub fn rank(&self) -> u8 {
+        let cold = self
+            .clone_suffix
+            .as_deref()
+            .is_some_and(|suffix| suffix.contains(".cold"));
+        match (cold, self.thunk, self.clone_suffix.is_some()) {
+            (true, _, _) => 3,
+            (false, true, _) => 2,
+            (false, false, true) => 1,
+            (false, false, false) => 0,
+        }
+    }
+}
+
+/// Every symbol in a binary, demangled once, to look up by name.
+pub struct SymbolIndex {
+    table: HashMap<String, Symbol>,
+    entries: Vec<Entry>,
+    by_name: HashMap<String, Vec<usize>>,
+    /// by [`super::mangle::Normalised::mangled`]
+    by_mangled: HashMap<String, Vec<usize>>,
+}
+
+/// bump when [`Entry`], or what's cached beside it, changes, so old caches are ignored
+pub(super) const CACHE_VERSION: u32 = 3;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Cached {
+    version: u32,
+    key: String,
+    entries: Vec<Entry>,
+}
+
+impl SymbolIndex {
+    pub fn open(bin_path: impl AsRef<Path>) -> Result<SymbolIndex> {
+        let (table, data) = full_symbol_table(bin_path)?;
+        Ok(SymbolIndex::with_data(table, &data))
+    }
+
+    /// [`SymbolIndex::open`], but via a file in `cache_dir`, named for the binary's build id, so
+    /// only the first start with a new binary has to read it all; a binary without a build id is
+    /// known by its size and modification time instead.
+    pub fn open_cached(
+        bin_path: impl AsRef<Path>,
+        cache_dir: impl AsRef<Path>,
+    ) -> Result<SymbolIndex> {
+        let bin_path = bin_path.as_ref();
+        let key = cache_key(bin_path)?;
+        let path = cache_dir.as_ref().join(format!("symbols-{key}.bin"));
+        match SymbolIndex::load(&path, &key) {
+            Ok(index) => return Ok(index),
+            Err(e) if path.exists() => println!("ignoring the symbol cache: {e:?}"),
+            Err(_) => (),
+        }
+
+        let index = SymbolIndex::open(bin_path)?;
+        // only slower next time
+        if let Err(e) = index.save(&path, &key) {
+            println!("couldn't write the symbol cache: {e:?}");
+        }
+        Ok(index)
+    }
+
+    fn load(path: &Path, key: &str) -> Result<SymbolIndex> {
+        let cached: Cached = bincode::deserialize_from(BufReader::new(fs::File::open(path)?))
+            .with_context(|| anyhow!("reading {path:?}"))?;
+        ensure!(
+            cached.version == CACHE_VERSION && cached.key == key,
+            "{path:?} is for something else"
+        );
+        Ok(SymbolIndex::from_entries(cached.entries))
+    }
+
+    fn save(&self, path: &Path, key: &str) -> Result<()> {
+        write_cache(
+            path,
+            &Cached {
+                version: CACHE_VERSION,
+                key: key.to_string(),
+                entries: self.entries.clone(),
+            },
+        )
+    }
+
+    /// as if everything was a function
+    pub fn new(table: HashMap<String, Symbol>) -> SymbolIndex {
+        SymbolIndex::with_data(table, &HashSet::new())
+    }
+
+    /// `data`: the names in `table` which are variables
+    pub fn with_data(table: HashMap<String, Symbol>, data: &HashSet<String>) -> SymbolIndex {
+        let no_return = DemangleOptions::default().no_return_type();
+        let name_only = no_return.no_params();
+
+        let mut entries = Vec::with_capacity(table.len());
+        for (raw, &(addr, size)) in &table {
+            if raw.is_empty() {
+                continue;
+            }
+            let normalised = normalise(raw);
+            let demangled = cpp_demangle::Symbol::new(normalised.mangled.as_str())
+                .ok()
+                .and_then(|sym| {
+                    let name = sym.demangle(&name_only).ok()?;
+                    let full = sym.demangle(&no_return).ok()?;
+                    let args = full.strip_prefix(&name).and_then(parse_args);
+                    Some((name, args))
+                });
+            let (name, args) = demangled.unwrap_or_else(|| (normalised.mangled.clone(), None));
+
+            entries.push(Entry {
+                raw: raw.to_string(),
+                addr,
+                size,
+                name,
+                args,
+                clone_suffix: normalised.clone_suffix.map(|s| s.to_string()),
+                thunk: normalised.thunk,
+                data: data.contains(raw),
+            });
+        }
+        SymbolIndex::from_entries(entries)
+    }
+
+    fn from_entries(entries: Vec<Entry>) -> SymbolIndex {
+        let mut table = HashMap::with_capacity(entries.len());
+        let mut by_name = HashMap::<String, Vec<usize>>::with_capacity(entries.len());
+        let mut by_mangled = HashMap::<String, Vec<usize>>::with_capacity(entries.len());
+        for (idx, entry) in entries.iter().enumerate() {
+            table.insert(entry.raw.clone(), (entry.addr, entry.size));
+            by_name.entry(entry.name.clone()).or_default().push(idx);
+            by_mangled
+                .entry(normalise(&entry.raw).mangled)
+                .or_default()
+                .push(idx);
+        }
+
+        SymbolIndex {
+            table,
+            entries,
+            by_name,
+            by_mangled,
+        }
+    }
+
+    /// the raw symbol table
+    pub fn table(&self) -> &HashMap<String, Symbol> {
+        &self.table
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// By demangled, qualified name, and optionally the argument types (whitespace is ignored);
+    /// best first, see [`Entry::rank`].
+    pub fn lookup(&self, name: &str, args: Option<&[&str]>) -> Vec<&Entry> {
+        let args = args.map(|args| args.iter().map(|a| squash(a)).collect::<Vec<_>>());
+        let found = self
+            .by_name
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|&idx| &self.entries[idx])
+            .filter(|entry| match (&args, &entry.args) {
+                (None, _) => true,
+                (Some(wanted), Some(has)) => {
+                    wanted.len() == has.len()
+                        && wanted.iter().zip(has).all(|(w, h)| *w == squash(h))
+                }
+                (Some(_), None) => false,
+            })
+            .collect();
+        ranked(found)
+    }
+
+    /// By mangled name, also finding any clones or thunks of it; best first.
+    pub fn lookup_mangled(&self, mangled: &str) -> Vec<&Entry> {
+        let found = self
+            .by_mangled
+            .get(&normalise(mangled).mangled)
+            .into_iter()
+            .flatten()
+            .map(|&idx| &self.entries[idx])
+            .collect();
+        ranked(found)
+    }
+
+    /// the best match for a demangled name, or a raw one
+    pub fn find_function(&self, name: &str) -> Result<&Entry> {
+        self.find(name)
+            .find(|entry| !entry.data)
+            .ok_or_else(|| anyhow!("no function found for {name:?}"))
+    }
+
+    /// a variable, by demangled name, or a raw one
+    pub fn find_data(&self, name: &str) -> Result<&Entry> {
+        self.find(name)
+            .find(|entry| entry.data)
+            .ok_or_else(|| anyhow!("no variable found for {name:?}"))
+    }
+
+    fn find<'s>(&'s self, name: &str) -> impl Iterator<Item = &'s Entry> {
+        self.lookup(name, None)
+            .into_iter()
+            .chain(self.lookup_mangled(name))
+    }
+}
+
+/// via a temporary file, so a half-written cache is never read
+pub(super) fn write_cache(path: &Path, cached: &impl serde::Serialize) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temp = PathBuf::from(format!("{}.tmp", path.display()));
+    let mut f = BufWriter::new(fs::File::create(&temp)?);
+    bincode::serialize_into(&mut f, cached)?;
+    f.flush()?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// the build id, otherwise the size and modification time
+pub(super) fn cache_key(bin_path: &Path) -> Result<String> {
+    if let Some(id) = build_id(bin_path)? {
+        return Ok(id);
+    }
+    let meta = fs::metadata(bin_path)?;
+    let modified = meta.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok(format!("{:x}-{:x}", meta.len(), modified.as_nanos()))
+}
+
+fn ranked(mut found: Vec<&Entry>) -> Vec<&Entry> {
+    found.sort_by(|a, b| (a.rank(), &a.raw).cmp(&(b.rank(), &b.raw)));
+    found
+}
+
+fn squash(s: &str) -> String {
+    s.split_whitespace().collect()
+}
+
+/// `(int, std::map<int, int> const&) const` into `["int", "std::map<int, int> const&"]`
+fn parse_args(rest: &str) -> Option<Vec<String>> {
+    let rest = rest.strip_prefix('(')?;
+    let mut args = Vec::new();
+    let mut depth = 0usize;
+    let mut current = String::new();
+    for c in rest.chars() {
+        match c {
+            '(' | '<' | '[' => depth += 1,
+            ')' if depth == 0 => {
+                let last = current.trim();
+                if !last.is_empty() {
+                    args.push(last.to_string());
+                }
+                return Some(args);
+            }
+            ')' | '>' | ']' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                args.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => (),
+        }
+        current.push(c);
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn index() -> SymbolIndex {
+        let names = [
+            "main",
+            "_ZN15CraftingMachine12giveProductsERK6Recipeb",
+            "_ZN15CraftingMachine12giveProductsERK6Recipeb.cold",
+            "_ZN15CraftingMachine12giveProductsERK6Recipeb.isra.0",
+            "_ZN8MainLoop14gameUpdateStepEP22MultiplayerManagerBaseP8ScenarioP10AppManagerNS_9HeavyModeE.isra.0",
+            "_ZThn208_NK15CraftingMachine17getAllowedEffectsEv",
+            "_ZNK15CraftingMachine9getStatusEv",
+            "_ZN3Foo3barESt3mapIiiSt4lessIiESaISt4pairIKiiEEEi",
+            "_ZN3Foo8instanceE",
+            "counter",
+        ];
+        SymbolIndex::with_data(
+            names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| (name.to_string(), (0x1000 * i as u64, 16)))
+                .collect(),
+            &HashSet::from(["_ZN3Foo8instanceE".to_string(), "counter".to_string()]),
+        )
+    }
+
+    #[test]
+    fn cache_round_trip() -> Result<()> {
+        let index = index();
+        let dir = std::env::temp_dir().join(format!("symbols-test-{}", std::process::id()));
+        let path = dir.join("symbols-abc.bin");
+        index.save(&path, "abc")?;
+        let loaded = SymbolIndex::load(&path, "abc")?;
+        assert!(SymbolIndex::load(&path, "def").is_err());
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(index.table(), loaded.table());
+        let raw = |index: &SymbolIndex| {
+            index
+                .lookup("CraftingMachine::giveProducts", None)
+                .iter()
+                .map(|e| e.raw.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(raw(&index), raw(&loaded));
+        assert_eq!(
+            index.find_function("Foo::bar")?,
+            loaded.find_function("Foo::bar")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ranked_clones() {
+        let index = index();
+        let found = index.lookup("CraftingMachine::giveProducts", None);
+        assert_eq!(
+            vec![
+                "_ZN15CraftingMachine12giveProductsERK6Recipeb",
+                "_ZN15CraftingMachine12giveProductsERK6Recipeb.isra.0",
+                "_ZN15CraftingMachine12giveProductsERK6Recipeb.cold",
+            ],
+            found.iter().map(|e| e.raw.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(vec!["Recipe const&".to_string(), "bool".to_string()]),
+            found[0].args
+        );
+
+        // only the clone exists
+        let step = index
+            .find_function("MainLoop::gameUpdateStep")
+            .expect("clone");
+        assert_eq!(Some(".isra.0"), step.clone_suffix.as_deref());
+        assert_eq!(
+            step.raw,
+            index.lookup_mangled("_ZN8MainLoop14gameUpdateStepEP22MultiplayerManagerBaseP8ScenarioP10AppManagerNS_9HeavyModeE")[0].raw
+        );
+    }
+
+    #[test]
+    fn args_and_thunks() {
+        let index = index();
+        assert_eq!(
+            1,
+            index
+                .lookup(
+                    "CraftingMachine::giveProducts",
+                    Some(&["Recipe  const &", "bool"])
+                )
+                .iter()
+                .filter(|e| e.rank() == 0)
+                .count()
+        );
+        assert!(index
+            .lookup("CraftingMachine::giveProducts", Some(&["bool"]))
+            .is_empty());
+
+        let thunk = index
+            .find_function("CraftingMachine::getAllowedEffects")
+            .expect("thunk");
+        assert!(thunk.thunk);
+        assert_eq!(Some(vec![]), thunk.args);
+
+        let bar = index.find_function("Foo::bar").expect("templated args");
+        assert_eq!(2, bar.args.as_ref().expect("function").len());
+
+        assert_eq!(0, index.find_function("main").expect("c").addr);
+        assert!(index.find_function("CraftingMachine::nope").is_err());
+    }
+
+    #[test]
+    fn data_not_functions() {
+        let index = index();
+        let instance = index.find_data("Foo::instance").expect("demangled");
+        assert_eq!(0x8000, instance.addr);
+        assert_eq!(instance, index.find_data("_ZN3Foo8instanceE").expect("raw"));
+        assert!(index.find_function("Foo::instance").is_err());
+
+        assert_eq!(0x9000, index.find_data("counter").expect("c").addr);
+        assert!(index.find_data("main").is_err());
+        assert!(index.find_data("Foo::bar").is_err());
+    }
+}
@@ -0,0 +1,464 @@
+use anyhow::{anyhow, bail, Result};
+use nix::libc::{c_long, user_regs_struct};
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+
+use super::breakpoints::{BreakpointId, Breakpoints};
+use super::inject::{inject_munmap, stale_shells, Shell, MAP_LEN};
+use super::ptrace::{interrupt, read_dr7, write_dr7, Condition, Gone, Memory, Stop, ThreadGroup};
+
+/// An attached thread, and everything we've changed about it.
+///
+/// [`Tracee::detach`] (or dropping it, e.g. on an error or a panic) puts it all back: the registers,
+/// any patched memory, the breakpoints and debug registers, and unmaps anything we mapped; then
+/// detaches, leaving the process as we found it.
+///
+/// Attached with [`Tracee::attach_group`], every other thread of the process is traced too, and
+/// kept running; any which hit one of our `int3`s are stepped over it.
+pub struct Tracee {
+    pid: Pid,
+    /// every thread of the process, if we've seized them all
+    group: Option<ThreadGroup>,
+    memory: Memory,
+    breakpoints: Breakpoints,
+    dr7: c_long,
+    /// registers to put back, while we've sent the thread off somewhere else
+    saved_regs: Option<user_regs_struct>,
+    /// (address, original words), in the order they were written
+    patches: Vec<(u64, Vec<u64>)>,
+    shell: Option<Shell>,
+    /// mappings to remove, as `(address, length)`, and the scratch space to run the removal in
+    regions: Vec<(u64, u64)>,
+    scratch: u64,
+    attached: bool,
+}
+
+impl Tracee {
+    /// seize `pid` (a thread id), and wait for it to stop
+    pub fn attach(pid: Pid) -> Result<Tracee> {
+        ptrace::seize(pid, ptrace::Options::empty())?;
+        let mut tracee = Tracee::new(pid, None);
+        interrupt(pid)?;
+        tracee.dr7 = read_dr7(pid)?;
+        Ok(tracee)
+    }
+
+    /// Seize every thread of the process `tgid`, and wait for `pid`, one of them, to stop; the
+    /// rest are left running.
+    pub fn attach_group(tgid: Pid, pid: Pid) -> Result<Tracee> {
+        let group = ThreadGroup::seize(tgid)?;
+        if !group.threads().any(|tid| tid == pid) {
+            bail!("{pid} isn't a thread of {tgid}");
+        }
+        let mut tracee = Tracee::new(pid, Some(group));
+        tracee.interrupt()?;
+        tracee.dr7 = read_dr7(pid)?;
+        Ok(tracee)
+    }
+
+    fn new(pid: Pid, group: Option<ThreadGroup>) -> Tracee {
+        Tracee {
+            pid,
+            group,
+            memory: Memory::new(pid),
+            breakpoints: Breakpoints::new(pid),
+            dr7: 0,
+            saved_regs: None,
+            patches: Vec::new(),
+            shell: None,
+            regions: Vec::new(),
+            scratch: 0,
+            attached: true,
+        }
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// for reading; anything written here isn't put back, unlike [`Tracee::patch`]
+    pub fn memory(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// In a debug register, see [`Breakpoints::add`]; once they're all in use, an `int3` if every
+    /// thread is traced (see [`Tracee::add_software_breakpoint`]), otherwise an error.
+    pub fn add_breakpoint(&mut self, addr: u64) -> Result<BreakpointId> {
+        if self.group.is_some() && !self.breakpoints.has_free_slot() {
+            return self.breakpoints.add_software(&mut self.memory, addr);
+        }
+        self.breakpoints.add(addr)
+    }
+
+    /// An `int3`, for when the debug registers are full; see [`Breakpoints::add_software`]. Only
+    /// once every thread is traced, see [`Tracee::attach_group`], as any other thread which ran
+    /// into it would be killed.
+    pub fn add_software_breakpoint(&mut self, addr: u64) -> Result<BreakpointId> {
+        if self.group.is_none() {
+            bail!("an int3 needs every thread of {} to be traced", self.pid);
+        }
+        self.breakpoints.add_software(&mut self.memory, addr)
+    }
+
+    /// see [`Breakpoints::watch`]
+    pub fn watch(&mut self, addr: u64, condition: Condition) -> Result<BreakpointId> {
+        self.breakpoints.watch(addr, condition)
+    }
+
+    pub fn remove_breakpoint(&mut self, id: BreakpointId) -> Result<()> {
+        self.breakpoints.remove(&mut self.memory, id)
+    }
+
+    pub fn breakpoints(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    /// continue until the next stop, and which breakpoint that was, if it was one of ours
+    pub fn run_until_hit(&mut self) -> Result<Option<BreakpointId>> {
+        if self.group.is_none() {
+            return self.breakpoints.run_until_hit(&mut self.memory);
+        }
+        self.step_over_in_group()?;
+        let stop = self.run_group_until_stop()?;
+        self.breakpoints.hit(stop)
+    }
+
+    /// [`Breakpoints::step_over`], with the other threads stopped while the `int3` is lifted, so
+    /// none of them can run past it; they're continued afterwards.
+    fn step_over_in_group(&mut self) -> Result<()> {
+        if !self.breakpoints.on_int3() {
+            return Ok(());
+        }
+        let pid = self.pid;
+        let group = self.group.as_mut().expect("only with a group");
+        group.interrupt_all()?;
+        self.breakpoints.step_over_with(&mut self.memory, || loop {
+            group.step(pid)?;
+            match group.wait_for(pid)? {
+                // it hasn't got to the instruction yet; the signal is passed on when it's continued
+                Stop::Signal(_) | Stop::GroupStop(_) => {}
+                // part-way through it; the new thread is already one of the group
+                Stop::Cloned(_) => {}
+                stop @ (Stop::Exited(_) | Stop::Killed(_)) => return Err(Gone { pid, stop }.into()),
+                stop => return Ok(stop),
+            }
+        })?;
+        group.cont_others(pid)
+    }
+
+    /// [`super::ptrace::run_until_stop`], while looking after the other threads: anything they
+    /// stop for is passed on, after stepping them over any of our `int3`s.
+    fn run_group_until_stop(&mut self) -> Result<Stop> {
+        let pid = self.pid;
+        let group = self.group.as_mut().expect("only with a group");
+        group.cont(pid)?;
+        let mut listening = false;
+        loop {
+            let (tid, stop) = group.wait()?;
+            if tid != pid {
+                match stop {
+                    Stop::Exited(_) | Stop::Killed(_) => {}
+                    Stop::Breakpoint => {
+                        self.breakpoints
+                            .step_thread_over(&mut self.memory, tid, || {
+                                group.step(tid)?;
+                                group.wait_for(tid)
+                            })?;
+                        group.cont(tid)?;
+                    }
+                    _ => group.cont(tid)?,
+                }
+                continue;
+            }
+            match stop {
+                // the end of the group-stop
+                Stop::Interrupted if listening => {
+                    listening = false;
+                    group.cont(pid)?;
+                }
+                Stop::Interrupted | Stop::Breakpoint | Stop::Hardware | Stop::Step => {
+                    return Ok(stop)
+                }
+                Stop::GroupStop(_) => {
+                    listening = true;
+                    group.cont(pid)?;
+                }
+                Stop::Signal(_) | Stop::Cloned(_) => group.cont(pid)?,
+                Stop::Exited(_) | Stop::Killed(_) => return Err(Gone { pid, stop }.into()),
+            }
+        }
+    }
+
+    /// [`super::ptrace::interrupt`], through the group, if we have one
+    fn interrupt(&mut self) -> Result<Stop> {
+        let pid = self.pid;
+        let Some(group) = self.group.as_mut() else {
+            return interrupt(pid);
+        };
+        group.interrupt(pid)?;
+        loop {
+            match group.wait_for(pid)? {
+                Stop::Signal(_) | Stop::Cloned(_) => group.cont(pid)?,
+                stop @ (Stop::Exited(_) | Stop::Killed(_)) => return Err(Gone { pid, stop }.into()),
+                stop => return Ok(stop),
+            }
+        }
+    }
+
+    /// overwrite memory, remembering what was there
+    pub fn patch(&mut self, addr: u64, data: &[u64]) -> Result<()> {
+        let backup = self.memory.read_words(addr, data.len())?;
+        self.patches.push((addr, backup));
+        self.memory.write_words(addr, data)
+    }
+
+    /// remember the current registers, to be put back by [`Tracee::restore_regs`], or on detach
+    pub fn save_regs(&mut self) -> Result<user_regs_struct> {
+        let regs = ptrace::getregs(self.pid)?;
+        self.saved_regs = Some(regs);
+        Ok(regs)
+    }
+
+    pub fn restore_regs(&mut self) -> Result<()> {
+        if let Some(regs) = self.saved_regs {
+            ptrace::setregs(self.pid, regs)?;
+            self.saved_regs = None;
+        }
+        Ok(())
+    }
+
+    /// Also tidies up any shells left behind by earlier runs, which didn't get to detach.
+    ///
+    /// precondition: thread is stopped at a reasonable place, e.g. a breakpoint outside of a lock or syscall
+    pub fn inject_shell(&mut self, scratch: u64) -> Result<&Shell> {
+        if self.shell.is_none() {
+            self.scratch = scratch;
+            for addr in stale_shells(self.pid)? {
+                println!("removing a stale shell at 0x{addr:x}");
+                inject_munmap(self.pid, scratch, addr, MAP_LEN)?;
+            }
+            let shell = Shell::inject_into(self.pid, scratch)?;
+            self.regions.push((shell.map_addr, MAP_LEN));
+            self.shell = Some(shell);
+        }
+        Ok(self.shell.as_ref().expect("just set"))
+    }
+
+    pub fn shell(&self) -> Option<&Shell> {
+        self.shell.as_ref()
+    }
+
+    /// put everything back, and detach
+    pub fn detach(mut self) -> Result<()> {
+        self.release()
+    }
+
+    /// The thread has gone (see [`super::ptrace::Gone`]), so there's nothing to put back, and
+    /// nothing to detach from.
+    pub fn forget(mut self) {
+        self.attached = false;
+        if let Some(group) = self.group.take() {
+            group.forget();
+        }
+    }
+
+    /// Every step is attempted, even if an earlier one fails; the first error is returned.
+    fn release(&mut self) -> Result<()> {
+        if !self.attached {
+            return Ok(());
+        }
+        self.attached = false;
+
+        let pid = self.pid;
+        let mut result = self.ensure_stopped();
+        let mut record = |step: Result<()>| {
+            if let Err(e) = step {
+                eprintln!("while detaching from {pid}: {e:?}");
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        };
+
+        // runs its own code, and puts the registers back afterwards
+        self.shell = None;
+        for (addr, len) in self.regions.drain(..) {
+            record(inject_munmap(pid, self.scratch, addr, len));
+        }
+        if let Some(regs) = self.saved_regs.take() {
+            record(ptrace::setregs(pid, regs).map_err(|e| anyhow!(e)));
+        }
+        for (addr, backup) in self.patches.drain(..).rev() {
+            record(self.memory.write_words(addr, &backup));
+        }
+        // a thread stopped on an `int3` has already been put back on the instruction; any others
+        // need putting back before it goes
+        if let Some(group) = self.group.as_mut() {
+            record(group.interrupt_all());
+            for (tid, stop) in group.pending() {
+                if tid != pid && stop == Stop::Breakpoint {
+                    record(self.breakpoints.rewind(tid).map(|_| ()));
+                }
+            }
+        }
+        record(self.breakpoints.clear(&mut self.memory));
+        record(write_dr7(pid, self.dr7));
+        match self.group.take() {
+            Some(group) => record(group.detach()),
+            None => record(ptrace::detach(pid, None).map_err(|e| anyhow!(e))),
+        }
+        result
+    }
+
+    /// we can only put things back while it's stopped; it's running if we failed mid-continue
+    fn ensure_stopped(&mut self) -> Result<()> {
+        if ptrace::getregs(self.pid).is_ok() {
+            return Ok(());
+        }
+        // it may get to a breakpoint first, which is then put back as if it'd been hit
+        let stop = self.interrupt()?;
+        self.breakpoints.hit(stop)?;
+        Ok(())
+    }
+}
+
+impl Drop for Tracee {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            eprintln!("failed to cleanly detach from {}: {e:?}", self.pid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::hint::black_box;
+    use std::thread;
+    use std::time::Duration;
+
+    use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    use super::*;
+    use crate::debug::breakpoints::Slot;
+
+    #[inline(never)]
+    extern "C" fn hooked(n: u64) -> u64 {
+        black_box(n) + 1
+    }
+
+    fn call_forever(n: u64) -> ! {
+        loop {
+            hooked(n);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// killed when dropped, even if the test fails
+    struct Child(Pid);
+
+    impl Drop for Child {
+        fn drop(&mut self) {
+            unsafe { nix::libc::kill(self.0.as_raw(), nix::libc::SIGKILL) };
+            let _ = waitpid(self.0, None);
+        }
+    }
+
+    #[test]
+    fn int3_with_other_threads() -> Result<()> {
+        let child = match unsafe { fork() }? {
+            ForkResult::Child => {
+                thread::spawn(|| call_forever(1));
+                thread::spawn(|| call_forever(2));
+                call_forever(3)
+            }
+            ForkResult::Parent { child } => Child(child),
+        };
+        let main = child.0;
+        thread::sleep(Duration::from_millis(100));
+
+        let hooked = hooked as *const () as u64;
+        let started = call_forever as *const () as u64;
+
+        // without the other threads, only the debug registers are safe
+        let mut tracee = Tracee::attach(main)?;
+        for _ in 0..4 {
+            tracee.add_breakpoint(started)?;
+        }
+        assert!(tracee.add_breakpoint(hooked).is_err());
+        assert!(tracee.add_software_breakpoint(hooked).is_err());
+        tracee.detach()?;
+
+        // with them, the fifth is an int3, which they run into too, and are stepped over
+        let mut tracee = Tracee::attach_group(main, main)?;
+        for _ in 0..4 {
+            tracee.add_breakpoint(started)?;
+        }
+        let id = tracee.add_breakpoint(hooked)?;
+        assert_eq!(Some(Slot::Software), tracee.breakpoints().slot(id));
+        for _ in 0..20 {
+            assert_eq!(Some(id), tracee.run_until_hit()?);
+            assert_eq!(3, ptrace::getregs(main)?.rdi);
+        }
+        tracee.detach()?;
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            WaitStatus::StillAlive,
+            waitpid(main, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL))?
+        );
+        Ok(())
+    }
+
+    extern "C" fn ignore(_: nix::libc::c_int) {}
+
+    /// Signals and new threads arriving while it's stepped over the `int3` don't lose the step, or
+    /// let anything else past it.
+    #[test]
+    fn int3_among_signals_and_clones() -> Result<()> {
+        let child = match unsafe { fork() }? {
+            ForkResult::Child => {
+                let main = nix::unistd::getpid().as_raw();
+                unsafe {
+                    nix::libc::signal(
+                        nix::libc::SIGUSR1,
+                        ignore as *const () as nix::libc::sighandler_t,
+                    )
+                };
+                thread::spawn(move || loop {
+                    unsafe {
+                        nix::libc::syscall(nix::libc::SYS_tgkill, main, main, nix::libc::SIGUSR1)
+                    };
+                    thread::sleep(Duration::from_micros(100));
+                });
+                thread::spawn(|| loop {
+                    let _ = thread::spawn(|| call_forever(1)).join();
+                });
+                thread::spawn(|| loop {
+                    let _ = thread::spawn(|| {}).join();
+                });
+                call_forever(3)
+            }
+            ForkResult::Parent { child } => Child(child),
+        };
+        let main = child.0;
+        thread::sleep(Duration::from_millis(100));
+
+        let hooked = hooked as *const () as u64;
+        let mut tracee = Tracee::attach_group(main, main)?;
+        let id = tracee.add_software_breakpoint(hooked)?;
+        for _ in 0..100 {
+            assert_eq!(Some(id), tracee.run_until_hit()?);
+            let regs = ptrace::getregs(main)?;
+            assert_eq!((hooked, 3), (regs.rip, regs.rdi));
+        }
+        tracee.detach()?;
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            WaitStatus::StillAlive,
+            waitpid(main, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL))?
+        );
+        Ok(())
+    }
+}
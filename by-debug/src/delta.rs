@@ -0,0 +1,709 @@
+//! A compact encoding for a stream of [`Observation`]s.
+//!
+//! Every frame starts with an envelope: [`MAGIC`] and a schema version byte. The magic can't be
+//! the start of a (fixint, little endian) bincode `Observation`, which is schema version 0,
+//! see [`crate::legacy`], so readers can accept either.
+//!
+//! Key frames stand alone. Delta frames only make sense after the previous frame in the
+//! same stream: the unit list is sent as the units added and removed, and products (as the
+//! increase) and statuses only for the units which changed.
+//! Everything is LEB128 varints, zigzagged if it could be negative.
+//!
+//! After the units, every frame has a list of tagged fields, `(tag, len, bytes)`. New data on
+//! `Observation` goes here: readers skip tags they don't know, and default ones which are
+//! missing, so old and new files can be read by old and new code. The version only needs to
+//! change if the layout before the fields does. Version 1 frames predate the fields, and end
+//! after the units.
+//!
+//! Fields in a delta frame which are the same as in the previous frame are left out, and readers
+//! take them from the previous frame; e.g. the [`Session`] is only sent in key frames, and when
+//! it changes.
+
+use anyhow::{anyhow, bail, ensure, Result};
+use time::OffsetDateTime;
+
+use crate::{CraftingLite, Observation, Session};
+
+pub const MAGIC: &[u8; 3] = b"FXD";
+/// the schema version we write; we can read all of `MIN_VERSION..=VERSION`
+pub const VERSION: u8 = 2;
+pub const MIN_VERSION: u8 = 1;
+/// the first version with the field list
+const FIELDS_VERSION: u8 = 2;
+
+const KIND_KEY: u8 = 0;
+const KIND_DELTA: u8 = 1;
+
+/// [`Observation::session`]; empty bytes if `None`
+const FIELD_SESSION: u64 = 1;
+/// [`Observation::tick`], a varint; left out if `None`, even in delta frames
+const FIELD_TICK: u64 = 2;
+
+pub fn is_delta_frame(buf: &[u8]) -> bool {
+    buf.starts_with(MAGIC)
+}
+
+/// Encodes observations, remembering the previous one to encode against.
+pub struct Encoder {
+    prev: Option<Observation>,
+    since_key: usize,
+    key_every: usize,
+}
+
+impl Encoder {
+    /// `key_every`: emit a key frame every this many frames; `1` means only key frames
+    pub fn new(key_every: usize) -> Self {
+        assert!(key_every > 0, "key_every must be positive");
+        Self {
+            prev: None,
+            since_key: 0,
+            key_every,
+        }
+    }
+
+    pub fn encode(&mut self, obs: &Observation) -> Result<Vec<u8>> {
+        ensure!(
+            obs.inner
+                .windows(2)
+                .all(|w| w[0].unit_number < w[1].unit_number),
+            "observation units must be sorted and unique"
+        );
+
+        let mut out = Vec::with_capacity(16 + obs.inner.len() * 2);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+
+        let session = write_session(obs.session.as_ref())?;
+        let mut tick = Vec::new();
+        let mut fields: Vec<(u64, &[u8])> = Vec::with_capacity(2);
+        if let Some(t) = obs.tick {
+            write_varint(&mut tick, t);
+            fields.push((FIELD_TICK, &tick));
+        }
+
+        match self.prev.as_ref() {
+            Some(prev) if self.since_key + 1 < self.key_every => {
+                out.push(KIND_DELTA);
+                write_delta(&mut out, prev, obs)?;
+                if prev.session != obs.session {
+                    fields.push((FIELD_SESSION, &session));
+                }
+                self.since_key += 1;
+            }
+            _ => {
+                out.push(KIND_KEY);
+                write_key(&mut out, obs)?;
+                if obs.session.is_some() {
+                    fields.push((FIELD_SESSION, &session));
+                }
+                self.since_key = 0;
+            }
+        }
+        write_fields(&mut out, &fields);
+
+        self.prev = Some(obs.clone());
+        Ok(out)
+    }
+}
+
+/// decode a frame, which must be a key frame if `prev` is `None`
+pub fn decode_frame(buf: &[u8], prev: Option<&Observation>) -> Result<Observation> {
+    let mut r = Reader { buf };
+    let magic = r.take(MAGIC.len())?;
+    ensure!(magic == MAGIC, "not a delta frame");
+    let version = r.byte()?;
+    ensure!(
+        (MIN_VERSION..=VERSION).contains(&version),
+        "unsupported schema version {version}, we support {MIN_VERSION}..={VERSION}"
+    );
+    let mut obs = match r.byte()? {
+        KIND_KEY => read_key(&mut r)?,
+        KIND_DELTA => {
+            let prev = prev.ok_or_else(|| anyhow!("delta frame without a previous frame"))?;
+            let mut obs = read_delta(&mut r, prev)?;
+            obs.session = prev.session.clone();
+            obs
+        }
+        other => bail!("unknown frame kind {other}"),
+    };
+    let fields = if version >= FIELDS_VERSION {
+        read_fields(&mut r)?
+    } else {
+        Vec::new()
+    };
+    for (tag, bytes) in fields {
+        match tag {
+            FIELD_SESSION => obs.session = read_session(bytes)?,
+            FIELD_TICK => {
+                let mut r = Reader { buf: bytes };
+                obs.tick = Some(r.varint()?);
+                ensure!(r.buf.is_empty(), "{} trailing bytes in tick", r.buf.len());
+            }
+            _ => (),
+        }
+    }
+    ensure!(r.buf.is_empty(), "{} trailing bytes in frame", r.buf.len());
+    Ok(obs)
+}
+
+fn write_key(out: &mut Vec<u8>, obs: &Observation) -> Result<()> {
+    write_signed(out, i64::try_from(obs.time.unix_timestamp_nanos())?);
+    write_varint(out, obs.inner.len() as u64);
+    let mut last_unit = 0;
+    for c in &obs.inner {
+        write_varint(out, u64::from(c.unit_number - last_unit));
+        last_unit = c.unit_number;
+        write_varint(out, u64::from(c.products_complete));
+        write_varint(out, u64::from(c.status));
+    }
+    Ok(())
+}
+
+fn read_key(r: &mut Reader) -> Result<Observation> {
+    let time = read_time(r.signed()?)?;
+    let count = r.count()?;
+    let mut inner = Vec::with_capacity(count);
+    let mut unit_number = 0u32;
+    for _ in 0..count {
+        unit_number = unit_number
+            .checked_add(r.u32()?)
+            .ok_or_else(|| anyhow!("unit number overflow"))?;
+        inner.push(CraftingLite {
+            unit_number,
+            products_complete: r.u32()?,
+            status: r.u32()?,
+        });
+    }
+    Ok(Observation {
+        time,
+        session: None,
+        tick: None,
+        inner,
+    })
+}
+
+fn write_delta(out: &mut Vec<u8>, prev: &Observation, obs: &Observation) -> Result<()> {
+    let time = i64::try_from(obs.time.unix_timestamp_nanos())?;
+    let prev_time = i64::try_from(prev.time.unix_timestamp_nanos())?;
+    write_signed(out, time - prev_time);
+
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    merge(&prev.inner, &obs.inner, |p, c| match (p, c) {
+        (Some(p), None) => removed.push(p.unit_number),
+        (None, Some(c)) => added.push(c.unit_number),
+        _ => (),
+    });
+    write_gaps(out, &removed);
+    write_gaps(out, &added);
+
+    let mut product_changes = Vec::new();
+    let mut status_changes = Vec::new();
+    for (i, c) in obs.inner.iter().enumerate() {
+        let before = prev
+            .inner
+            .binary_search_by_key(&c.unit_number, |p| p.unit_number)
+            .ok()
+            .map(|idx| &prev.inner[idx]);
+        let (products, status) = before
+            .map(|p| (p.products_complete, p.status))
+            .unwrap_or_default();
+        if c.products_complete != products {
+            product_changes.push((i, i64::from(c.products_complete) - i64::from(products)));
+        }
+        if c.status != status {
+            status_changes.push((i, i64::from(c.status)));
+        }
+    }
+
+    write_sparse(out, &product_changes, write_signed);
+    write_sparse(out, &status_changes, |out, v| write_varint(out, v as u64));
+    Ok(())
+}
+
+fn read_delta(r: &mut Reader, prev: &Observation) -> Result<Observation> {
+    let prev_time = i64::try_from(prev.time.unix_timestamp_nanos())?;
+    let time = read_time(
+        prev_time
+            .checked_add(r.signed()?)
+            .ok_or_else(|| anyhow!("time overflow"))?,
+    )?;
+
+    let removed = read_gaps(r)?;
+    let added = read_gaps(r)?;
+
+    let mut units = Vec::with_capacity(prev.inner.len() + added.len());
+    let mut removed_it = removed.iter().peekable();
+    for p in &prev.inner {
+        if removed_it.peek() == Some(&&p.unit_number) {
+            removed_it.next();
+            continue;
+        }
+        units.push((p.unit_number, p.products_complete, p.status));
+    }
+    ensure!(removed_it.next().is_none(), "removed unit not present");
+    for unit in added {
+        units.push((unit, 0, 0));
+    }
+    units.sort_unstable_by_key(|(unit, _, _)| *unit);
+    ensure!(
+        units.windows(2).all(|w| w[0].0 < w[1].0),
+        "added unit already present"
+    );
+
+    let mut inner = units
+        .into_iter()
+        .map(|(unit_number, products_complete, status)| CraftingLite {
+            unit_number,
+            products_complete,
+            status,
+        })
+        .collect::<Vec<_>>();
+
+    read_sparse(r, &mut inner, |r, c| {
+        c.products_complete = u32::try_from(i64::from(c.products_complete) + r.signed()?)?;
+        Ok(())
+    })?;
+    read_sparse(r, &mut inner, |r, c| {
+        c.status = r.u32()?;
+        Ok(())
+    })?;
+
+    Ok(Observation {
+        time,
+        session: None,
+        tick: None,
+        inner,
+    })
+}
+
+/// call `f` for each unit in either sorted list, paired up if present in both
+fn merge<'a>(
+    a: &'a [CraftingLite],
+    b: &'a [CraftingLite],
+    mut f: impl FnMut(Option<&'a CraftingLite>, Option<&'a CraftingLite>),
+) {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) if x.unit_number == y.unit_number => {
+                f(Some(x), Some(y));
+                i += 1;
+                j += 1;
+            }
+            (Some(x), Some(y)) if x.unit_number < y.unit_number => {
+                f(Some(x), None);
+                i += 1;
+            }
+            (Some(x), None) => {
+                f(Some(x), None);
+                i += 1;
+            }
+            (_, Some(y)) => {
+                f(None, Some(y));
+                j += 1;
+            }
+            (None, None) => unreachable!("loop condition"),
+        }
+    }
+}
+
+fn write_fields(out: &mut Vec<u8>, fields: &[(u64, &[u8])]) {
+    write_varint(out, fields.len() as u64);
+    for (tag, bytes) in fields {
+        write_varint(out, *tag);
+        write_varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+}
+
+/// `(tag, bytes)`, in the order they were written
+fn read_fields<'b>(r: &mut Reader<'b>) -> Result<Vec<(u64, &'b [u8])>> {
+    let count = r.count()?;
+    let mut ret = Vec::with_capacity(count);
+    for _ in 0..count {
+        let tag = r.varint()?;
+        let len = r.count()?;
+        ret.push((tag, r.take(len)?));
+    }
+    Ok(ret)
+}
+
+fn write_session(session: Option<&Session>) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    if let Some(session) = session {
+        write_varint(&mut out, u64::from(session.pid));
+        write_signed(
+            &mut out,
+            i64::try_from(session.started.unix_timestamp_nanos())?,
+        );
+        write_opt_str(&mut out, session.build_id.as_deref());
+        write_opt_str(&mut out, session.save_name.as_deref());
+    }
+    Ok(out)
+}
+
+fn read_session(buf: &[u8]) -> Result<Option<Session>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    let mut r = Reader { buf };
+    let session = Session {
+        pid: r.u32()?,
+        started: read_time(r.signed()?)?,
+        build_id: r.opt_str()?,
+        save_name: r.opt_str()?,
+    };
+    ensure!(
+        r.buf.is_empty(),
+        "{} trailing bytes in session",
+        r.buf.len()
+    );
+    Ok(Some(session))
+}
+
+/// `0` for `None`, otherwise the length plus one
+fn write_opt_str(out: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        None => write_varint(out, 0),
+        Some(s) => {
+            write_varint(out, s.len() as u64 + 1);
+            out.extend_from_slice(s.as_bytes());
+        }
+    }
+}
+
+/// (index, value) pairs, with the indexes ascending
+fn write_sparse(out: &mut Vec<u8>, changes: &[(usize, i64)], write: impl Fn(&mut Vec<u8>, i64)) {
+    write_varint(out, changes.len() as u64);
+    let mut last_idx = 0;
+    for &(idx, v) in changes {
+        write_varint(out, (idx - last_idx) as u64);
+        last_idx = idx;
+        write(out, v);
+    }
+}
+
+fn read_sparse(
+    r: &mut Reader,
+    inner: &mut [CraftingLite],
+    mut apply: impl FnMut(&mut Reader, &mut CraftingLite) -> Result<()>,
+) -> Result<()> {
+    let changes = r.count()?;
+    let mut idx = 0usize;
+    for _ in 0..changes {
+        idx += usize::try_from(r.varint()?)?;
+        let c = inner
+            .get_mut(idx)
+            .ok_or_else(|| anyhow!("change out of range"))?;
+        apply(r, c)?;
+    }
+    Ok(())
+}
+
+fn read_time(nanos: i64) -> Result<OffsetDateTime> {
+    Ok(OffsetDateTime::from_unix_timestamp_nanos(i128::from(
+        nanos,
+    ))?)
+}
+
+fn write_gaps(out: &mut Vec<u8>, sorted: &[u32]) {
+    write_varint(out, sorted.len() as u64);
+    let mut last = 0;
+    for v in sorted {
+        write_varint(out, u64::from(v - last));
+        last = *v;
+    }
+}
+
+fn read_gaps(r: &mut Reader) -> Result<Vec<u32>> {
+    let count = r.count()?;
+    let mut ret = Vec::with_capacity(count);
+    let mut last = 0u32;
+    for _ in 0..count {
+        last = last
+            .checked_add(r.u32()?)
+            .ok_or_else(|| anyhow!("gap overflow"))?;
+        ret.push(last);
+    }
+    Ok(ret)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_signed(out: &mut Vec<u8>, v: i64) {
+    write_varint(out, ((v << 1) ^ (v >> 63)) as u64);
+}
+
+struct Reader<'b> {
+    buf: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, n: usize) -> Result<&'b [u8]> {
+        ensure!(self.buf.len() >= n, "truncated frame");
+        let (ret, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(ret)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut ret = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            ret |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(ret);
+            }
+        }
+        bail!("varint too long")
+    }
+
+    fn signed(&mut self) -> Result<i64> {
+        let v = self.varint()?;
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::try_from(self.varint()?)?)
+    }
+
+    fn opt_str(&mut self) -> Result<Option<String>> {
+        let len = match self.varint()? {
+            0 => return Ok(None),
+            len => usize::try_from(len - 1)?,
+        };
+        Ok(Some(String::from_utf8(self.take(len)?.to_vec())?))
+    }
+
+    fn count(&mut self) -> Result<usize> {
+        let len = usize::try_from(self.varint()?)?;
+        // every entry is at least one byte, so this is a cheap sanity check against huge allocations
+        ensure!(len <= self.buf.len(), "count {len} exceeds frame");
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn obs(secs: i64, units: &[(u32, u32, u32)]) -> Observation {
+        Observation {
+            time: OffsetDateTime::from_unix_timestamp(secs).expect("static time"),
+            session: None,
+            tick: None,
+            inner: units
+                .iter()
+                .map(|&(unit_number, products_complete, status)| CraftingLite {
+                    unit_number,
+                    products_complete,
+                    status,
+                })
+                .collect(),
+        }
+    }
+
+    fn units(obs: &Observation) -> Vec<(u32, u32, u32)> {
+        obs.inner
+            .iter()
+            .map(|c| (c.unit_number, c.products_complete, c.status))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let stream = [
+            obs(1_700_000_000, &[(3, 10, 1), (7, 0, 22), (900, 5, 1)]),
+            obs(1_700_000_007, &[(3, 12, 1), (7, 0, 21), (900, 5, 1)]),
+            // 7 removed, 8 and 1000 added, 3 rebuilt with fewer products
+            obs(
+                1_700_000_014,
+                &[(3, 2, 1), (8, 0, 15), (900, 6, 1), (1000, 1, 1)],
+            ),
+            obs(1_700_000_021, &[]),
+            obs(1_700_000_028, &[(1, 1, 1)]),
+        ];
+
+        let mut enc = Encoder::new(3);
+        let mut prev = None;
+        for (i, o) in stream.iter().enumerate() {
+            let frame = enc.encode(o)?;
+            assert!(is_delta_frame(&frame));
+            assert_eq!(frame[MAGIC.len() + 1] == KIND_KEY, i % 3 == 0, "{i}");
+            let back = decode_frame(&frame, prev.as_ref())?;
+            assert_eq!(back.time, o.time);
+            assert_eq!(units(&back), units(o));
+            prev = Some(back);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn delta_is_small() -> Result<()> {
+        let many = (0..10_000u32).map(|u| (u * 3, u, 1)).collect::<Vec<_>>();
+        let first = obs(1_700_000_000, &many);
+        let mut next = many.clone();
+        next[17].1 += 1;
+        next[9000].2 = 22;
+        let second = obs(1_700_000_007, &next);
+
+        let mut enc = Encoder::new(usize::MAX);
+        let key = enc.encode(&first)?;
+        let delta = enc.encode(&second)?;
+
+        // unchanged units cost nothing
+        assert!(delta.len() < 32, "{}", delta.len());
+        assert!(key.len() > delta.len());
+        assert!(decode_frame(&delta, None).is_err());
+        assert_eq!(units(&decode_frame(&delta, Some(&first))?), next);
+        Ok(())
+    }
+
+    #[test]
+    fn old_bincode_still_unpacks() -> Result<()> {
+        use bincode::Options;
+
+        let old = obs(1_700_000_000, &[(3, 10, 1), (7, 0, 22)]);
+        let packed = crate::bincode().serialize(&crate::legacy::ObservationV0 {
+            time: old.time,
+            inner: old
+                .inner
+                .iter()
+                .map(|c| crate::legacy::CraftingLiteV0 {
+                    unit_number: c.unit_number,
+                    products_complete: c.products_complete,
+                    status: c.status,
+                })
+                .collect(),
+        })?;
+        assert!(!is_delta_frame(&packed));
+
+        let mut unpacker = crate::Unpacker::default();
+        assert_eq!(units(&unpacker.unpack(packed.as_slice())?), units(&old));
+
+        let mut enc = Encoder::new(10);
+        enc.encode(&old)?;
+        let next = obs(1_700_000_007, &[(3, 11, 1), (7, 0, 22)]);
+        let delta = enc.encode(&next)?;
+        assert_eq!(units(&unpacker.unpack(delta.as_slice())?), units(&next));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_fields_skipped() -> Result<()> {
+        let o = obs(1_700_000_000, &[(3, 10, 1)]);
+        let mut frame = Encoder::new(1).encode(&o)?;
+        // replace the empty field list with one from the future
+        assert_eq!(frame.pop(), Some(0));
+        write_fields(&mut frame, &[(77, b"hello"), (78, b"")]);
+        assert_eq!(units(&decode_frame(&frame, None)?), units(&o));
+
+        frame[MAGIC.len()] = VERSION + 1;
+        assert!(decode_frame(&frame, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn version_one_has_no_fields() -> Result<()> {
+        let first = obs(1_700_000_000, &[(3, 10, 1), (7, 0, 22)]);
+        let second = obs(1_700_000_007, &[(3, 11, 1), (7, 0, 22)]);
+        let mut enc = Encoder::new(usize::MAX);
+        let frames = [enc.encode(&first)?, enc.encode(&second)?].map(|mut frame| {
+            // as written before the field list existed
+            assert_eq!(frame.pop(), Some(0));
+            frame[MAGIC.len()] = 1;
+            frame
+        });
+
+        let key = decode_frame(&frames[0], None)?;
+        assert_eq!(units(&key), units(&first));
+        assert_eq!((key.session.as_ref(), key.tick), (None, None));
+        let delta = decode_frame(&frames[1], Some(&key))?;
+        assert_eq!(units(&delta), units(&second));
+
+        // a version 2 reader would want the field list
+        let mut frame = frames[0].clone();
+        frame[MAGIC.len()] = VERSION;
+        assert!(decode_frame(&frame, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn session_only_sent_on_change() -> Result<()> {
+        let session = Session {
+            pid: 1234,
+            build_id: Some("a1b2c3".to_string()),
+            save_name: None,
+            started: OffsetDateTime::from_unix_timestamp(1_699_999_990).expect("static time"),
+        };
+        let with = |mut o: Observation, session: &Option<Session>| {
+            o.session = session.clone();
+            o
+        };
+        let reloaded = Some(Session {
+            save_name: Some("my-base".to_string()),
+            ..session.clone()
+        });
+        let session = Some(session);
+        let stream = [
+            with(obs(1_700_000_000, &[(3, 10, 1)]), &session),
+            with(obs(1_700_000_007, &[(3, 11, 1)]), &session),
+            with(obs(1_700_000_014, &[(3, 12, 1)]), &reloaded),
+            with(obs(1_700_000_021, &[(3, 13, 1)]), &None),
+        ];
+
+        let mut enc = Encoder::new(usize::MAX);
+        let frames = stream
+            .iter()
+            .map(|o| enc.encode(o))
+            .collect::<Result<Vec<_>>>()?;
+        // an empty field list
+        assert_eq!(frames[1].last(), Some(&0));
+
+        let mut prev: Option<Observation> = None;
+        for (frame, o) in frames.iter().zip(&stream) {
+            let back = decode_frame(frame, prev.as_ref())?;
+            assert_eq!(back.session, o.session);
+            prev = Some(back);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn tick_round_trip() -> Result<()> {
+        let mut first = obs(1_700_000_000, &[(3, 10, 1)]);
+        first.tick = Some(1_234_567);
+        let second = obs(1_700_000_007, &[(3, 11, 1)]);
+        let mut third = obs(1_700_000_014, &[(3, 12, 1)]);
+        third.tick = Some(60);
+
+        let mut enc = Encoder::new(usize::MAX);
+        let mut prev = None;
+        for o in [first, second, third] {
+            let back = decode_frame(&enc.encode(&o)?, prev.as_ref())?;
+            assert_eq!(back.tick, o.tick);
+            prev = Some(back);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unsorted_rejected() {
+        let bad = obs(0, &[(5, 0, 0), (4, 0, 0)]);
+        assert!(Encoder::new(1).encode(&bad).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+//! The extractors' end of serve: they `/exp/store` observations, and ask `/exp/since` what they
+//! need to backfill.
+
+use std::io;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use bunyarrs::{vars_dbg, Bunyarr};
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+
+use crate::store::Store;
+use crate::unpack_observation;
+
+struct ExpState {
+    data: Arc<RwLock<Store>>,
+    logger: Bunyarr,
+}
+
+pub fn routes(data: Arc<RwLock<Store>>, logger: Bunyarr) -> Router {
+    Router::new()
+        .route("/exp/store", post(store))
+        .route("/exp/since", get(since))
+        .with_state(Arc::new(ExpState { data, logger }))
+}
+
+#[axum::debug_handler]
+async fn store(State(state): State<Arc<ExpState>>, buf: Bytes) -> StatusCode {
+    // TODO: less copying / unbounded memory usage?
+    let buf = buf.to_vec();
+    let observation = match unpack_observation(io::Cursor::new(buf)) {
+        Ok(observation) => observation,
+        Err(err) => {
+            eprintln!("error parsing observation: {}", err);
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    let mut data = state.data.write().await;
+    match data.append(observation) {
+        Ok(_) => StatusCode::ACCEPTED,
+        Err(err) => {
+            state
+                .logger
+                .error(vars_dbg! { err }, "failed to store observation");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SinceQuery {
+    session: String,
+}
+
+/// The backfill protocol: the newest observation we have from a session, as unix nanos, or
+/// `null` if we've never heard of it; extractors will `/exp/store` anything newer they have.
+#[axum::debug_handler]
+async fn since(State(state): State<Arc<ExpState>>, Query(query): Query<SinceQuery>) -> Json<Value> {
+    let data = state.data.read().await;
+    let last = data
+        .series(Some(&query.session))
+        .ok()
+        .and_then(|series| series.last())
+        .and_then(|obs| i64::try_from(obs.time.unix_timestamp_nanos()).ok());
+    Json(json!({ "last": last }))
+}
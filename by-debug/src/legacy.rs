@@ -0,0 +1,70 @@
+//! Frozen copies of the structs as they were when observations were bare bincode
+//! (schema version 0, before [`crate::delta`] frames existed).
+//!
+//! These must never change: they describe files that already exist. `Observation` is free to
+//! grow, as long as there's a `From` here to fill in the new bits.
+
+use time::OffsetDateTime;
+
+use crate::{CraftingLite, Observation};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CraftingLiteV0 {
+    pub unit_number: u32,
+    pub products_complete: u32,
+    pub status: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ObservationV0 {
+    pub time: OffsetDateTime,
+    pub inner: Vec<CraftingLiteV0>,
+}
+
+impl From<ObservationV0> for Observation {
+    fn from(v0: ObservationV0) -> Self {
+        Observation {
+            time: v0.time,
+            session: None,
+            tick: None,
+            inner: v0
+                .inner
+                .into_iter()
+                .map(|c| CraftingLite {
+                    unit_number: c.unit_number,
+                    products_complete: c.products_complete,
+                    status: c.status,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bincode::Options;
+
+    use super::*;
+
+    #[test]
+    fn v0_golden() -> anyhow::Result<()> {
+        // 2023-11-14T22:13:20.5Z, one unit: 7, 12 products, status 1
+        let packed = [
+            0xe7, 0x07, 0x00, 0x00, 0x3e, 0x01, 0x16, 0x0d, 0x14, 0x00, 0x65, 0xcd, 0x1d, 0x00,
+            0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00,
+            0x0c, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        ];
+        let obs: Observation = crate::bincode()
+            .deserialize::<ObservationV0>(&packed)?
+            .into();
+        assert_eq!(obs.time.unix_timestamp_nanos(), 1_700_000_000_500_000_000);
+        assert_eq!(obs.inner.len(), 1);
+        assert_eq!(obs.inner[0].unit_number, 7);
+        assert_eq!(obs.inner[0].products_complete, 12);
+        assert_eq!(obs.inner[0].status, 1);
+
+        let via_unpack = crate::unpack_observation(packed.as_slice())?;
+        assert_eq!(via_unpack.time, obs.time);
+        Ok(())
+    }
+}
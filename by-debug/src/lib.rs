@@ -1,20 +1,135 @@
 pub mod debug;
+pub mod delta;
+pub mod exp;
+pub mod legacy;
+pub mod store;
 
 use anyhow::Result;
 use bincode::Options;
 use std::io::Read;
 use time::OffsetDateTime;
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct CraftingLite {
     pub unit_number: u32,
     pub products_complete: u32,
     pub status: u32,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+/// Which game an observation came from: one run of the extractor against one process.
+///
+/// Unit numbers only mean anything within a session; they start again when a save is loaded.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Session {
+    pub pid: u32,
+    /// the binary's GNU build id, as hex
+    pub build_id: Option<String>,
+    pub save_name: Option<String>,
+    /// when the extractor started
+    pub started: OffsetDateTime,
+}
+
+impl Session {
+    /// short, and safe for urls and metric labels
+    pub fn key(&self) -> String {
+        format!("{}-{}", self.pid, self.started.unix_timestamp())
+    }
+}
+
+/// Notices a process loading a different game, which needs a new [`Session`], as the unit
+/// numbers start again: the tick goes backwards, the save name changes, or the set of crafting
+/// machines moves.
+pub struct SessionTracker {
+    current: Session,
+    tick: Option<u64>,
+    /// zero until we've seen the set
+    set_base: u64,
+}
+
+impl SessionTracker {
+    pub fn new(session: Session) -> Self {
+        SessionTracker {
+            current: session,
+            tick: None,
+            set_base: 0,
+        }
+    }
+
+    pub fn current(&self) -> &Session {
+        &self.current
+    }
+
+    /// The session for an observation of this state, made at `now`; `true` if it's a new one.
+    pub fn observe(
+        &mut self,
+        now: OffsetDateTime,
+        tick: Option<u64>,
+        set_base: u64,
+        save_name: Option<String>,
+    ) -> (&Session, bool) {
+        let rewound = matches!((self.tick, tick), (Some(prev), Some(tick)) if tick < prev);
+        let moved = self.set_base != 0 && set_base != 0 && set_base != self.set_base;
+        let renamed = save_name != self.current.save_name;
+        let changed = rewound || moved || renamed;
+        if changed {
+            // the key only has whole seconds, and must differ from the last session's
+            let earliest = self.current.started + time::Duration::SECOND;
+            self.current = Session {
+                save_name,
+                started: now.max(earliest),
+                ..self.current.clone()
+            };
+        }
+        if tick.is_some() {
+            self.tick = tick;
+        }
+        if set_base != 0 {
+            self.set_base = set_base;
+        }
+        (&self.current, changed)
+    }
+}
+
+/// the most ticks which can pass in a wall-clock second: `game.speed` goes up to 100
+const MAX_TICKS_PER_SEC: i64 = 60 * 100;
+
+/// Checks ticks read from the game, as a wrong path to it reads whatever is there instead: a tick
+/// must follow on from the last, and no faster than the game can run. One that doesn't is left
+/// out, but if the next follows on from it, that's a new game (e.g. a load), and both are kept.
+#[derive(Default)]
+pub struct TickCheck {
+    last: Option<(u32, OffsetDateTime)>,
+    /// left out, until we know whether it's a new game
+    suspect: Option<(u32, OffsetDateTime)>,
+}
+
+impl TickCheck {
+    /// the tick read at `now`, if it's believable
+    pub fn check(&mut self, now: OffsetDateTime, tick: u32) -> Option<u64> {
+        let follows = |(prev, at): (u32, OffsetDateTime)| {
+            // a second's slack, for the time it took to read
+            let secs = (now - at).whole_seconds().max(0) + 1;
+            tick >= prev && i64::from(tick - prev) <= secs * MAX_TICKS_PER_SEC
+        };
+        if self.last.is_some_and(follows) || self.suspect.is_some_and(follows) {
+            self.last = Some((tick, now));
+            self.suspect = None;
+            return Some(u64::from(tick));
+        }
+        self.suspect = Some((tick, now));
+        None
+    }
+}
+
+/// the session key of observations which don't have a [`Session`], e.g. from old archives
+pub const UNKNOWN_SESSION: &str = "unknown";
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct Observation {
     pub time: OffsetDateTime,
+    pub session: Option<Session>,
+    /// the game's tick counter, which stops while it's paused, and goes backwards on a load
+    pub tick: Option<u64>,
     pub inner: Vec<CraftingLite>,
 }
 
@@ -22,6 +137,13 @@ impl Observation {
     pub fn ts(&self) -> i64 {
         self.time.unix_timestamp()
     }
+
+    pub fn session_key(&self) -> String {
+        match &self.session {
+            Some(session) => session.key(),
+            None => UNKNOWN_SESSION.to_string(),
+        }
+    }
 }
 
 fn bincode() -> impl Options {
@@ -30,10 +152,122 @@ fn bincode() -> impl Options {
         .with_little_endian()
 }
 
+/// a standalone (key frame) packed observation, see [`delta`]
 pub fn pack_observation(obs: &Observation) -> Result<Vec<u8>> {
-    Ok(bincode().serialize(obs)?)
+    delta::Encoder::new(1).encode(obs)
 }
 
+/// a standalone packed observation, either a key frame or the old (schema 0) bincode format
 pub fn unpack_observation(r: impl Read) -> Result<Observation> {
-    Ok(bincode().deserialize_from(r)?)
+    Unpacker::default().unpack(r)
+}
+
+/// Unpacks a stream of observations (e.g. from an archive), in order, which may contain
+/// delta frames, key frames, or old bincode observations; whichever schema version they were
+/// written with, they're migrated to the current [`Observation`].
+#[derive(Default)]
+pub struct Unpacker {
+    prev: Option<Observation>,
+}
+
+impl Unpacker {
+    pub fn unpack(&mut self, mut r: impl Read) -> Result<Observation> {
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+        let obs = if delta::is_delta_frame(&buf) {
+            delta::decode_frame(&buf, self.prev.as_ref())?
+        } else {
+            bincode().deserialize::<legacy::ObservationV0>(&buf)?.into()
+        };
+        self.prev = Some(obs.clone());
+        Ok(obs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn implausible_ticks() {
+        let at = |secs| OffsetDateTime::from_unix_timestamp(secs).expect("static time");
+        let mut check = TickCheck::default();
+
+        // nothing to compare the first with
+        assert_eq!(None, check.check(at(1_700_000_000), 600));
+        assert_eq!(Some(1020), check.check(at(1_700_000_007), 1020));
+        assert_eq!(Some(1020), check.check(at(1_700_000_014), 1020));
+        // more than a minute of game in a second, then backwards
+        assert_eq!(None, check.check(at(1_700_000_015), 100_000));
+        assert_eq!(None, check.check(at(1_700_000_016), 100_000_000));
+        assert_eq!(None, check.check(at(1_700_000_021), 60));
+        // carrying on from the last good one
+        assert_eq!(Some(1440), check.check(at(1_700_000_028), 1440));
+
+        // a load, which the next one follows on from
+        assert_eq!(None, check.check(at(1_700_000_035), 60));
+        assert_eq!(Some(480), check.check(at(1_700_000_042), 480));
+        assert_eq!(Some(900), check.check(at(1_700_000_049), 900));
+    }
+
+    #[test]
+    fn new_session_on_load() {
+        let at = |secs| OffsetDateTime::from_unix_timestamp(secs).expect("static time");
+        let first = Session {
+            pid: 1234,
+            build_id: None,
+            save_name: Some("base".to_string()),
+            started: at(1_700_000_000),
+        };
+        let base = || Some("base".to_string());
+        let mut tracker = SessionTracker::new(first.clone());
+
+        // finding the set for the first time, and the tick moving on, are the same game
+        assert!(!tracker.observe(at(1_700_000_000), None, 0, base()).1);
+        assert!(
+            !tracker
+                .observe(at(1_700_000_010), Some(600), 0x1000, base())
+                .1
+        );
+        assert!(
+            !tracker
+                .observe(at(1_700_000_020), Some(1200), 0x1000, base())
+                .1
+        );
+        // a tick we couldn't read doesn't count as going backwards
+        assert!(!tracker.observe(at(1_700_000_030), None, 0x1000, base()).1);
+        assert_eq!(&first, tracker.current());
+
+        // loaded an earlier save, in the same second as the last observation
+        let (rewound, changed) = tracker.observe(at(1_700_000_000), Some(60), 0x1000, base());
+        assert!(changed);
+        assert_eq!(at(1_700_000_001), rewound.started);
+        assert_ne!(first.key(), rewound.key());
+        let rewound = rewound.clone();
+
+        let (moved, changed) = tracker.observe(at(1_700_000_050), Some(120), 0x2000, base());
+        assert!(changed);
+        assert_eq!(at(1_700_000_050), moved.started);
+        assert_ne!(rewound.key(), moved.key());
+
+        let (renamed, changed) = tracker.observe(
+            at(1_700_000_060),
+            Some(180),
+            0x2000,
+            Some("other".to_string()),
+        );
+        assert!(changed);
+        assert_eq!(Some("other"), renamed.save_name.as_deref());
+        assert_eq!(first.pid, renamed.pid);
+        assert!(
+            !tracker
+                .observe(
+                    at(1_700_000_070),
+                    Some(240),
+                    0x2000,
+                    Some("other".to_string())
+                )
+                .1
+        );
+    }
 }
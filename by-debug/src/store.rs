@@ -0,0 +1,751 @@
+use std::collections::hash_map;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bunyarrs::{vars, vars_dbg, Bunyarr};
+
+use crate::{
+    pack_observation, unpack_observation, Observation, Session, Unpacker, UNKNOWN_SESSION,
+};
+
+const SEGMENT_MAGIC: &[u8; 8] = b"FACTOSEG";
+/// version 1 segments predate sessions; everything in them is [`UNKNOWN_SESSION`]
+const SEGMENT_VERSION: u32 = 2;
+const SEGMENT_HEADER_LEN: u64 = 12;
+/// u32 payload length + i64 timestamp (unix nanos)
+const RECORD_HEADER_LEN: u64 = 12;
+/// version 2 records follow the header with a u8 length and the session key
+const MAX_KEY_LEN: usize = u8::MAX as usize;
+
+/// start a new segment once the current one is this big
+const SEGMENT_ROTATE_BYTES: u64 = 256 * 1024 * 1024;
+/// the most recent observations are kept decoded, as almost every request wants them
+const RECENT_KEPT: usize = 128;
+
+const IMPORTED_FILE: &str = "imported-archives";
+
+#[derive(Copy, Clone, Debug)]
+struct Entry {
+    ts_nanos: i64,
+    segment: usize,
+    offset: u64,
+    len: u32,
+}
+
+struct Segment {
+    path: PathBuf,
+    file: fs::File,
+    len: u64,
+    version: u32,
+}
+
+/// the observations from one [`Session`]
+struct Series {
+    /// sorted by `ts_nanos`, no duplicates
+    index: Vec<Entry>,
+    recent: BTreeMap<i64, Arc<Observation>>,
+    /// built from the whole series the first time it's asked for, then kept up to date by appends
+    /// at the end; anything else throws it away, see [`SeriesRef::units`]
+    units: Mutex<Option<Arc<HashMap<u32, UnitSummary>>>>,
+}
+
+static EMPTY_SERIES: Series = Series {
+    index: Vec::new(),
+    recent: BTreeMap::new(),
+    units: Mutex::new(None),
+};
+
+/// A unit as of its most recent observation in a series; times are unix seconds.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnitSummary {
+    /// the last observation before the products changed
+    pub produced_change: Option<i64>,
+    pub last_status: u32,
+    /// the last observation before the status changed, and what it was then
+    pub last_status_change: Option<i64>,
+    pub previous_status: Option<u32>,
+    last_products: u32,
+    last_ts: i64,
+}
+
+fn summarise(units: &mut HashMap<u32, UnitSummary>, obs: &Observation) {
+    let ts = obs.time.unix_timestamp();
+    for crafting in &obs.inner {
+        let unit = match units.entry(crafting.unit_number) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(UnitSummary {
+                    last_status: crafting.status,
+                    last_products: crafting.products_complete,
+                    last_ts: ts,
+                    ..UnitSummary::default()
+                });
+                continue;
+            }
+        };
+        if unit.last_products != crafting.products_complete {
+            unit.produced_change = Some(unit.last_ts);
+        }
+        if unit.last_status != crafting.status {
+            unit.last_status_change = Some(unit.last_ts);
+            unit.previous_status = Some(unit.last_status);
+        }
+        unit.last_status = crafting.status;
+        unit.last_products = crafting.products_complete;
+        unit.last_ts = ts;
+    }
+}
+
+/// Append-only observation storage, on disk in `dir` as numbered segment files.
+///
+/// Observations are kept in a separate series for each session (see [`Observation::session_key`]),
+/// all interleaved in the same segments.
+/// Only the timestamp indexes (and a handful of recent observations) are held in memory;
+/// everything else is read back from the segments on demand.
+pub struct Store {
+    dir: PathBuf,
+    segments: Vec<Segment>,
+    series: HashMap<String, Series>,
+    /// start a new segment once the current one is this big
+    rotate_bytes: u64,
+    logger: Bunyarr,
+}
+
+/// One session's observations, from [`Store::series`].
+#[derive(Copy, Clone)]
+pub struct SeriesRef<'s> {
+    store: &'s Store,
+    key: &'s str,
+    series: &'s Series,
+}
+
+impl Store {
+    pub fn open(dir: impl AsRef<Path>, logger: Bunyarr) -> Result<Store> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some(num) = name.strip_suffix(".facto-seg") else {
+                continue;
+            };
+            match num.parse::<u64>() {
+                Ok(num) => names.push((num, entry.path())),
+                Err(_) => logger.warn(vars! { name }, "ignoring a stray segment-like file"),
+            }
+        }
+        names.sort_unstable();
+
+        let mut store = Store {
+            dir,
+            segments: Vec::with_capacity(names.len() + 1),
+            series: HashMap::new(),
+            rotate_bytes: SEGMENT_ROTATE_BYTES,
+            logger,
+        };
+
+        let mut indexes = HashMap::<String, Vec<Entry>>::new();
+        for (_, path) in names {
+            store.load_segment(path, &mut indexes)?;
+        }
+
+        for (key, mut index) in indexes {
+            index.sort_unstable_by_key(|e| e.ts_nanos);
+            index.dedup_by_key(|e| e.ts_nanos);
+
+            let mut recent = BTreeMap::new();
+            for entry in &index[index.len().saturating_sub(RECENT_KEPT)..] {
+                recent.insert(entry.ts_nanos, Arc::new(store.read_entry(entry)?));
+            }
+            store.series.insert(
+                key,
+                Series {
+                    index,
+                    recent,
+                    units: Mutex::new(None),
+                },
+            );
+        }
+
+        let observations = store.series.values().map(|s| s.index.len()).sum::<usize>();
+        let sessions = store.series.len();
+        let segments = store.segments.len();
+        store
+            .logger
+            .info(vars! { observations, sessions, segments }, "store opened");
+
+        Ok(store)
+    }
+
+    /// scan the record headers of a segment, dropping any torn record at the end
+    fn load_segment(
+        &mut self,
+        path: PathBuf,
+        indexes: &mut HashMap<String, Vec<Entry>>,
+    ) -> Result<()> {
+        let mut file = fs::OpenOptions::new().read(true).append(true).open(&path)?;
+        let file_len = file.metadata()?.len();
+
+        let mut header = [0u8; SEGMENT_HEADER_LEN as usize];
+        file.read_exact(&mut header)
+            .with_context(|| anyhow!("reading segment header of {path:?}"))?;
+        ensure!(&header[..8] == SEGMENT_MAGIC, "not a segment: {path:?}");
+        let version = u32::from_le_bytes(header[8..].try_into().expect("fixed slice"));
+        ensure!(
+            (1..=SEGMENT_VERSION).contains(&version),
+            "unsupported segment version {version} in {path:?}"
+        );
+
+        let segment = self.segments.len();
+        let mut offset = SEGMENT_HEADER_LEN;
+        let mut reader = io::BufReader::new(&file);
+        loop {
+            if offset + RECORD_HEADER_LEN > file_len {
+                break;
+            }
+            let mut rec = [0u8; RECORD_HEADER_LEN as usize];
+            reader.read_exact(&mut rec)?;
+            let len = u32::from_le_bytes(rec[..4].try_into().expect("fixed slice"));
+            let ts_nanos = i64::from_le_bytes(rec[4..].try_into().expect("fixed slice"));
+            let mut payload = offset + RECORD_HEADER_LEN;
+            let key = if version >= 2 {
+                if payload + 1 > file_len {
+                    break;
+                }
+                let mut key_len = [0u8];
+                reader.read_exact(&mut key_len)?;
+                payload += 1 + u64::from(key_len[0]);
+                if payload > file_len {
+                    break;
+                }
+                let mut key = vec![0u8; usize::from(key_len[0])];
+                reader.read_exact(&mut key)?;
+                String::from_utf8(key).with_context(|| anyhow!("session key in {path:?}"))?
+            } else {
+                UNKNOWN_SESSION.to_string()
+            };
+            let end = payload + u64::from(len);
+            if end > file_len {
+                break;
+            }
+            indexes.entry(key).or_default().push(Entry {
+                ts_nanos,
+                segment,
+                offset: payload,
+                len,
+            });
+            reader.seek_relative(i64::from(len))?;
+            offset = end;
+        }
+        drop(reader);
+
+        if offset != file_len {
+            self.logger.warn(
+                vars! { path, offset, file_len },
+                "truncating torn record at the end of segment",
+            );
+            file.set_len(offset)?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        self.segments.push(Segment {
+            path,
+            file,
+            len: offset,
+            version,
+        });
+        Ok(())
+    }
+
+    fn new_segment(&mut self) -> Result<()> {
+        let next = self
+            .segments
+            .last()
+            .and_then(|s| s.path.file_name())
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".facto-seg"))
+            .map(|num| num.parse::<u64>())
+            .transpose()?
+            .map(|num| num + 1)
+            .unwrap_or_default();
+        let path = self.dir.join(format!("{next:08}.facto-seg"));
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create_new(true)
+            .open(&path)?;
+        file.write_all(SEGMENT_MAGIC)?;
+        file.write_all(&SEGMENT_VERSION.to_le_bytes())?;
+        file.flush()?;
+        self.segments.push(Segment {
+            path,
+            file,
+            len: SEGMENT_HEADER_LEN,
+            version: SEGMENT_VERSION,
+        });
+        Ok(())
+    }
+
+    /// returns `false` if we already had an observation for this exact time, in this session
+    pub fn append(&mut self, obs: Observation) -> Result<bool> {
+        let ts_nanos = i64::try_from(obs.time.unix_timestamp_nanos())?;
+        let key = obs.session_key();
+        ensure!(key.len() <= MAX_KEY_LEN, "session key too long: {key:?}");
+        let pos = match self
+            .series
+            .get(&key)
+            .map(|s| s.index.binary_search_by_key(&ts_nanos, |e| e.ts_nanos))
+        {
+            Some(Ok(_)) => return Ok(false),
+            Some(Err(pos)) => pos,
+            None => 0,
+        };
+
+        let payload = pack_observation(&obs)?;
+        let len = u32::try_from(payload.len())?;
+
+        let needs_segment = match self.segments.last() {
+            Some(seg) => seg.len >= self.rotate_bytes || seg.version != SEGMENT_VERSION,
+            None => true,
+        };
+        if needs_segment {
+            self.new_segment()?;
+        }
+        let segment = self.segments.len() - 1;
+        let seg = &mut self.segments[segment];
+
+        let mut record =
+            Vec::with_capacity(RECORD_HEADER_LEN as usize + 1 + key.len() + payload.len());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&ts_nanos.to_le_bytes());
+        record.push(u8::try_from(key.len())?);
+        record.extend_from_slice(key.as_bytes());
+        let offset = seg.len + u64::try_from(record.len())?;
+        record.extend_from_slice(&payload);
+        if let Err(e) = seg.file.write_all(&record).and_then(|()| seg.file.flush()) {
+            // don't leave half a record for the next one to be written after
+            if let Err(truncating) = seg.file.set_len(seg.len) {
+                let path = &seg.path;
+                self.logger.warn(
+                    vars_dbg! { path, truncating },
+                    "couldn't truncate a failed append",
+                );
+            }
+            return Err(e).with_context(|| anyhow!("appending to {:?}", seg.path));
+        }
+
+        seg.len += u64::try_from(record.len())?;
+
+        let series = self.series.entry(key).or_insert_with(|| Series {
+            index: Vec::new(),
+            recent: BTreeMap::new(),
+            units: Mutex::new(Some(Arc::default())),
+        });
+        let units = series
+            .units
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        match units {
+            Some(units) if pos == series.index.len() => summarise(Arc::make_mut(units), &obs),
+            _ => *units = None,
+        }
+        series.index.insert(
+            pos,
+            Entry {
+                ts_nanos,
+                segment,
+                offset,
+                len,
+            },
+        );
+
+        series.recent.insert(ts_nanos, Arc::new(obs));
+        while series.recent.len() > RECENT_KEPT {
+            series.recent.pop_first();
+        }
+
+        Ok(true)
+    }
+
+    fn read_entry(&self, entry: &Entry) -> Result<Observation> {
+        let seg = &self.segments[entry.segment];
+        let mut buf = vec![0u8; usize::try_from(entry.len)?];
+        seg.file
+            .read_exact_at(&mut buf, entry.offset)
+            .with_context(|| anyhow!("reading {:?} at {}", seg.path, entry.offset))?;
+        unpack_observation(io::Cursor::new(buf))
+    }
+
+    /// `None` picks the session with the most recent observation, which is empty if the store is
+    pub fn series(&self, key: Option<&str>) -> Result<SeriesRef<'_>> {
+        let (key, series) = match key {
+            Some(key) => self
+                .series
+                .get_key_value(key)
+                .ok_or_else(|| anyhow!("unknown session {key:?}"))?,
+            None => match self
+                .series
+                .iter()
+                .max_by_key(|(_, s)| s.index.last().map(|e| e.ts_nanos))
+            {
+                Some(found) => found,
+                None => return Ok(self.series_ref(UNKNOWN_SESSION, &EMPTY_SERIES)),
+            },
+        };
+        Ok(self.series_ref(key, series))
+    }
+
+    /// every session, oldest first
+    pub fn sessions(&self) -> Vec<SeriesRef<'_>> {
+        let mut ret = self
+            .series
+            .iter()
+            .map(|(key, series)| self.series_ref(key, series))
+            .collect::<Vec<_>>();
+        ret.sort_by_key(|s| s.series.index.first().map(|e| e.ts_nanos));
+        ret
+    }
+
+    fn series_ref<'s>(&'s self, key: &'s str, series: &'s Series) -> SeriesRef<'s> {
+        SeriesRef {
+            store: self,
+            key,
+            series,
+        }
+    }
+
+    /// Copy any old-style `.facto-cp.archiv` files from `from` into the store.
+    ///
+    /// Archives which were read to the end are remembered, and not re-read on the next start;
+    /// ones that look like they're still being written are re-read each time (duplicates are ignored).
+    pub fn import_archives(&mut self, from: impl AsRef<Path>) -> Result<()> {
+        let imported_path = self.dir.join(IMPORTED_FILE);
+        let mut imported = match fs::read_to_string(&imported_path) {
+            Ok(s) => s.lines().map(|s| s.to_string()).collect::<HashSet<_>>(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => bail!(e),
+        };
+
+        for path in fs::read_dir(from)? {
+            let path = path?;
+            let name = path.file_name().to_string_lossy().to_string();
+            if !name.ends_with(".facto-cp.archiv") || imported.contains(&name) {
+                continue;
+            }
+            // when the exporter has crashed during startup? boo
+            if path.metadata()?.len() == 0 {
+                continue;
+            }
+            let path = path.path();
+            self.logger.info(vars! { path }, "importing archive");
+            let mut archiv = archiv::ExpandOptions::default()
+                .stream(io::BufReader::new(fs::File::open(&path)?))?;
+
+            let mut unpacker = Unpacker::default();
+            let complete = loop {
+                let item = match archiv.next_item() {
+                    Err(err) => {
+                        self.logger.warn(
+                            vars_dbg! { err },
+                            "failed to read item, assuming live archive",
+                        );
+                        break false;
+                    }
+                    Ok(None) => break true,
+                    Ok(Some(item)) => item,
+                };
+                self.append(unpacker.unpack(item)?)?;
+            };
+
+            if complete {
+                let mut f = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&imported_path)?;
+                writeln!(f, "{name}")?;
+                imported.insert(name);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'s> SeriesRef<'s> {
+    pub fn key(&self) -> &'s str {
+        self.key
+    }
+
+    /// from the most recent observation
+    pub fn session(&self) -> Option<Session> {
+        self.last().and_then(|obs| obs.session.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.series.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.index.is_empty()
+    }
+
+    /// unix seconds of the observation at `idx`
+    pub fn ts(&self, idx: usize) -> i64 {
+        self.series.index[idx].ts_nanos.div_euclid(1_000_000_000)
+    }
+
+    /// the first index whose observation is at or after `unix` seconds
+    pub fn position(&self, unix: i64) -> usize {
+        let nanos = unix.saturating_mul(1_000_000_000);
+        self.series.index.partition_point(|e| e.ts_nanos < nanos)
+    }
+
+    pub fn get(&self, idx: usize) -> Result<Arc<Observation>> {
+        let entry = self
+            .series
+            .index
+            .get(idx)
+            .ok_or_else(|| anyhow!("no observation {idx}"))?;
+        if let Some(obs) = self.series.recent.get(&entry.ts_nanos) {
+            return Ok(Arc::clone(obs));
+        }
+        Ok(Arc::new(self.store.read_entry(entry)?))
+    }
+
+    pub fn last(&self) -> Option<Arc<Observation>> {
+        self.series
+            .recent
+            .last_key_value()
+            .map(|(_, obs)| Arc::clone(obs))
+    }
+
+    /// every unit ever seen in the series; the first call reads the whole series back
+    pub fn units(&self) -> Result<Arc<HashMap<u32, UnitSummary>>> {
+        let mut units = self
+            .series
+            .units
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(units) = &*units {
+            return Ok(Arc::clone(units));
+        }
+        let mut built = HashMap::new();
+        for idx in 0..self.len() {
+            summarise(&mut built, &*self.get(idx)?);
+        }
+        let built = Arc::new(built);
+        *units = Some(Arc::clone(&built));
+        Ok(built)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::CraftingLite;
+    use time::OffsetDateTime;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("store-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &Path) -> Store {
+        Store::open(dir, Bunyarr::with_name("store-test")).expect("opens")
+    }
+
+    fn session(pid: u32) -> Session {
+        Session {
+            pid,
+            build_id: None,
+            save_name: None,
+            started: OffsetDateTime::from_unix_timestamp(1_700_000_000).expect("in range"),
+        }
+    }
+
+    fn obs(session: Option<Session>, secs: i64, products: u32) -> Observation {
+        Observation {
+            time: OffsetDateTime::from_unix_timestamp(secs).expect("in range"),
+            session,
+            tick: None,
+            inner: vec![CraftingLite {
+                unit_number: 1,
+                products_complete: products,
+                status: 1,
+            }],
+        }
+    }
+
+    fn products(series: SeriesRef) -> Vec<u32> {
+        (0..series.len())
+            .map(|idx| series.get(idx).expect("readable").inner[0].products_complete)
+            .collect()
+    }
+
+    #[test]
+    fn append_and_reopen() -> Result<()> {
+        let dir = temp_dir("reopen");
+        let mut store = open(&dir);
+        assert!(store.series(None)?.is_empty());
+        // out of order, and a duplicate
+        assert!(store.append(obs(Some(session(1)), 20, 2))?);
+        assert!(store.append(obs(Some(session(1)), 10, 1))?);
+        assert!(!store.append(obs(Some(session(1)), 20, 99))?);
+        assert!(store.append(obs(Some(session(2)), 30, 3))?);
+        assert_eq!(vec![1, 2], products(store.series(Some("1-1700000000"))?));
+        assert_eq!("2-1700000000", store.series(None)?.key());
+        drop(store);
+
+        // everything but the recent cache comes back off the disk
+        let mut store = open(&dir);
+        assert_eq!(2, store.sessions().len());
+        let first = store.series(Some("1-1700000000"))?;
+        assert_eq!(vec![1, 2], products(first));
+        assert_eq!(Some(session(1)), first.session());
+        assert_eq!(1, first.position(15));
+        assert!(!store.append(obs(Some(session(2)), 30, 3))?);
+        assert_eq!(1, store.segments.len());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn segment_roll() -> Result<()> {
+        let dir = temp_dir("roll");
+        let mut store = open(&dir);
+        store.rotate_bytes = 1;
+        for secs in 0..3 {
+            store.append(obs(None, secs, u32::try_from(secs)?))?;
+        }
+        assert_eq!(3, store.segments.len());
+        drop(store);
+
+        let mut store = open(&dir);
+        assert_eq!(3, store.segments.len());
+        assert_eq!(
+            vec![0, 1, 2],
+            products(store.series(Some(UNKNOWN_SESSION))?)
+        );
+        store.rotate_bytes = 1;
+        store.append(obs(None, 3, 3))?;
+        assert!(dir.join("00000003.facto-seg").exists());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn torn_record_dropped() -> Result<()> {
+        let dir = temp_dir("torn");
+        let mut store = open(&dir);
+        store.append(obs(Some(session(1)), 10, 1))?;
+        let path = store.segments[0].path.clone();
+        drop(store);
+
+        let whole = fs::metadata(&path)?.len();
+        let mut f = fs::OpenOptions::new().append(true).open(&path)?;
+        f.write_all(&[0xff; 7])?;
+        drop(f);
+
+        let mut store = open(&dir);
+        assert_eq!(whole, fs::metadata(&path)?.len());
+        store.append(obs(Some(session(1)), 20, 2))?;
+        drop(store);
+        assert_eq!(vec![1, 2], products(open(&dir).series(None)?));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    fn status(secs: i64, products: u32, status: u32) -> Observation {
+        let mut obs = obs(Some(session(1)), secs, products);
+        obs.inner[0].status = status;
+        obs
+    }
+
+    #[test]
+    fn units_summarised() -> Result<()> {
+        let dir = temp_dir("units");
+        let mut store = open(&dir);
+        store.append(status(10, 1, 1))?;
+        store.append(status(20, 2, 1))?;
+        let first = store.series(None)?.units()?[&1].clone();
+        assert_eq!(Some(10), first.produced_change);
+        assert_eq!(None, first.last_status_change);
+
+        // kept up to date as it's appended to
+        store.append(status(30, 2, 37))?;
+        let expected = UnitSummary {
+            produced_change: Some(10),
+            last_status: 37,
+            last_status_change: Some(20),
+            previous_status: Some(1),
+            last_products: 2,
+            last_ts: 30,
+        };
+        assert_eq!(expected, store.series(None)?.units()?[&1]);
+        drop(store);
+
+        // and rebuilt after reopening, or an insert in the middle
+        let mut store = open(&dir);
+        assert_eq!(expected, store.series(None)?.units()?[&1]);
+        store.append(status(25, 5, 1))?;
+        let rebuilt = &store.series(None)?.units()?[&1];
+        assert_eq!(Some(25), rebuilt.produced_change);
+        assert_eq!(Some(25), rebuilt.last_status_change);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn stray_segment_ignored() -> Result<()> {
+        let dir = temp_dir("stray");
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("backup.facto-seg"), b"not ours")?;
+        let mut store = open(&dir);
+        store.append(obs(None, 10, 1))?;
+        assert!(dir.join("00000000.facto-seg").exists());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// records without a session key, from before sessions
+    #[test]
+    fn reads_version_one() -> Result<()> {
+        let dir = temp_dir("v1");
+        fs::create_dir_all(&dir)?;
+        let payload = pack_observation(&obs(None, 10, 1))?;
+        let mut seg = SEGMENT_MAGIC.to_vec();
+        seg.extend_from_slice(&1u32.to_le_bytes());
+        seg.extend_from_slice(&u32::try_from(payload.len())?.to_le_bytes());
+        seg.extend_from_slice(&10_000_000_000i64.to_le_bytes());
+        seg.extend_from_slice(&payload);
+        fs::write(dir.join("00000000.facto-seg"), seg)?;
+
+        let mut store = open(&dir);
+        assert_eq!(vec![1], products(store.series(Some(UNKNOWN_SESSION))?));
+        // new records don't go in the old format
+        store.append(obs(Some(session(1)), 20, 2))?;
+        assert_eq!(2, store.segments.len());
+        drop(store);
+
+        let store = open(&dir);
+        assert_eq!(vec![1], products(store.series(Some(UNKNOWN_SESSION))?));
+        assert_eq!(vec![2], products(store.series(Some("1-1700000000"))?));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
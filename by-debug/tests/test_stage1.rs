@@ -1,45 +1,165 @@
-use std::collections::HashMap;
+use std::mem::offset_of;
 use std::process::Command;
 use std::time::Duration;
-use std::{iter, thread};
+use std::{fs, iter, thread};
 
 use anyhow::Result;
-use facto_exporter::debug::elf::{find_function, full_symbol_table, Symbol};
+use facto_exporter::debug::breakpoints::Slot;
+use facto_exporter::debug::elf::{debug_file, struct_layouts};
 use facto_exporter::debug::inject::{inject_mmap, CraftingLite, Shell};
+use facto_exporter::debug::offsets::{discover, discover_cached, find_members};
 use facto_exporter::debug::pad_to_word;
 use facto_exporter::debug::ptrace::{
-    breakpoint, debug_to_int3, find_executable_map, run_until_stop, wait_for_stop,
-    which_breakpoints, write_words_ptr,
+    breakpoint, debug_to_int3, find_executable_map, interrupt, run_until_stop, which_breakpoints,
+    write_words_ptr, Condition, Gone, Stop,
 };
+use facto_exporter::debug::symbols::SymbolIndex;
+use facto_exporter::debug::tracee::Tracee;
 use nix::libc::pid_t;
 use nix::sys::ptrace;
+use nix::sys::signal::Signal;
 use nix::unistd::Pid;
 
 #[test]
 fn smoke() -> Result<()> {
     let victim_path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/victim1/victim1");
 
-    let table = full_symbol_table(victim_path)?;
+    let index = SymbolIndex::open(victim_path)?;
     let mut child = Command::new(victim_path).spawn()?;
     let child_pid = Pid::from_raw(pid_t::try_from(child.id())?);
     // this was some attempt to make sure the application is 'running', but the current test uses
     // breakpoints to make sure it is only stopped in regular code anyway
     thread::sleep(Duration::from_millis(30));
-    let res = work(child_pid, &table);
+    let res = work(child_pid, &index);
     let _ = child.kill();
     let _ = child.wait();
     res
 }
 
-fn work(pid: Pid, table: &HashMap<String, Symbol>) -> Result<()> {
+/// more breakpoints than debug registers, and the victim survives them
+#[test]
+fn breakpoints() -> Result<()> {
+    survives(breakpoints_in)
+}
+
+#[test]
+fn watchpoints() -> Result<()> {
+    survives(watchpoints_in)
+}
+
+/// the victim being killed is reported, rather than waited on forever
+#[test]
+fn gone() -> Result<()> {
+    let victim_path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/victim1/victim1");
+
+    let index = SymbolIndex::open(victim_path)?;
+    let mut child = Command::new(victim_path).spawn()?;
+    let pid = Pid::from_raw(pid_t::try_from(child.id())?);
+    thread::sleep(Duration::from_millis(30));
+
+    let mut tracee = Tracee::attach(pid)?;
+    let (from, _, offset) = find_executable_map(pid)?;
+    let step = tracee.add_breakpoint(from + index.find_function("step")?.addr - offset)?;
+    assert_eq!(Some(step), tracee.run_until_hit()?);
+
+    child.kill()?;
+    let err = tracee.run_until_hit().expect_err("killed");
+    let gone = err.downcast_ref::<Gone>().expect("a Gone");
+    assert_eq!(Stop::Killed(Signal::SIGKILL), gone.stop);
+    tracee.forget();
+    Ok(())
+}
+
+fn survives(f: impl FnOnce(Pid, &SymbolIndex) -> Result<()>) -> Result<()> {
+    let victim_path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/victim1/victim1");
+
+    let index = SymbolIndex::open(victim_path)?;
+    let mut child = Command::new(victim_path).spawn()?;
+    let child_pid = Pid::from_raw(pid_t::try_from(child.id())?);
+    thread::sleep(Duration::from_millis(30));
+    let res = f(child_pid, &index);
+    // still running, after we've gone
+    thread::sleep(Duration::from_millis(500));
+    let survived = child.try_wait()?.is_none();
+    let _ = child.kill();
+    let _ = child.wait();
+    res?;
+    assert!(survived, "victim died after the detach");
+    Ok(())
+}
+
+fn breakpoints_in(pid: Pid, index: &SymbolIndex) -> Result<()> {
+    // the only thread, but an int3 needs them all
+    let mut tracee = Tracee::attach_group(pid, pid)?;
+    let (from, _, offset) = find_executable_map(pid)?;
+    let at = |name: &str| -> Result<u64> { Ok(from + index.find_function(name)?.addr - offset) };
+    let step = at("step")?;
+
+    let hardware = tracee.add_breakpoint(step)?;
+    assert_eq!(Some(Slot::Hardware(0)), tracee.breakpoints().slot(hardware));
+    // main never gets back to its start
+    for slot in 1..4 {
+        let id = tracee.add_breakpoint(at("main")?)?;
+        assert_eq!(Some(Slot::Hardware(slot)), tracee.breakpoints().slot(id));
+    }
+    // the debug registers are full, but it's safe to fall back to an int3
+    let software = tracee.add_breakpoint(step)?;
+    assert_eq!(Some(Slot::Software), tracee.breakpoints().slot(software));
+
+    // the debug register fires before the instruction, then the int3 is it
+    for _ in 0..2 {
+        assert_eq!(Some(hardware), tracee.run_until_hit()?);
+        assert_eq!(Some(software), tracee.run_until_hit()?);
+        assert_eq!(step, ptrace::getregs(pid)?.rip);
+    }
+
+    tracee.remove_breakpoint(hardware)?;
+    for _ in 0..2 {
+        assert_eq!(Some(software), tracee.run_until_hit()?);
+    }
+    tracee.detach()
+}
+
+fn watchpoints_in(pid: Pid, index: &SymbolIndex) -> Result<()> {
+    let mut tracee = Tracee::attach(pid)?;
+    let (from, _, offset) = find_executable_map(pid)?;
+    let step = from + index.find_function("step")?.addr - offset;
+
+    let on_step = tracee.add_breakpoint(step)?;
+    assert_eq!(Some(on_step), tracee.run_until_hit()?);
+    tracee.remove_breakpoint(on_step)?;
+    let regs = ptrace::getregs(pid)?;
+
+    // `s.size()`, which is only read
+    let size = regs.rdi + offset_of!(FakeSet, size) as u64;
+    assert!(tracee.watch(size + 1, Condition::ReadWrite(8)).is_err());
+    let read = tracee.watch(size, Condition::ReadWrite(8))?;
+    assert_eq!(Some(read), tracee.run_until_hit()?);
+    assert_eq!(Some(Slot::Hardware(0)), tracee.breakpoints().slot(read));
+    tracee.remove_breakpoint(read)?;
+
+    // the return address; written by each call from main, and the next one is `step` again
+    let written = tracee.watch(regs.rsp, Condition::Write(8))?;
+    assert_eq!(Some(Slot::Hardware(0)), tracee.breakpoints().slot(written));
+    let mut calls = 0;
+    while ptrace::getregs(pid)?.rip != step {
+        assert!(calls < 4, "never got back to step");
+        assert_eq!(Some(written), tracee.run_until_hit()?);
+        calls += 1;
+    }
+    tracee.detach()
+}
+
+fn work(pid: Pid, index: &SymbolIndex) -> Result<()> {
     let crafting_lite_size = std::mem::size_of::<CraftingLite>() as u64;
     assert_eq!(crafting_lite_size, 4 * 4);
 
-    let (step_named, step, _) = find_function(table, "step")?;
-    println!("step found as (mangled): {step_named} at {step:#x}");
+    let step = index.find_function("step")?;
+    println!("step found as (mangled): {} at {:#x}", step.raw, step.addr);
+    let step = step.addr;
 
-    ptrace::attach(pid)?;
-    wait_for_stop(pid)?;
+    ptrace::seize(pid, ptrace::Options::empty())?;
+    interrupt(pid)?;
 
     let (from, to, offset) = find_executable_map(pid)?;
     assert_eq!(from % 8, 0);
@@ -62,6 +182,7 @@ fn work(pid: Pid, table: &HashMap<String, Symbol>) -> Result<()> {
     assert_eq!([false, false, true, false], which_breakpoints(pid)?);
 
     println!("jumping to shell...");
+    let orig_regs = ptrace::getregs(pid)?;
     shell.enter()?;
 
     debug_to_int3(pid, shell.map_addr)?;
@@ -91,7 +212,16 @@ fn work(pid: Pid, table: &HashMap<String, Symbol>) -> Result<()> {
     );
 
     println!("checking it isn't completely corrupt...");
+    ptrace::setregs(pid, orig_regs)?;
     run_until_stop(pid)?;
+    assert_eq!([false, false, true, false], which_breakpoints(pid)?);
+
+    println!("removing the shell...");
+    let map_addr = shell.map_addr;
+    shell.remove()?;
+    assert!(!fs::read_to_string(format!("/proc/{pid}/maps"))?.contains(&format!("{map_addr:x}-")));
+    run_until_stop(pid)?;
+    assert_eq!([false, false, true, false], which_breakpoints(pid)?);
 
     Ok(())
 }
@@ -101,7 +231,7 @@ fn alloc_fake_set(pid: Pid, from: u64) -> Result<u64> {
 
     let set_off = 32;
     let mut mem = Vec::with_capacity(4096);
-    mem.extend(iter::repeat(0).take(set_off));
+    mem.extend(iter::repeat_n(0, set_off));
 
     let mut craftings = Vec::new();
     for i in 0..4 {
@@ -109,7 +239,7 @@ fn alloc_fake_set(pid: Pid, from: u64) -> Result<u64> {
         let mut crafting = FakeCrafting::default();
         crafting.data[0x26] = 0x100 + i;
         crafting.data[0x81] = 0x1000 + i;
-        mem.extend_from_slice(&bytemuck::bytes_of(&crafting));
+        mem.extend_from_slice(bytemuck::bytes_of(&crafting));
     }
 
     let mut entries = Vec::new();
@@ -118,7 +248,7 @@ fn alloc_fake_set(pid: Pid, from: u64) -> Result<u64> {
     let set_base = fake_structs_addr + mem.len() as u64;
     let to_set_addr = |x: Option<usize>| x.map(|x| set_base + (set_size * x) as u64).unwrap_or(0);
     for (left, right, crafting) in entries {
-        mem.extend_from_slice(&bytemuck::bytes_of(&FakeSetEntry {
+        mem.extend_from_slice(bytemuck::bytes_of(&FakeSetEntry {
             left: to_set_addr(left),
             right: to_set_addr(right),
             data: fake_structs_addr + crafting as u64,
@@ -127,7 +257,7 @@ fn alloc_fake_set(pid: Pid, from: u64) -> Result<u64> {
     }
 
     let set_addr = fake_structs_addr + mem.len() as u64;
-    mem.extend_from_slice(&bytemuck::bytes_of(&FakeSet {
+    mem.extend_from_slice(bytemuck::bytes_of(&FakeSet {
         begin: to_set_addr(root),
         size: craftings.len(),
         ..FakeSet::default()
@@ -137,6 +267,74 @@ fn alloc_fake_set(pid: Pid, from: u64) -> Result<u64> {
     Ok(set_addr)
 }
 
+/// the fake set is laid out like the real one, according to the victim's debug info
+#[test]
+fn fake_set_layout() -> Result<()> {
+    let victim_path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/victim1/victim1");
+    assert_eq!(Some(victim_path.into()), debug_file(victim_path)?);
+    let layouts = struct_layouts(victim_path)?.expect("built with -g");
+
+    let set = "std::set<Obj, std::less<Obj>, std::allocator<Obj> >";
+    let header = ["_M_t", "_M_impl", "_M_header"];
+    let root = layouts.member(set, &[&header[..], &["_M_parent"]].concat())?;
+    assert_eq!(offset_of!(FakeSet, begin) as u64, root.offset);
+    assert_eq!(Some("std::_Rb_tree_node_base*"), root.type_name.as_deref());
+    let size = layouts.member(set, &["_M_t", "_M_impl", "_M_node_count"])?;
+    assert_eq!(offset_of!(FakeSet, size) as u64, size.offset);
+    assert_eq!(Some(8), size.size);
+
+    let node = "std::_Rb_tree_node<Obj>";
+    let member = |path: &str| layouts.member(node, &[path]).map(|m| m.offset as usize);
+    assert_eq!(offset_of!(FakeSetEntry, left), member("_M_left")?);
+    assert_eq!(offset_of!(FakeSetEntry, right), member("_M_right")?);
+    assert_eq!(offset_of!(FakeSetEntry, data), member("_M_storage")?);
+
+    let obj = layouts.get("Obj").expect("defined");
+    assert_eq!(56, obj.size);
+    assert!(layouts.member("Obj", &["nope"]).is_err());
+    Ok(())
+}
+
+/// the victim's `CraftingMachine` has fields named like the game's
+#[test]
+fn offsets_from_debug_info() -> Result<()> {
+    let victim_path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/victim1/victim1");
+    let layouts = struct_layouts(victim_path)?.expect("built with -g");
+
+    // after the vtable pointer and `surface`, in the base; then `progress` is in its tail padding
+    let [unit_number, products_finished, set_size] = find_members(&layouts);
+    assert_eq!(Some(16), unit_number);
+    assert_eq!(Some(24), products_finished);
+    // the victim's set isn't of crafting machines
+    assert_eq!(None, set_size);
+    Ok(())
+}
+
+/// the second start finds them without reading the debug info
+#[test]
+fn offsets_cached() -> Result<()> {
+    let victim_path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/victim1/victim1");
+    let cache_dir = std::env::temp_dir().join(format!("offsets-cached-{}", std::process::id()));
+    let index = SymbolIndex::open(victim_path)?;
+
+    let discovered = discover_cached(victim_path, &cache_dir, index.table())?;
+    assert_eq!(discover(victim_path, index.table())?, discovered);
+    let cached = fs::read_dir(&cache_dir)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+        .collect::<Result<Vec<_>>>()?;
+    assert!(
+        matches!(cached.as_slice(), [name] if name.starts_with("offsets-")),
+        "{cached:?}"
+    );
+    assert_eq!(
+        discovered,
+        discover_cached(victim_path, &cache_dir, index.table())?
+    );
+
+    fs::remove_dir_all(&cache_dir)?;
+    Ok(())
+}
+
 // the layout here is arbitrary
 fn place<T: Copy>(heap: &mut Vec<(Option<usize>, Option<usize>, T)>, vals: &[T]) -> Option<usize> {
     if vals.is_empty() {
@@ -158,8 +356,8 @@ fn test_place() {
     let vals = [1, 2, 3, 4, 5, 6, 7];
     let root = place(&mut heap, &vals);
 
-    for i in 0..heap.len() {
-        println!("{i}: {:?}", heap[i]);
+    for (i, entry) in heap.iter().enumerate() {
+        println!("{i}: {entry:?}");
     }
 
     // the layout here is arbitrary
@@ -3,6 +3,8 @@
 
 #include "obj.h"
 
+CraftingMachine machine;
+
 int step(std::set<Obj> &s) {
     std::cout << "hello " << s.size() << std::endl;
     return s.size();
@@ -16,4 +16,18 @@ struct Obj {
     bool operator<(const Obj& other) const;
 };
 
+// named like the game's, to find their fields in the debug info
+struct Entity {
+    virtual ~Entity() = default;
+    void *surface;
+    unsigned int unitNumber;
+};
+
+struct CraftingMachine : Entity {
+    float progress;
+    unsigned int productsFinished;
+};
+
+extern CraftingMachine machine;
+
 extern int step(std::set<Obj> &);